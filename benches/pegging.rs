@@ -0,0 +1,179 @@
+//! Pegging of generated PartDtl data at a few sizes, up to about the 400k rows of a
+//! production snapshot. Run with `cargo bench`, and compare against an earlier run with
+//! `cargo bench -- --save-baseline <name>` then `cargo bench -- --baseline <name>`.
+
+use apollo::{
+    directlinks::DirectLinks,
+    fixture::FixtureDataSource,
+    getdata::peg_time_phase_data,
+    onhand::OnHand,
+    partsubs::PartSubstitutes,
+    peg::{
+        get_unique_part_numbers, group_part_inputs, multi_peg_part_dtl, PartInput, PegOptions,
+        SafetyStock,
+    },
+    sql::SQLReturnRow,
+};
+use chrono::{Duration, NaiveDate};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rust_decimal::Decimal;
+
+const ROWS_PER_PART: usize = 20;
+
+// Part counts for the whole pegging run. The largest is about a production snapshot.
+const PART_COUNTS: [usize; 3] = [500, 2_000, 20_000];
+
+// Filtering every row for every part grows with the square of the rows, so the before and
+// after comparison of building the pegging input stops short of production size
+const INPUT_PART_COUNTS: [usize; 2] = [500, 2_000];
+
+/// Demand and supply spread over two plants for every part, with some on hand in one
+fn fixture(parts: usize) -> FixtureDataSource {
+    let start = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+    let mut part_dtl = vec![];
+    let mut on_hand = vec![];
+
+    for part in 0..parts {
+        let part_num = format!("P{:05}", part);
+        for row in 0..ROWS_PER_PART {
+            let requirement = row % 2 == 0;
+            let sourcefile = match (requirement, row % 4) {
+                (true, 0) => "OR",
+                (true, _) => "JM",
+                (false, 1) => "PO",
+                (false, _) => "JH",
+            };
+            part_dtl.push(SQLReturnRow {
+                requirement,
+                due_date: start + Duration::days(((part * 7 + row * 3) % 120) as i64),
+                sourcefile: sourcefile.to_owned(),
+                ..SQLReturnRow::new_on_hand(
+                    &part_num,
+                    if row % 3 == 0 { "West" } else { "MfgSys" },
+                    Decimal::from(1 + (part + row) % 9),
+                )
+            });
+        }
+        on_hand.push(OnHand {
+            part_num,
+            site: "MfgSys".to_owned(),
+            qty: Decimal::from(part % 5),
+            uom: "".to_owned(),
+        });
+    }
+
+    FixtureDataSource {
+        part_dtl,
+        on_hand,
+        ..FixtureDataSource::default()
+    }
+    .prepare()
+}
+
+/// Pegs every part from input found by filtering all of the rows for each part, which is
+/// how pegging worked before the input was grouped by part
+fn peg_filtering_per_part(fixture: &FixtureDataSource, part_numbers: &[String]) -> usize {
+    part_numbers
+        .par_iter()
+        .map(|part_num| {
+            let input = PartInput {
+                part_dtl: fixture
+                    .part_dtl
+                    .iter()
+                    .filter(|row| &row.part_num == part_num)
+                    .collect(),
+                on_hand: fixture
+                    .on_hand
+                    .iter()
+                    .filter(|row| &row.part_num == part_num)
+                    .collect(),
+            };
+            peg_part(&input, part_num)
+        })
+        .sum()
+}
+
+/// Pegs every part from input grouped by part in a single pass
+fn peg_grouped_by_part(fixture: &FixtureDataSource, part_numbers: &[String]) -> usize {
+    let inputs = group_part_inputs(&fixture.part_dtl, &fixture.on_hand);
+    let no_input = PartInput::default();
+
+    part_numbers
+        .par_iter()
+        .map(|part_num| peg_part(inputs.get(part_num).unwrap_or(&no_input), part_num))
+        .sum()
+}
+
+fn peg_part(input: &PartInput, part_num: &str) -> usize {
+    multi_peg_part_dtl(
+        input,
+        &SafetyStock::new(),
+        &DirectLinks::new(),
+        part_num,
+        &PegOptions::default(),
+    )
+    .demand
+    .len()
+}
+
+fn pegging_input(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pegging_input");
+    group.sample_size(10);
+
+    for parts in INPUT_PART_COUNTS {
+        let fixture = fixture(parts);
+        let rows = fixture.part_dtl.len();
+        let part_numbers = get_unique_part_numbers(&fixture.part_dtl);
+
+        group.bench_with_input(
+            BenchmarkId::new("filter_per_part", rows),
+            &fixture,
+            |b, fixture| b.iter(|| peg_filtering_per_part(fixture, &part_numbers)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("group_part_inputs", rows),
+            &fixture,
+            |b, fixture| b.iter(|| peg_grouped_by_part(fixture, &part_numbers)),
+        );
+    }
+
+    group.finish();
+}
+
+fn pegging(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pegging");
+    group.sample_size(10);
+
+    for parts in PART_COUNTS {
+        let fixture = fixture(parts);
+        let rows = fixture.part_dtl.len();
+
+        group.bench_with_input(
+            BenchmarkId::new("get_unique_part_numbers", rows),
+            &fixture,
+            |b, fixture| b.iter(|| get_unique_part_numbers(&fixture.part_dtl)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("peg_time_phase_data", rows),
+            &fixture,
+            |b, fixture| {
+                b.iter(|| {
+                    peg_time_phase_data(
+                        &fixture.part_dtl,
+                        &fixture.on_hand,
+                        &SafetyStock::new(),
+                        &DirectLinks::new(),
+                        &PartSubstitutes::new(),
+                        &PegOptions::default(),
+                    )
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, pegging_input, pegging);
+criterion_main!(benches);
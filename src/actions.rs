@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    partplant::PartPlant,
+    parttimephase::Supply,
+    peg::{ExcessSupply, Pegging},
+};
+
+// How many days early supply may arrive before it is worth deferring
+const DEFAULT_DEFER_TOLERANCE_DAYS: i64 = 7;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionType {
+    /// Supply pegged to demand that is due before it arrives
+    Expedite,
+    /// Supply arriving well before the earliest demand it is pegged to
+    Defer,
+    /// Supply that is not pegged to any demand
+    Cancel,
+    /// Supply that is only partly pegged to demand, to be reduced by what is left over
+    Reduce,
+    /// Demand that no supply covers
+    NewSupply,
+}
+
+/// Query string filters for the action messages. Due dates are inclusive and apply to the
+/// date the supply is required on, or its own due date when nothing requires it.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ActionFilter {
+    pub buyer: Option<String>,
+    pub planner: Option<String>,
+    pub part: Option<String>,
+    pub action: Option<ActionType>,
+    pub due_from: Option<NaiveDate>,
+    pub due_to: Option<NaiveDate>,
+    pub defer_tolerance: Option<i64>,
+}
+
+impl ActionFilter {
+    fn matches(&self, message: &ActionMessage) -> bool {
+        let date = message.required_date.or(message.due_date);
+
+        self.part.as_ref().is_none_or(|part| &message.part_number == part)
+            && self.action.is_none_or(|action| message.action == action)
+            && self
+                .buyer
+                .as_ref()
+                .is_none_or(|buyer| message.buyer.eq_ignore_ascii_case(buyer))
+            && self
+                .planner
+                .as_ref()
+                .is_none_or(|planner| message.planner.eq_ignore_ascii_case(planner))
+            && self.due_from.is_none_or(|from| date.is_none_or(|date| date >= from))
+            && self.due_to.is_none_or(|to| date.is_none_or(|date| date <= to))
+    }
+}
+
+/// A suggested change to supply, or a suggestion for new supply
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct ActionMessage {
+    pub action: ActionType,
+    pub part_number: String,
+    pub plant: String,
+    pub buyer: String,
+    pub planner: String,
+    pub sourcefile: String,
+    pub job_num: String,
+    pub asm: i32,
+    pub mtl: i32,
+    pub po_num: Option<i32>,
+    pub po_line: Option<i32>,
+    pub po_rel: Option<i32>,
+    pub qty: Decimal,
+    /// When the supply is due now. Empty for new supply.
+    pub due_date: Option<NaiveDate>,
+    /// When the earliest demand needs the supply. Empty for supply to cancel or reduce.
+    pub required_date: Option<NaiveDate>,
+    /// Days the supply has to move by, later being positive
+    pub days: i64,
+}
+
+// Everything identifying one supply, so supply pegged to several demands is acted on once
+type SupplyKey = (
+    String,
+    String,
+    String,
+    NaiveDate,
+    String,
+    i32,
+    i32,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+);
+
+struct PeggedSupply {
+    qty: Decimal,
+    required_date: NaiveDate,
+}
+
+fn supply_key(part_number: &str, supply: &Supply) -> SupplyKey {
+    (
+        part_number.to_owned(),
+        supply.plant.to_owned(),
+        supply.sourcefile.to_owned(),
+        supply.due_date,
+        supply.job_num.to_owned(),
+        supply.asm,
+        supply.mtl,
+        supply.po_num,
+        supply.po_line,
+        supply.po_rel,
+    )
+}
+
+fn excess_key(excess: &ExcessSupply) -> SupplyKey {
+    (
+        excess.part_number.to_owned(),
+        excess.plant.to_owned(),
+        excess.sourcefile.to_owned(),
+        excess.due_date,
+        excess.job_num.to_owned(),
+        excess.asm,
+        excess.mtl,
+        excess.po_num,
+        excess.po_line,
+        excess.po_rel,
+    )
+}
+
+/// Builds the action messages for every part in the pegging, the earliest first
+pub fn get_actions(
+    pegging: &Pegging,
+    part_plants: &HashMap<(String, String), PartPlant>,
+    filter: &ActionFilter,
+) -> Vec<ActionMessage> {
+    let tolerance = filter.defer_tolerance.unwrap_or(DEFAULT_DEFER_TOLERANCE_DAYS).max(0);
+
+    let message = |action: ActionType, part_number: &str, plant: &str| {
+        let part_plant = part_plants.get(&(part_number.to_owned(), plant.to_owned()));
+        ActionMessage {
+            action,
+            part_number: part_number.to_owned(),
+            plant: plant.to_owned(),
+            buyer: part_plant.map_or("".to_owned(), |p| p.buyer.to_owned()),
+            planner: part_plant.map_or("".to_owned(), |p| p.planner.to_owned()),
+            sourcefile: "".to_owned(),
+            job_num: "".to_owned(),
+            asm: 0,
+            mtl: 0,
+            po_num: None,
+            po_line: None,
+            po_rel: None,
+            qty: Decimal::ZERO,
+            due_date: None,
+            required_date: None,
+            days: 0,
+        }
+    };
+
+    // Roll pegged supply up to the earliest demand it covers, leaving on hand and safety
+    // stock alone. Substitute supply is acted on under its own part and in its own quantity.
+    let mut pegged: BTreeMap<SupplyKey, PeggedSupply> = BTreeMap::new();
+    pegging.demand.values().flatten().for_each(|demand| {
+        demand
+            .supply
+            .iter()
+            .filter(|supply| supply.sourcefile != "OH" && supply.sourcefile != "SS")
+            .for_each(|supply| {
+                let part_number = supply.substitute_part.as_ref().unwrap_or(&demand.part_number);
+                let entry = pegged
+                    .entry(supply_key(part_number, supply))
+                    .or_insert(PeggedSupply {
+                        qty: Decimal::ZERO,
+                        required_date: demand.due_date,
+                    });
+                entry.qty += supply.pegged_qty * supply.conversion_factor.unwrap_or(Decimal::ONE);
+                entry.required_date = NaiveDate::min(entry.required_date, demand.due_date);
+            })
+    });
+
+    let pegged_keys: HashSet<SupplyKey> = pegged.keys().cloned().collect();
+
+    let mut messages: Vec<ActionMessage> = pegged
+        .into_iter()
+        .filter_map(|(key, supply)| {
+            let (part_number, plant, sourcefile, due_date, job_num, asm, mtl, po_num, po_line, po_rel) =
+                key;
+            let days = (supply.required_date - due_date).num_days();
+            let action = if days < 0 {
+                ActionType::Expedite
+            } else if days > tolerance {
+                ActionType::Defer
+            } else {
+                return None;
+            };
+
+            Some(ActionMessage {
+                sourcefile,
+                job_num,
+                asm,
+                mtl,
+                po_num,
+                po_line,
+                po_rel,
+                qty: supply.qty,
+                due_date: Some(due_date),
+                required_date: Some(supply.required_date),
+                days,
+                ..message(action, &part_number, &plant)
+            })
+        })
+        .collect();
+
+    pegging
+        .excess
+        .values()
+        .flatten()
+        .filter(|excess| excess.sourcefile != "OH")
+        .for_each(|excess: &ExcessSupply| {
+            // Supply that demand still needs part of is only cut back to what is pegged
+            let action = match pegged_keys.contains(&excess_key(excess)) {
+                true => ActionType::Reduce,
+                false => ActionType::Cancel,
+            };
+            messages.push(ActionMessage {
+                sourcefile: excess.sourcefile.to_owned(),
+                job_num: excess.job_num.to_owned(),
+                asm: excess.asm,
+                mtl: excess.mtl,
+                po_num: excess.po_num,
+                po_line: excess.po_line,
+                po_rel: excess.po_rel,
+                qty: excess.qty,
+                due_date: Some(excess.due_date),
+                ..message(action, &excess.part_number, &excess.plant)
+            })
+        });
+
+    pegging
+        .demand
+        .values()
+        .flatten()
+        .filter(|demand| demand.pegged_demand < demand.demand_qty)
+        .for_each(|demand| {
+            messages.push(ActionMessage {
+                qty: demand.demand_qty - demand.pegged_demand,
+                required_date: Some(demand.due_date),
+                ..message(ActionType::NewSupply, &demand.part_number, &demand.plant)
+            })
+        });
+
+    messages.retain(|message| filter.matches(message));
+    messages.sort_by(|a, b| {
+        (a.required_date.or(a.due_date), &a.part_number, &a.plant)
+            .cmp(&(b.required_date.or(b.due_date), &b.part_number, &b.plant))
+    });
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{date, demand, excess, supply};
+    use rust_decimal_macros::dec;
+
+    fn po(po_num: i32, day: u32, demand_day: u32, qty: Decimal) -> Supply {
+        Supply {
+            po_num: Some(po_num),
+            po_line: Some(1),
+            po_rel: Some(1),
+            ..supply("PO", day, demand_day, qty)
+        }
+    }
+
+    fn pegging() -> Pegging {
+        Pegging {
+            demand: HashMap::from([(
+                "RAW".to_owned(),
+                vec![
+                    demand("RAW", 5, dec!(2), vec![supply("OH", 1, 5, dec!(2))]),
+                    // PO 1 arrives late for this demand and on time for the next
+                    demand("RAW", 10, dec!(3), vec![po(1, 12, 10, dec!(3))]),
+                    demand("RAW", 12, dec!(1), vec![po(1, 12, 12, dec!(1))]),
+                    // PO 2 arrives two weeks early
+                    demand("RAW", 25, dec!(4), vec![po(2, 11, 25, dec!(2))]),
+                ],
+            )]),
+            excess: HashMap::from([(
+                "RAW".to_owned(),
+                vec![ExcessSupply {
+                    po_num: Some(3),
+                    po_line: Some(1),
+                    po_rel: Some(1),
+                    ..excess("RAW", "PO", 28, dec!(6))
+                }],
+            )]),
+        }
+    }
+
+    fn part_plants() -> HashMap<(String, String), PartPlant> {
+        HashMap::from([(
+            ("RAW".to_owned(), "MfgSys".to_owned()),
+            PartPlant {
+                part_num: "RAW".to_owned(),
+                plant: "MfgSys".to_owned(),
+                buyer: "JANE".to_owned(),
+                planner: "BOB".to_owned(),
+                safety_qty: dec!(0),
+                lead_time: 0,
+                mfg_lead_time: 0,
+            },
+        )])
+    }
+
+    #[test]
+    fn suggests_expedite_defer_cancel_and_new_supply() {
+        let messages = get_actions(&pegging(), &part_plants(), &ActionFilter::default());
+
+        let actions: Vec<ActionType> = messages.iter().map(|m| m.action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                ActionType::Expedite,
+                ActionType::Defer,
+                ActionType::NewSupply,
+                ActionType::Cancel
+            ]
+        );
+
+        // Supply pegged to several demands is expedited once, for all of its pegged qty
+        assert_eq!(messages[0].po_num, Some(1));
+        assert_eq!(messages[0].qty, dec!(4));
+        assert_eq!(messages[0].days, -2);
+        assert_eq!(messages[0].buyer, "JANE");
+
+        assert_eq!(messages[1].po_num, Some(2));
+        assert_eq!(messages[1].days, 14);
+
+        assert_eq!(messages[2].qty, dec!(2));
+        assert_eq!(messages[2].due_date, None);
+
+        assert_eq!(messages[3].qty, dec!(6));
+        assert_eq!(messages[3].required_date, None);
+    }
+
+    #[test]
+    fn filters_actions() {
+        let filter = ActionFilter {
+            defer_tolerance: Some(20),
+            ..ActionFilter::default()
+        };
+        let messages = get_actions(&pegging(), &part_plants(), &filter);
+        assert!(messages.iter().all(|m| m.action != ActionType::Defer));
+
+        let filter = ActionFilter {
+            buyer: Some("jane".to_owned()),
+            action: Some(ActionType::Cancel),
+            ..ActionFilter::default()
+        };
+        assert_eq!(get_actions(&pegging(), &part_plants(), &filter).len(), 1);
+
+        let filter = ActionFilter {
+            planner: Some("ALICE".to_owned()),
+            ..ActionFilter::default()
+        };
+        assert!(get_actions(&pegging(), &part_plants(), &filter).is_empty());
+
+        let filter = ActionFilter {
+            due_from: Some(date(20)),
+            due_to: Some(date(26)),
+            ..ActionFilter::default()
+        };
+        let messages = get_actions(&pegging(), &part_plants(), &filter);
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn reduces_supply_that_is_partly_pegged() {
+        let mut pegging = pegging();
+        // PO 2 has 2 pegged and 3 left over
+        let left_over = ExcessSupply {
+            due_date: date(11),
+            qty: dec!(3),
+            po_num: Some(2),
+            uom_qty: dec!(3),
+            ..pegging.excess["RAW"][0].clone()
+        };
+        pegging.excess.get_mut("RAW").unwrap().push(left_over);
+
+        let messages = get_actions(&pegging, &part_plants(), &ActionFilter::default());
+
+        let reduce: Vec<&ActionMessage> = messages
+            .iter()
+            .filter(|m| m.action == ActionType::Reduce)
+            .collect();
+        assert_eq!(reduce.len(), 1);
+        assert_eq!(reduce[0].po_num, Some(2));
+        assert_eq!(reduce[0].qty, dec!(3));
+
+        // Only PO 3, with nothing pegged, is cancelled
+        let cancel: Vec<&ActionMessage> = messages
+            .iter()
+            .filter(|m| m.action == ActionType::Cancel)
+            .collect();
+        assert_eq!(cancel.len(), 1);
+        assert_eq!(cancel[0].po_num, Some(3));
+    }
+}
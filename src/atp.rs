@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{onhand::OnHand, peg::Pegging};
+
+/// Query string parameters for ATP. With a `qty` the earliest date it can be promised is
+/// worked out, and with a `date` as well whether it can be promised by then.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AtpQuery {
+    pub qty: Option<Decimal>,
+    pub date: Option<NaiveDate>,
+}
+
+/// Supply that becomes free to promise on a date
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct AtpBucket {
+    pub date: NaiveDate,
+    pub available_qty: Decimal,
+    pub cumulative_qty: Decimal,
+}
+
+/// Available to promise of a part in one plant
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct PartAtp {
+    pub part_number: String,
+    pub plant: String,
+    pub on_hand: Decimal,
+    pub atp: Vec<AtpBucket>,
+    /// The earliest date the requested quantity is available, if it ever is
+    pub promise_date: Option<NaiveDate>,
+    /// What is available by the requested date
+    pub available_by_date: Option<Decimal>,
+    pub can_promise: Option<bool>,
+}
+
+/// Works out ATP of the part in every plant it has on hand, demand or supply in.
+///
+/// Whatever supply the pegging leaves unpegged is free to promise from its due date, while
+/// supply pegged to demand, held as safety stock or made direct for a job is already
+/// committed. On hand and past due supply are available today.
+pub fn get_part_atp(
+    pegging: &Pegging,
+    on_hand: &[OnHand],
+    part_num: &str,
+    query: &AtpQuery,
+    today: NaiveDate,
+) -> Vec<PartAtp> {
+    let mut plants: BTreeMap<String, (Decimal, BTreeMap<NaiveDate, Decimal>)> = BTreeMap::new();
+
+    on_hand
+        .iter()
+        .filter(|row| row.part_num == part_num)
+        .for_each(|row| plants.entry(row.site.to_owned()).or_default().0 += row.qty);
+
+    if let Some(demands) = pegging.demand.get(part_num) {
+        demands.iter().for_each(|demand| {
+            plants.entry(demand.plant.to_owned()).or_default();
+        });
+    }
+
+    // A part without any PartDtl is never pegged, so all of its on hand is free
+    if !pegging.demand.contains_key(part_num) && !pegging.excess.contains_key(part_num) {
+        plants.values_mut().for_each(|(on_hand, available)| {
+            if *on_hand > Decimal::ZERO {
+                available.insert(today, *on_hand);
+            }
+        });
+    }
+
+    if let Some(excess) = pegging.excess.get(part_num) {
+        excess.iter().for_each(|supply| {
+            *plants
+                .entry(supply.plant.to_owned())
+                .or_default()
+                .1
+                .entry(NaiveDate::max(supply.due_date, today))
+                .or_default() += supply.qty;
+        });
+    }
+
+    plants
+        .into_iter()
+        .map(|(plant, (on_hand, available))| {
+            let mut cumulative_qty = Decimal::ZERO;
+            let atp: Vec<AtpBucket> = available
+                .into_iter()
+                .map(|(date, available_qty)| {
+                    cumulative_qty += available_qty;
+                    AtpBucket {
+                        date,
+                        available_qty,
+                        cumulative_qty,
+                    }
+                })
+                .collect();
+
+            let promise_date = query.qty.and_then(|qty| match qty > Decimal::ZERO {
+                true => atp
+                    .iter()
+                    .find(|bucket| bucket.cumulative_qty >= qty)
+                    .map(|bucket| bucket.date),
+                false => Some(today),
+            });
+            let available_by_date = query.date.map(|date| {
+                atp.iter()
+                    .take_while(|bucket| bucket.date <= date)
+                    .last()
+                    .map_or(Decimal::ZERO, |bucket| bucket.cumulative_qty)
+            });
+            let can_promise = match (query.qty, query.date) {
+                (Some(_), Some(date)) => Some(promise_date.is_some_and(|promise| promise <= date)),
+                _ => None,
+            };
+
+            PartAtp {
+                part_number: part_num.to_owned(),
+                plant,
+                on_hand,
+                atp,
+                promise_date,
+                available_by_date,
+                can_promise,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peg::{PegOptions, SafetyStock};
+    use crate::test_support::{date, on_hand, peg, row};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn promises_from_supply_left_free_by_pegging() {
+        let part_dtl = vec![
+            row("FG", true, 8, dec!(6)),
+            row("FG", false, 10, dec!(10)),
+            row("FG", true, 15, dec!(3)),
+            row("FG", false, 20, dec!(5)),
+        ];
+        let on_hand = vec![on_hand("FG", dec!(4))];
+        let pegging = peg(&part_dtl, &on_hand, &SafetyStock::new(), &PegOptions::default());
+        let query = AtpQuery {
+            qty: Some(dec!(8)),
+            date: Some(date(12)),
+        };
+
+        let atp = get_part_atp(&pegging, &on_hand, "FG", &query, date(1));
+        assert_eq!(atp.len(), 1);
+
+        // On hand and the first PO cover both orders, leaving 5 of the PO and the second PO
+        let buckets: Vec<(NaiveDate, Decimal)> = atp[0]
+            .atp
+            .iter()
+            .map(|b| (b.date, b.cumulative_qty))
+            .collect();
+        assert_eq!(buckets, vec![(date(10), dec!(5)), (date(20), dec!(10))]);
+        assert_eq!(atp[0].promise_date, Some(date(20)));
+        assert_eq!(atp[0].available_by_date, Some(dec!(5)));
+        assert_eq!(atp[0].can_promise, Some(false));
+
+        assert!(get_part_atp(&pegging, &on_hand, "NONE", &query, date(1)).is_empty());
+    }
+}
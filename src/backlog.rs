@@ -1,39 +1,112 @@
-use crate::{orderrelease::OrderRelease, sql::get_sql_config};
-use async_std::net::TcpStream;
+use crate::{
+    config::PlanningScope,
+    orderrelease::{Coverage, OrderRelease},
+    sql::{parameter_list, SqlPool},
+};
 use chrono::NaiveDate;
-use rust_decimal::Decimal;
-use rust_decimal_macros::dec;
-use std::io::{Error, ErrorKind};
-use tiberius::{Client, Query};
+use serde::Deserialize;
+use tiberius::{Query, Row};
 
-pub async fn get_backlog_result() -> Result<Vec<OrderRelease>, anyhow::Error> {
-    let config = get_sql_config();
+/// Query string filters for the backlog. Due dates are inclusive.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct BacklogFilter {
+    pub customer: Option<String>,
+    pub part: Option<String>,
+    pub due_from: Option<NaiveDate>,
+    pub due_to: Option<NaiveDate>,
+}
 
-    // Create TCP TcpStream
-    let tcp = TcpStream::connect(&config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
+impl BacklogFilter {
+    pub fn matches(&self, order_release: &OrderRelease) -> bool {
+        if let Some(customer) = &self.customer {
+            match &order_release.customer {
+                Some(cust_id) if cust_id.eq_ignore_ascii_case(customer) => {}
+                _ => return false,
+            }
+        }
 
-    // Connect to server
-    let mut client = Client::connect(config, tcp).await?;
+        if let Some(part) = &self.part {
+            if &order_release.part_number != part {
+                return false;
+            }
+        }
 
-    // Construct Query
-    let query_string = "
+        if self.due_from.is_some() || self.due_to.is_some() {
+            let due_date = match order_release.due_date {
+                Some(due_date) => due_date,
+                None => return false,
+            };
+
+            if self.due_from.is_some_and(|from| due_date < from)
+                || self.due_to.is_some_and(|to| due_date > to)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+// Columns shared by every OrderRel query
+const ORDER_RELEASE_SELECT: &str = "
             SELECT
                 OrderRel.OrderNum,
                 OrderRel.OrderLine,
                 OrderRel.OrderRelNum,
-                OrderRel.PartNum
+                OrderRel.Plant,
+                OrderRel.PartNum,
+                OrderRel.ReqDate,
+                Customer.CustID
             FROM 
                 Erp.OrderRel
+            LEFT OUTER JOIN Erp.OrderHed as OrderHed on
+                OrderHed.Company = OrderRel.Company
+                and OrderHed.OrderNum = OrderRel.OrderNum
+            LEFT OUTER JOIN Erp.Customer as Customer on
+                Customer.Company = OrderHed.Company
+                and Customer.CustNum = OrderHed.CustNum
+";
+
+fn transform_row_to_order_release(val: &Row) -> OrderRelease {
+    OrderRelease {
+        order: val.get::<i32, _>("OrderNum"),
+        line: val.get::<i32, _>("OrderLine"),
+        release: val.get::<i32, _>("OrderRelNum"),
+        plant: val.get::<&str, &str>("Plant").unwrap_or("").to_owned(),
+        part_number: val.get::<&str, &str>("PartNum").unwrap_or("").to_owned(),
+        customer: val.get::<&str, &str>("CustID").map(|s| s.to_owned()),
+        due_date: val.get::<NaiveDate, _>("ReqDate"),
+        coverage: Coverage::Uncovered,
+        satisfied_date: None,
+        demand: vec![],
+    }
+}
+
+pub async fn get_backlog_result(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+) -> Result<Vec<OrderRelease>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
+
+    // Construct Query
+    let mut query_string = ORDER_RELEASE_SELECT.to_string();
+    query_string.push_str(&format!(
+        "
             WHERE 
-                OrderRel.Company = 'AE'
+                OrderRel.Company = @P1
                 and OrderRel.OpenRelease = 1
                 and OrderRel.FirmRelease = 1
-                and OrderRel.Plant = 'MfgSys'
-            "
-    .to_string();
+                and OrderRel.Plant IN ({})
+            ",
+        parameter_list(1, scope.plants.len())
+    ));
+
+    let mut select = Query::new(query_string);
 
-    let select = Query::new(query_string);
+    select.bind(scope.company.to_owned());
+    scope.plants.iter().for_each(|plant| select.bind(plant.to_owned()));
 
     // Stream Query
     let stream = select.query(&mut client).await?;
@@ -42,30 +115,110 @@ pub async fn get_backlog_result() -> Result<Vec<OrderRelease>, anyhow::Error> {
     let row = stream.into_first_result().await?;
 
     // Transform Rows into result type
-    let mut result: Vec<OrderRelease> = vec![];
-
-    row.iter().for_each(|val| {
-        let order = val.get::<i32, _>("OrderNum");
-        let line = val.get::<i32, _>("OrderLine");
-        let release = val.get::<i32, _>("OrderRelNum");
-        let part_number = val.get::<&str, &str>("PartNum").unwrap().to_owned();
-
-        result.push(OrderRelease {
-            order,
-            line,
-            release,
-            part_number,
+    let result: Vec<OrderRelease> = row.iter().map(transform_row_to_order_release).collect();
+
+    // Result set should be cached now
+
+    Ok(result)
+}
+
+pub async fn get_order_release(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+    order: i32,
+    line: i32,
+    release: i32,
+) -> Result<Option<OrderRelease>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
+
+    // Construct Query
+    let mut query_string = ORDER_RELEASE_SELECT.to_string();
+    query_string.push_str(&format!(
+        "
+            WHERE 
+                OrderRel.OrderNum = @P1
+                and OrderRel.OrderLine = @P2
+                and OrderRel.OrderRelNum = @P3
+                and OrderRel.Company = @P4
+                and OrderRel.Plant IN ({})
+            ",
+        parameter_list(4, scope.plants.len())
+    ));
+
+    let mut select = Query::new(query_string);
+
+    select.bind(order);
+    select.bind(line);
+    select.bind(release);
+    select.bind(scope.company.to_owned());
+    scope.plants.iter().for_each(|plant| select.bind(plant.to_owned()));
+
+    // Stream Query
+    let stream = select.query(&mut client).await?;
+
+    // Consume stream
+    let row = stream.into_first_result().await?;
+
+    let result = row.first().map(transform_row_to_order_release);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, month, day).unwrap()
+    }
+
+    fn release(part_number: &str, customer: &str, due_date: NaiveDate) -> OrderRelease {
+        OrderRelease {
+            order: Some(1),
+            line: Some(1),
+            release: Some(1),
+            plant: "MfgSys".to_owned(),
+            part_number: part_number.to_owned(),
+            customer: Some(customer.to_owned()),
+            due_date: Some(due_date),
+            coverage: Coverage::Uncovered,
+            satisfied_date: None,
             demand: vec![],
-        });
-    });
+        }
+    }
 
-    // Close Client Connection
-    client
-        .close()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = BacklogFilter::default();
 
-    // Result set should be cached now
+        assert!(filter.matches(&release("FG", "ACME", date(3, 1))));
+    }
+
+    #[test]
+    fn filters_on_customer_and_part() {
+        let filter = BacklogFilter {
+            customer: Some("acme".to_owned()),
+            part: Some("FG".to_owned()),
+            ..Default::default()
+        };
+
+        assert!(filter.matches(&release("FG", "ACME", date(3, 1))));
+        assert!(!filter.matches(&release("FG", "GLOBEX", date(3, 1))));
+        assert!(!filter.matches(&release("SUB", "ACME", date(3, 1))));
+    }
+
+    #[test]
+    fn due_date_range_is_inclusive() {
+        let filter = BacklogFilter {
+            due_from: Some(date(3, 1)),
+            due_to: Some(date(3, 31)),
+            ..Default::default()
+        };
 
-    return Ok(result);
+        assert!(filter.matches(&release("FG", "ACME", date(3, 1))));
+        assert!(filter.matches(&release("FG", "ACME", date(3, 31))));
+        assert!(!filter.matches(&release("FG", "ACME", date(2, 29))));
+        assert!(!filter.matches(&release("FG", "ACME", date(4, 1))));
+    }
 }
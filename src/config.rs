@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::peg::{PegOptions, PriorityRule, SupplyStrategy};
+
+const DEFAULT_COMPANY: &str = "AE";
+const DEFAULT_PLANT: &str = "MfgSys";
+const DEFAULT_EXCLUDED_PROD_CODES: &str = "ETO,RMA,SAMPLE,TOOL";
+const DEFAULT_EXCLUDED_WAREHOUSE_PLANTS: &str = "CONS%";
+
+/// The slice of the ERP that a snapshot is read from and pegged over
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct PlanningScope {
+    pub company: String,
+    pub plants: Vec<String>,
+    pub excluded_prod_codes: Vec<String>,
+    /// `LIKE` patterns for warehouse plants whose on hand is never nettable
+    pub excluded_warehouse_plants: Vec<String>,
+    pub safety_stock: Vec<SafetyStockOverride>,
+}
+
+/// A safety stock level set in the configuration, which wins over the level on PartPlant.
+/// Without a plant it applies in every plant.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct SafetyStockOverride {
+    pub part_num: String,
+    pub plant: Option<String>,
+    pub qty: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompanyConfig {
+    pub company: String,
+    pub plants: Vec<String>,
+}
+
+/// The companies and plants one deployment serves. The first company is the default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanningConfig {
+    pub companies: Vec<CompanyConfig>,
+    pub excluded_prod_codes: Vec<String>,
+    pub excluded_warehouse_plants: Vec<String>,
+    /// Whether pegging may transfer supply between plants unless a request says otherwise
+    pub allow_transfers: bool,
+    /// Whether pegging holds safety stock back unless a request says otherwise
+    pub reserve_safety_stock: bool,
+    pub safety_stock: Vec<SafetyStockOverride>,
+    /// Demand priority rules used unless a request says otherwise
+    pub priority: Vec<PriorityRule>,
+    pub customer_priority: Vec<String>,
+    /// How demand picks supply unless a request says otherwise
+    pub supply_strategy: SupplyStrategy,
+    /// Whether shortages are covered with substitute parts unless a request says otherwise
+    pub use_substitutes: bool,
+}
+
+/// Query string parameters for picking a company and plants, along with how to peg them.
+/// `plant` may hold several comma separated plants and `priority` several comma separated
+/// demand priority rules.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PlanningQuery {
+    pub company: Option<String>,
+    pub plant: Option<String>,
+    pub transfers: Option<bool>,
+    pub safety_stock: Option<bool>,
+    pub priority: Option<String>,
+    pub strategy: Option<String>,
+    pub substitutes: Option<bool>,
+}
+
+impl PlanningQuery {
+    pub fn is_empty(&self) -> bool {
+        self.company.is_none() && self.plant.is_none()
+    }
+}
+
+impl PlanningConfig {
+    /// Reads the configuration from the environmental variables. When PLANNING_CONFIG_FILE
+    /// names a TOML file, anything not set in the environment is read from it instead.
+    pub fn from_env() -> Result<PlanningConfig, anyhow::Error> {
+        let file = match env::var("PLANNING_CONFIG_FILE") {
+            Ok(path) if !path.trim().is_empty() => {
+                let contents = fs::read_to_string(path.trim())
+                    .map_err(|e| anyhow::anyhow!("PLANNING_CONFIG_FILE '{}': {}", path, e))?;
+                parse_config_file(&contents)
+                    .map_err(|e| anyhow::anyhow!("PLANNING_CONFIG_FILE '{}': {}", path, e))?
+            }
+            _ => HashMap::new(),
+        };
+
+        PlanningConfig::from_vars(|name| {
+            env::var(name)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+                .or_else(|| file.get(name).cloned())
+        })
+    }
+
+    /// Builds the configuration from a variable lookup.
+    ///
+    /// PLANNING_COMPANIES lists the companies, PLANNING_PLANTS the plants used for every
+    /// company and PLANNING_PLANTS_{COMPANY} overrides the plants for a single company.
+    /// SAFETY_STOCK lists `part=qty` or `part@plant=qty` levels. DEMAND_PRIORITY lists the
+    /// demand priority rules and CUSTOMER_PRIORITY the customers, the most important first.
+    /// SUPPLY_STRATEGY picks how demand takes supply and USE_SUBSTITUTES turns on covering
+    /// shortages with substitute parts.
+    ///
+    /// Blank variables are treated as unset, so the defaults hold for anything left empty.
+    /// EXCLUDED_PROD_CODES and EXCLUDED_WAREHOUSE_PLANTS are turned off with `none`.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<PlanningConfig, anyhow::Error> {
+        let var = |name: &str| var(name).filter(|value| !value.trim().is_empty());
+        let default_plants = split_list(
+            &var("PLANNING_PLANTS").unwrap_or(DEFAULT_PLANT.to_owned()),
+        );
+
+        let companies: Vec<CompanyConfig> =
+            split_list(&var("PLANNING_COMPANIES").unwrap_or(DEFAULT_COMPANY.to_owned()))
+                .into_iter()
+                .map(|company| {
+                    let plants = var(&format!("PLANNING_PLANTS_{}", env_suffix(&company)))
+                        .map(|plants| split_list(&plants))
+                        .unwrap_or(default_plants.clone());
+                    CompanyConfig { company, plants }
+                })
+                .collect();
+
+        if companies.is_empty() {
+            return Err(anyhow::anyhow!("PLANNING_COMPANIES must list at least one company"));
+        }
+
+        if let Some(company) = companies.iter().find(|company| company.plants.is_empty()) {
+            return Err(anyhow::anyhow!(
+                "No plants are configured for company '{}'",
+                company.company
+            ));
+        }
+
+        let safety_stock = split_list(&var("SAFETY_STOCK").unwrap_or_default())
+            .iter()
+            .map(|level| parse_safety_stock(level))
+            .collect::<Result<Vec<SafetyStockOverride>, anyhow::Error>>()?;
+
+        let priority = parse_priority(&var("DEMAND_PRIORITY").unwrap_or_default())
+            .map_err(|e| anyhow::anyhow!("DEMAND_PRIORITY: {}", e))?;
+        let supply_strategy = match var("SUPPLY_STRATEGY") {
+            Some(strategy) if !strategy.trim().is_empty() => parse_strategy(&strategy)
+                .map_err(|e| anyhow::anyhow!("SUPPLY_STRATEGY: {}", e))?,
+            _ => SupplyStrategy::default(),
+        };
+
+        Ok(PlanningConfig {
+            companies,
+            excluded_prod_codes: exclusion_list(
+                &var("EXCLUDED_PROD_CODES").unwrap_or(DEFAULT_EXCLUDED_PROD_CODES.to_owned()),
+            ),
+            excluded_warehouse_plants: exclusion_list(
+                &var("EXCLUDED_WAREHOUSE_PLANTS")
+                    .unwrap_or(DEFAULT_EXCLUDED_WAREHOUSE_PLANTS.to_owned()),
+            ),
+            allow_transfers: var("ALLOW_PLANT_TRANSFERS")
+                .map(|allow| allow.trim().eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            reserve_safety_stock: var("RESERVE_SAFETY_STOCK")
+                .map(|reserve| reserve.trim().eq_ignore_ascii_case("true"))
+                .unwrap_or(true),
+            safety_stock,
+            priority,
+            customer_priority: split_list(&var("CUSTOMER_PRIORITY").unwrap_or_default()),
+            supply_strategy,
+            use_substitutes: var("USE_SUBSTITUTES")
+                .map(|substitutes| substitutes.trim().eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+        })
+    }
+
+    /// The pegging options the snapshots are built with
+    pub fn default_peg_options(&self) -> PegOptions {
+        PegOptions {
+            allow_transfers: self.allow_transfers,
+            reserve_safety_stock: self.reserve_safety_stock,
+            priority: self.priority.clone(),
+            customer_priority: self.customer_priority.clone(),
+            supply_strategy: self.supply_strategy,
+            use_substitutes: self.use_substitutes,
+        }
+    }
+
+    /// The pegging options asked for on a request, falling back to the configured defaults
+    pub fn peg_options(&self, query: &PlanningQuery) -> Result<PegOptions, String> {
+        let priority = match &query.priority {
+            Some(priority) => parse_priority(priority)?,
+            None => self.priority.clone(),
+        };
+        let supply_strategy = match &query.strategy {
+            Some(strategy) => parse_strategy(strategy)?,
+            None => self.supply_strategy,
+        };
+
+        Ok(PegOptions {
+            allow_transfers: query.transfers.unwrap_or(self.allow_transfers),
+            reserve_safety_stock: query.safety_stock.unwrap_or(self.reserve_safety_stock),
+            priority,
+            customer_priority: self.customer_priority.clone(),
+            supply_strategy,
+            use_substitutes: query.substitutes.unwrap_or(self.use_substitutes),
+        })
+    }
+
+    /// One scope per company covering all of its plants
+    pub fn default_scopes(&self) -> Vec<PlanningScope> {
+        self.companies
+            .iter()
+            .map(|company| self.build_scope(company, company.plants.clone()))
+            .collect()
+    }
+
+    /// Resolves the company and plants picked on a request. Missing values fall back to the
+    /// default company and all of the company's plants.
+    pub fn scope(&self, query: &PlanningQuery) -> Result<PlanningScope, String> {
+        let company = match &query.company {
+            Some(requested) => self
+                .companies
+                .iter()
+                .find(|company| company.company.eq_ignore_ascii_case(requested))
+                .ok_or(format!("Company '{}' is not configured", requested))?,
+            None => &self.companies[0],
+        };
+
+        let plants = match &query.plant {
+            Some(requested) => split_list(requested)
+                .iter()
+                .map(|requested| {
+                    company
+                        .plants
+                        .iter()
+                        .find(|plant| plant.eq_ignore_ascii_case(requested))
+                        .cloned()
+                        .ok_or(format!(
+                            "Plant '{}' is not configured for company '{}'",
+                            requested, company.company
+                        ))
+                })
+                .collect::<Result<Vec<String>, String>>()?,
+            None => company.plants.clone(),
+        };
+
+        if plants.is_empty() {
+            return Err("At least one plant must be given".to_owned());
+        }
+
+        Ok(self.build_scope(company, plants))
+    }
+
+    fn build_scope(&self, company: &CompanyConfig, mut plants: Vec<String>) -> PlanningScope {
+        // Keep scopes comparable no matter what order the plants were asked for in
+        plants.sort();
+        plants.dedup();
+
+        PlanningScope {
+            company: company.company.to_owned(),
+            plants,
+            excluded_prod_codes: self.excluded_prod_codes.clone(),
+            excluded_warehouse_plants: self.excluded_warehouse_plants.clone(),
+            safety_stock: self.safety_stock.clone(),
+        }
+    }
+}
+
+/// Splits a comma separated list, dropping blanks
+pub fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_owned())
+        .collect()
+}
+
+// Parses a comma separated list of demand priority rules
+fn parse_priority(rules: &str) -> Result<Vec<PriorityRule>, String> {
+    split_list(rules)
+        .iter()
+        .map(|rule| {
+            PriorityRule::parse(rule).ok_or(format!(
+                "Unknown priority rule '{}', expected due_date, source, firm or customer",
+                rule
+            ))
+        })
+        .collect()
+}
+
+fn parse_strategy(strategy: &str) -> Result<SupplyStrategy, String> {
+    SupplyStrategy::parse(strategy).ok_or(format!(
+        "Unknown supply strategy '{}', expected earliest_due, on_hand_last, prefer_job, \
+         closest_date or no_late_supply",
+        strategy
+    ))
+}
+
+// Parses `part=qty` or `part@plant=qty`
+fn parse_safety_stock(level: &str) -> Result<SafetyStockOverride, anyhow::Error> {
+    let (part, qty) = level
+        .rsplit_once('=')
+        .ok_or(anyhow::anyhow!("Safety stock '{}' is not part=qty", level))?;
+    let qty = qty
+        .trim()
+        .parse::<Decimal>()
+        .map_err(|e| anyhow::anyhow!("Safety stock '{}' has a bad quantity: {}", level, e))?;
+
+    let (part_num, plant) = match part.split_once('@') {
+        Some((part_num, plant)) => (part_num, Some(plant.trim().to_owned())),
+        None => (part, None),
+    };
+
+    Ok(SafetyStockOverride {
+        part_num: part_num.trim().to_owned(),
+        plant,
+        qty,
+    })
+}
+
+/// Reads a TOML file holding the same settings as the environmental variables, keyed by
+/// the variable names, e.g. `PLANNING_COMPANIES = ["AE", "BE"]` or
+/// `RESERVE_SAFETY_STOCK = false`. Lists are joined with commas so that every value reads the
+/// same as its variable would.
+fn parse_config_file(contents: &str) -> Result<HashMap<String, String>, anyhow::Error> {
+    let table: toml::Table = contents.parse()?;
+
+    table
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                toml::Value::Array(values) => values
+                    .into_iter()
+                    .map(config_value)
+                    .collect::<Option<Vec<String>>>()
+                    .map(|values| values.join(",")),
+                value => config_value(value),
+            }
+            .ok_or(anyhow::anyhow!("{} must be a string, number, boolean or list of them", name))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn config_value(value: toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// A list of exclusions, where `none` turns them off
+fn exclusion_list(value: &str) -> Vec<String> {
+    match value.trim().eq_ignore_ascii_case("none") {
+        true => vec![],
+        false => split_list(value),
+    }
+}
+
+fn env_suffix(company: &str) -> String {
+    company
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn config(vars: &[(&str, &str)]) -> PlanningConfig {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        PlanningConfig::from_vars(|name| vars.get(name).cloned()).unwrap()
+    }
+
+    #[test]
+    fn defaults_to_the_original_company_and_plant() {
+        let config = config(&[]);
+
+        let scope = config.scope(&PlanningQuery::default()).unwrap();
+        assert_eq!(scope.company, "AE");
+        assert_eq!(scope.plants, vec!["MfgSys"]);
+        assert_eq!(scope.excluded_prod_codes, vec!["ETO", "RMA", "SAMPLE", "TOOL"]);
+        assert_eq!(scope.excluded_warehouse_plants, vec!["CONS%"]);
+        assert!(!config.default_peg_options().allow_transfers);
+        assert!(config.default_peg_options().reserve_safety_stock);
+        assert!(!config.default_peg_options().use_substitutes);
+        assert!(scope.safety_stock.is_empty());
+    }
+
+    #[test]
+    fn reads_safety_stock_levels() {
+        let config = config(&[("SAFETY_STOCK", "RAW=10, SUB@West=2.5")]);
+
+        assert_eq!(
+            config.safety_stock,
+            vec![
+                SafetyStockOverride {
+                    part_num: "RAW".to_owned(),
+                    plant: None,
+                    qty: Decimal::from(10),
+                },
+                SafetyStockOverride {
+                    part_num: "SUB".to_owned(),
+                    plant: Some("West".to_owned()),
+                    qty: Decimal::new(25, 1),
+                },
+            ]
+        );
+        assert!(PlanningConfig::from_vars(|name| match name {
+            "SAFETY_STOCK" => Some("RAW".to_owned()),
+            _ => None,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn requests_can_turn_transfers_on_and_off() {
+        let config = config(&[("ALLOW_PLANT_TRANSFERS", "true")]);
+
+        assert!(config.peg_options(&PlanningQuery::default()).unwrap().allow_transfers);
+        assert!(!config
+            .peg_options(&PlanningQuery {
+                transfers: Some(false),
+                ..PlanningQuery::default()
+            })
+            .unwrap()
+            .allow_transfers);
+    }
+
+    #[test]
+    fn requests_can_opt_in_to_substitutes() {
+        let config = config(&[]);
+
+        assert!(config
+            .peg_options(&PlanningQuery {
+                substitutes: Some(true),
+                ..PlanningQuery::default()
+            })
+            .unwrap()
+            .use_substitutes);
+    }
+
+    #[test]
+    fn requests_can_pick_the_demand_priority() {
+        let config = config(&[("DEMAND_PRIORITY", "firm"), ("CUSTOMER_PRIORITY", "BIG,SMALL")]);
+
+        let options = config.peg_options(&PlanningQuery::default()).unwrap();
+        assert_eq!(options.priority, vec![PriorityRule::Firm]);
+        assert_eq!(options.customer_priority, vec!["BIG", "SMALL"]);
+
+        let options = config
+            .peg_options(&PlanningQuery {
+                priority: Some("Source, due_date".to_owned()),
+                ..PlanningQuery::default()
+            })
+            .unwrap();
+        assert_eq!(options.priority, vec![PriorityRule::SourceFile, PriorityRule::DueDate]);
+
+        assert!(config
+            .peg_options(&PlanningQuery {
+                priority: Some("loudest".to_owned()),
+                ..PlanningQuery::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn requests_can_pick_the_supply_strategy() {
+        let config = config(&[("SUPPLY_STRATEGY", "prefer_job")]);
+
+        assert_eq!(
+            config.default_peg_options().supply_strategy,
+            SupplyStrategy::PreferJob
+        );
+        assert_eq!(
+            config
+                .peg_options(&PlanningQuery {
+                    strategy: Some("No_Late_Supply".to_owned()),
+                    ..PlanningQuery::default()
+                })
+                .unwrap()
+                .supply_strategy,
+            SupplyStrategy::NoLateSupply
+        );
+        assert!(config
+            .peg_options(&PlanningQuery {
+                strategy: Some("random".to_owned()),
+                ..PlanningQuery::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn blank_variables_fall_back_to_the_defaults() {
+        // As left by copying .env.example to .env unchanged
+        let config = config(&[
+            ("PLANNING_COMPANIES", ""),
+            ("PLANNING_PLANTS", " "),
+            ("PLANNING_PLANTS_AE", ""),
+            ("EXCLUDED_PROD_CODES", ""),
+            ("EXCLUDED_WAREHOUSE_PLANTS", ""),
+            ("ALLOW_PLANT_TRANSFERS", ""),
+            ("SAFETY_STOCK", ""),
+            ("DEMAND_PRIORITY", ""),
+            ("CUSTOMER_PRIORITY", ""),
+            ("SUPPLY_STRATEGY", ""),
+            ("USE_SUBSTITUTES", ""),
+        ]);
+
+        assert_eq!(config, PlanningConfig::from_vars(|_| None).unwrap());
+        let scope = config.scope(&PlanningQuery::default()).unwrap();
+        assert_eq!(scope.company, "AE");
+        assert_eq!(scope.plants, vec!["MfgSys"]);
+        assert_eq!(scope.excluded_prod_codes, vec!["ETO", "RMA", "SAMPLE", "TOOL"]);
+    }
+
+    #[test]
+    fn reads_settings_from_a_toml_file() {
+        let file = parse_config_file(
+            "
+            PLANNING_COMPANIES = [\"AE\", \"SD\"]
+            RESERVE_SAFETY_STOCK = false
+            SAFETY_STOCK = \"RAW=10\"
+            ",
+        )
+        .unwrap();
+        let config = PlanningConfig::from_vars(|name| file.get(name).cloned()).unwrap();
+
+        assert_eq!(config.companies.len(), 2);
+        assert_eq!(config.companies[1].company, "SD");
+        assert!(!config.default_peg_options().reserve_safety_stock);
+        assert_eq!(config.safety_stock[0].qty, dec!(10));
+
+        assert!(parse_config_file("SAFETY_STOCK = { RAW = 10 }").is_err());
+    }
+
+    #[test]
+    fn picks_company_and_plants_per_request() {
+        let config = config(&[
+            ("PLANNING_COMPANIES", "AE, SD"),
+            ("PLANNING_PLANTS_SD", "East,West"),
+            ("EXCLUDED_PROD_CODES", "none"),
+        ]);
+
+        let scope = config
+            .scope(&PlanningQuery {
+                company: Some("sd".to_owned()),
+                plant: None,
+                transfers: None,
+                safety_stock: None,
+                priority: None,
+                strategy: None,
+                substitutes: None,
+            })
+            .unwrap();
+        assert_eq!(scope.company, "SD");
+        assert_eq!(scope.plants, vec!["East", "West"]);
+        assert!(scope.excluded_prod_codes.is_empty());
+
+        let scope = config
+            .scope(&PlanningQuery {
+                company: Some("SD".to_owned()),
+                plant: Some("west".to_owned()),
+                transfers: None,
+                safety_stock: None,
+                priority: None,
+                strategy: None,
+                substitutes: None,
+            })
+            .unwrap();
+        assert_eq!(scope.plants, vec!["West"]);
+
+        assert!(config
+            .scope(&PlanningQuery {
+                company: Some("SD".to_owned()),
+                plant: Some("MfgSys".to_owned()),
+                transfers: None,
+                safety_stock: None,
+                priority: None,
+                strategy: None,
+                substitutes: None,
+            })
+            .is_err());
+        assert!(config
+            .scope(&PlanningQuery {
+                company: Some("XX".to_owned()),
+                plant: None,
+                transfers: None,
+                safety_stock: None,
+                priority: None,
+                strategy: None,
+                substitutes: None,
+            })
+            .is_err());
+        assert_eq!(config.default_scopes().len(), 2);
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDate};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    atp::{get_part_atp, AtpQuery},
+    onhand::OnHand,
+    partmtl::Boms,
+    partplant::PartPlant,
+    peg::Pegging,
+};
+
+/// Query string parameters for CTP. `qty` is required, and with a `date` as well whether it
+/// can be promised by then is worked out.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CtpQuery {
+    pub qty: Decimal,
+    pub date: Option<NaiveDate>,
+}
+
+/// How a material on the BOM would be covered
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct CtpComponent {
+    pub part_number: String,
+    pub parent_part_number: String,
+    pub level: u32,
+    pub required_qty: Decimal,
+    /// Taken from what the material has available to promise
+    pub atp_qty: Decimal,
+    /// Made from the material's own BOM
+    pub build_qty: Decimal,
+    /// Bought in the material's lead time
+    pub buy_qty: Decimal,
+    pub available_date: NaiveDate,
+}
+
+/// Capable to promise of a part in one plant
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct PartCtp {
+    pub part_number: String,
+    pub plant: String,
+    pub qty: Decimal,
+    pub atp_qty: Decimal,
+    pub build_qty: Decimal,
+    pub buy_qty: Decimal,
+    pub promise_date: NaiveDate,
+    pub can_promise: Option<bool>,
+    /// The material holding the promise date back, if any
+    pub limiting_component: Option<String>,
+    pub components: Vec<CtpComponent>,
+}
+
+/// How a quantity of a part is covered and when it is all there
+struct Coverage {
+    atp_qty: Decimal,
+    build_qty: Decimal,
+    buy_qty: Decimal,
+    date: NaiveDate,
+    limiting: Option<String>,
+}
+
+/// Walks the BOM of one plant, handing out the ATP of each part once so a material used in
+/// several places is not promised twice
+struct BomWalk<'a> {
+    pegging: &'a Pegging,
+    on_hand: &'a [OnHand],
+    boms: &'a Boms,
+    part_plants: &'a HashMap<(String, String), PartPlant>,
+    plant: String,
+    today: NaiveDate,
+    // What is still free of each part, by date
+    available: HashMap<String, Vec<(NaiveDate, Decimal)>>,
+    components: Vec<CtpComponent>,
+}
+
+impl BomWalk<'_> {
+    /// Takes up to `qty` of the part's ATP, earliest first. Returns what was taken and the
+    /// date the last of it is available.
+    fn take_atp(&mut self, part_num: &str, qty: Decimal) -> (Decimal, NaiveDate) {
+        let (pegging, on_hand, plant) = (self.pegging, self.on_hand, &self.plant);
+        let buckets = self
+            .available
+            .entry(part_num.to_owned())
+            .or_insert_with(|| {
+                get_part_atp(pegging, on_hand, part_num, &AtpQuery::default(), self.today)
+                    .into_iter()
+                    .filter(|atp| &atp.plant == plant)
+                    .flat_map(|atp| atp.atp)
+                    .map(|bucket| (bucket.date, bucket.available_qty))
+                    .collect()
+            });
+
+        let mut taken = Decimal::ZERO;
+        let mut date = self.today;
+        for (bucket_date, bucket_qty) in buckets.iter_mut() {
+            if taken >= qty {
+                break;
+            }
+            let take = Decimal::min(*bucket_qty, qty - taken);
+            if take > Decimal::ZERO {
+                *bucket_qty -= take;
+                taken += take;
+                date = *bucket_date;
+            }
+        }
+
+        (taken, date)
+    }
+
+    fn lead_time(&self, part_num: &str, manufactured: bool) -> Duration {
+        let days = self
+            .part_plants
+            .get(&(part_num.to_owned(), self.plant.to_owned()))
+            .map_or(0, |part_plant| match manufactured {
+                true => part_plant.mfg_lead_time,
+                false => part_plant.lead_time,
+            });
+        Duration::days(days.max(0) as i64)
+    }
+
+    /// Covers `qty` of the part from its ATP first, then by making the rest from its BOM or,
+    /// for a part without one, buying it. `path` holds the parts being made above this one,
+    /// so a BOM that loops back on itself is bought instead.
+    fn cover(
+        &mut self,
+        part_num: &str,
+        qty: Decimal,
+        level: u32,
+        path: &mut Vec<String>,
+    ) -> Coverage {
+        let (atp_qty, atp_date) = self.take_atp(part_num, qty);
+        let shortfall = qty - atp_qty;
+        let mut coverage = Coverage {
+            atp_qty,
+            build_qty: Decimal::ZERO,
+            buy_qty: Decimal::ZERO,
+            date: atp_date,
+            limiting: (atp_date > self.today).then(|| part_num.to_owned()),
+        };
+        if shortfall <= Decimal::ZERO {
+            return coverage;
+        }
+
+        let boms = self.boms;
+        let materials = boms
+            .get(part_num)
+            .filter(|_| !path.iter().any(|parent| parent == part_num));
+        let ready = match materials {
+            Some(materials) => {
+                coverage.build_qty = shortfall;
+                path.push(part_num.to_owned());
+
+                // The build starts once the latest of its materials is in
+                let mut start = self.today;
+                let mut limiting = None;
+                for material in materials {
+                    let required_qty = shortfall * material.qty_per;
+                    let index = self.components.len();
+                    self.components.push(CtpComponent {
+                        part_number: material.mtl_part_num.to_owned(),
+                        parent_part_number: part_num.to_owned(),
+                        level,
+                        required_qty,
+                        atp_qty: Decimal::ZERO,
+                        build_qty: Decimal::ZERO,
+                        buy_qty: Decimal::ZERO,
+                        available_date: self.today,
+                    });
+
+                    let material_coverage =
+                        self.cover(&material.mtl_part_num, required_qty, level + 1, path);
+                    let component = &mut self.components[index];
+                    component.atp_qty = material_coverage.atp_qty;
+                    component.build_qty = material_coverage.build_qty;
+                    component.buy_qty = material_coverage.buy_qty;
+                    component.available_date = material_coverage.date;
+
+                    if material_coverage.date > start {
+                        start = material_coverage.date;
+                        limiting = material_coverage.limiting;
+                    }
+                }
+
+                path.pop();
+                // A build held back only by its own lead time is limited by the part itself
+                coverage.limiting = limiting.or_else(|| Some(part_num.to_owned()));
+                start + self.lead_time(part_num, true)
+            }
+            None => {
+                coverage.buy_qty = shortfall;
+                coverage.limiting = Some(part_num.to_owned());
+                self.today + self.lead_time(part_num, false)
+            }
+        };
+
+        if ready > coverage.date {
+            coverage.date = ready;
+        } else {
+            // The ATP arrives after the build or buy, so it is what holds things up
+            coverage.limiting = (atp_date > self.today).then(|| part_num.to_owned());
+        }
+
+        coverage
+    }
+}
+
+/// Works out CTP of the part in every plant it has ATP in, or every plant it is planned in
+/// when it has none.
+///
+/// The part's own ATP is promised first. Any shortfall is made from its current BOM, each
+/// material again coming from its ATP first and then being made or bought, starting the
+/// build once the last material is in and adding the part's manufacturing lead time. What a
+/// material has available to promise is what pegging left free, so materials already
+/// committed to open jobs are not counted again.
+pub fn get_part_ctp(
+    pegging: &Pegging,
+    on_hand: &[OnHand],
+    boms: &Boms,
+    part_plants: &HashMap<(String, String), PartPlant>,
+    part_num: &str,
+    query: &CtpQuery,
+    today: NaiveDate,
+) -> Vec<PartCtp> {
+    let mut ctp_plants: Vec<String> =
+        get_part_atp(pegging, on_hand, part_num, &AtpQuery::default(), today)
+            .into_iter()
+            .map(|atp| atp.plant)
+            .collect();
+    if ctp_plants.is_empty() && boms.contains_key(part_num) {
+        ctp_plants = part_plants
+            .keys()
+            .filter(|(part, _)| part == part_num)
+            .map(|(_, plant)| plant.to_owned())
+            .collect();
+        ctp_plants.sort();
+    }
+
+    ctp_plants
+        .into_iter()
+        .map(|plant| {
+            let mut walk = BomWalk {
+                pegging,
+                on_hand,
+                boms,
+                part_plants,
+                plant: plant.to_owned(),
+                today,
+                available: HashMap::new(),
+                components: Vec::new(),
+            };
+            let coverage = walk.cover(part_num, query.qty, 1, &mut Vec::new());
+
+            // The part itself is not a component, so only what held its build back counts
+            let limiting_component = coverage.limiting.filter(|limiting| limiting != part_num);
+
+            PartCtp {
+                part_number: part_num.to_owned(),
+                plant,
+                qty: query.qty,
+                atp_qty: coverage.atp_qty,
+                build_qty: coverage.build_qty,
+                buy_qty: coverage.buy_qty,
+                promise_date: coverage.date,
+                can_promise: query.date.map(|date| coverage.date <= date),
+                limiting_component,
+                components: walk.components,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partmtl::{group_part_mtls, PartMtl};
+    use crate::peg::{PegOptions, SafetyStock};
+    use crate::test_support::{date, on_hand, peg, row};
+    use rust_decimal_macros::dec;
+
+    fn part_mtl(part_num: &str, mtl_part_num: &str, qty_per: Decimal) -> PartMtl {
+        PartMtl {
+            part_num: part_num.to_owned(),
+            mtl_part_num: mtl_part_num.to_owned(),
+            qty_per,
+            uom: "".to_owned(),
+        }
+    }
+
+    fn part_plant(
+        part_num: &str,
+        lead_time: i32,
+        mfg_lead_time: i32,
+    ) -> ((String, String), PartPlant) {
+        (
+            (part_num.to_owned(), "MfgSys".to_owned()),
+            PartPlant {
+                part_num: part_num.to_owned(),
+                plant: "MfgSys".to_owned(),
+                buyer: "".to_owned(),
+                planner: "".to_owned(),
+                safety_qty: dec!(0),
+                lead_time,
+                mfg_lead_time,
+            },
+        )
+    }
+
+    #[test]
+    fn builds_the_shortfall_and_names_the_limiting_material() {
+        // FG has 2 free, and is made of 2 RAW and 1 LBL. RAW has a PO for 10 on the 10th.
+        let part_dtl = vec![row("RAW", false, 10, dec!(10))];
+        let on_hand = vec![on_hand("FG", dec!(2)), on_hand("LBL", dec!(100))];
+        let pegging = peg(&part_dtl, &on_hand, &SafetyStock::new(), &PegOptions::default());
+        let boms = group_part_mtls(&[
+            part_mtl("FG", "RAW", dec!(2)),
+            part_mtl("FG", "LBL", dec!(1)),
+        ]);
+        let part_plants = HashMap::from([part_plant("FG", 0, 3), part_plant("RAW", 20, 0)]);
+
+        let query = CtpQuery {
+            qty: dec!(6),
+            date: Some(date(12)),
+        };
+        let ctp = get_part_ctp(
+            &pegging,
+            &on_hand,
+            &boms,
+            &part_plants,
+            "FG",
+            &query,
+            date(1),
+        );
+        assert_eq!(ctp.len(), 1);
+        assert_eq!(ctp[0].atp_qty, dec!(2));
+        assert_eq!(ctp[0].build_qty, dec!(4));
+        // 8 RAW are in on the 10th and the build takes 3 days
+        assert_eq!(ctp[0].promise_date, date(13));
+        assert_eq!(ctp[0].can_promise, Some(false));
+        assert_eq!(ctp[0].limiting_component.as_deref(), Some("RAW"));
+        assert_eq!(ctp[0].components.len(), 2);
+        assert_eq!(ctp[0].components[0].required_qty, dec!(8));
+
+        // Building 6 more needs 12 RAW, 2 of which have to be bought in its 20 days
+        let query = CtpQuery {
+            qty: dec!(8),
+            date: None,
+        };
+        let ctp = get_part_ctp(
+            &pegging,
+            &on_hand,
+            &boms,
+            &part_plants,
+            "FG",
+            &query,
+            date(1),
+        );
+        assert_eq!(ctp[0].components[0].buy_qty, dec!(2));
+        assert_eq!(ctp[0].promise_date, date(24));
+        assert_eq!(ctp[0].limiting_component.as_deref(), Some("RAW"));
+    }
+}
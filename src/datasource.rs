@@ -0,0 +1,174 @@
+use std::env;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{
+    config::PlanningScope,
+    backlog::{get_backlog_result, get_order_release},
+    directlinks::{get_make_direct_jobs, JobProd},
+    fixture::FixtureDataSource,
+    jobmtl::{get_all_job_boms, get_job_boms, JobMtl},
+    onhand::{get_parts_on_hand, OnHand},
+    partmtl::{get_part_mtls, PartMtl},
+    partplant::{get_part_plants, PartPlant},
+    partsubs::{get_part_subs, PartSub},
+    orderrelease::OrderRelease,
+    uom::{get_uom_conversions, UomConversion},
+    sql::{get_part_dtl_rows, get_sql_pool, SQLReturnRow, SqlPool},
+};
+
+/// Everything the pegging engine reads from the ERP.
+///
+/// Every read is limited to the company and plants of the `scope`. Passing `None` for a
+/// filter returns every row.
+#[async_trait]
+pub trait PlanningDataSource: Send + Sync {
+    /// PartDtl rows ordered by part number, due date and requirement flag
+    async fn part_dtl(
+        &self,
+        scope: &PlanningScope,
+        part_numbers: Option<&[String]>,
+    ) -> Result<Vec<SQLReturnRow>, anyhow::Error>;
+
+    /// Nettable on hand quantity by part and site
+    async fn on_hand(&self, scope: &PlanningScope) -> Result<Vec<OnHand>, anyhow::Error>;
+
+    /// Job materials ordered by job, assembly and material sequence
+    async fn job_mtl(
+        &self,
+        scope: &PlanningScope,
+        job_numbers: Option<&[String]>) -> Result<Vec<JobMtl>, anyhow::Error>;
+
+    /// Make direct links from a producing job to the job materials it targets
+    async fn job_prod(
+        &self,
+        scope: &PlanningScope,
+        target_jobs: Option<&[String]>) -> Result<Vec<JobProd>, anyhow::Error>;
+
+    /// Buyer, planner and other planning details of each part in each plant
+    async fn part_plant(&self, scope: &PlanningScope) -> Result<Vec<PartPlant>, anyhow::Error>;
+
+    /// Parts that may stand in for other parts, ordered by part and substitute
+    async fn part_subs(&self, scope: &PlanningScope) -> Result<Vec<PartSub>, anyhow::Error>;
+
+    /// Materials of the current method of each part, ordered by part and material sequence
+    async fn part_mtl(&self, scope: &PlanningScope) -> Result<Vec<PartMtl>, anyhow::Error>;
+
+    /// How each UOM of each part converts to the part's inventory UOM
+    async fn uom_conversions(
+        &self,
+        scope: &PlanningScope,
+    ) -> Result<Vec<UomConversion>, anyhow::Error>;
+
+    /// The open, firm order releases making up the backlog
+    async fn order_rel(&self, scope: &PlanningScope) -> Result<Vec<OrderRelease>, anyhow::Error>;
+
+    /// A single order release, open or not
+    async fn order_release(
+        &self,
+        scope: &PlanningScope,
+        order: i32,
+        line: i32,
+        release: i32,
+    ) -> Result<Option<OrderRelease>, anyhow::Error>;
+}
+
+/// Reads straight from the Epicor database through a shared connection pool
+pub struct SqlDataSource {
+    pool: SqlPool,
+}
+
+impl SqlDataSource {
+    pub fn new(pool: SqlPool) -> SqlDataSource {
+        SqlDataSource { pool }
+    }
+}
+
+#[async_trait]
+impl PlanningDataSource for SqlDataSource {
+    async fn part_dtl(
+        &self,
+        scope: &PlanningScope,
+        part_numbers: Option<&[String]>,
+    ) -> Result<Vec<SQLReturnRow>, anyhow::Error> {
+        get_part_dtl_rows(&self.pool, scope, part_numbers).await
+    }
+
+    async fn on_hand(&self, scope: &PlanningScope) -> Result<Vec<OnHand>, anyhow::Error> {
+        get_parts_on_hand(&self.pool, scope).await
+    }
+
+    async fn job_mtl(
+        &self,
+        scope: &PlanningScope,
+        job_numbers: Option<&[String]>) -> Result<Vec<JobMtl>, anyhow::Error> {
+        match job_numbers {
+            Some(jobs) => get_job_boms(&self.pool, scope, jobs).await,
+            None => get_all_job_boms(&self.pool, scope).await,
+        }
+    }
+
+    async fn job_prod(
+        &self,
+        scope: &PlanningScope,
+        target_jobs: Option<&[String]>) -> Result<Vec<JobProd>, anyhow::Error> {
+        get_make_direct_jobs(&self.pool, scope, target_jobs).await
+    }
+
+    async fn part_plant(&self, scope: &PlanningScope) -> Result<Vec<PartPlant>, anyhow::Error> {
+        get_part_plants(&self.pool, scope).await
+    }
+
+    async fn part_subs(&self, scope: &PlanningScope) -> Result<Vec<PartSub>, anyhow::Error> {
+        get_part_subs(&self.pool, scope).await
+    }
+
+    async fn part_mtl(&self, scope: &PlanningScope) -> Result<Vec<PartMtl>, anyhow::Error> {
+        get_part_mtls(&self.pool, scope).await
+    }
+
+    async fn uom_conversions(
+        &self,
+        scope: &PlanningScope,
+    ) -> Result<Vec<UomConversion>, anyhow::Error> {
+        get_uom_conversions(&self.pool, scope).await
+    }
+
+    async fn order_rel(&self, scope: &PlanningScope) -> Result<Vec<OrderRelease>, anyhow::Error> {
+        get_backlog_result(&self.pool, scope).await
+    }
+
+    async fn order_release(
+        &self,
+        scope: &PlanningScope,
+        order: i32,
+        line: i32,
+        release: i32,
+    ) -> Result<Option<OrderRelease>, anyhow::Error> {
+        get_order_release(&self.pool, scope, order, line, release).await
+    }
+}
+
+/// Picks the data source from the DATA_SOURCE environmental variable. Defaults to SQL.
+pub async fn get_data_source() -> Result<Arc<dyn PlanningDataSource>, anyhow::Error> {
+    let data_source = env::var("DATA_SOURCE")
+        .ok()
+        .filter(|data_source| !data_source.trim().is_empty())
+        .unwrap_or("sql".to_owned());
+
+    match data_source.trim().to_lowercase().as_str() {
+        "sql" => Ok(Arc::new(SqlDataSource::new(get_sql_pool().await?))),
+        "fixture" => {
+            let dir = env::var("FIXTURE_DIR")
+                .ok()
+                .filter(|dir| !dir.trim().is_empty())
+                .ok_or(anyhow::anyhow!("FIXTURE_DIR must be set when DATA_SOURCE=fixture"))?;
+            Ok(Arc::new(FixtureDataSource::from_dir(dir)?))
+        }
+        other => Err(anyhow::anyhow!(
+            "Unknown DATA_SOURCE '{}'. Expected 'sql' or 'fixture'",
+            other
+        )),
+    }
+}
@@ -1,18 +1,22 @@
-use async_std::net::TcpStream;
+use std::collections::HashMap;
 
 use chrono::NaiveDate;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use serde::Serialize;
-use std::io::{Error, ErrorKind};
-use tiberius::{Client, Query};
+use serde::{Deserialize, Serialize};
+use tiberius::Query;
 
-use crate::sql::get_sql_config;
+use crate::{
+    config::PlanningScope,
+    sql::{parameter_list, SqlPool},
+};
 
 #[allow(dead_code)]
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Hash)]
 pub struct JobProd {
     pub job_num: String,
+    /// The plant of the job making the part
+    pub plant: String,
     pub due_date: NaiveDate,
     pub prod_qty: Decimal,
     pub target_job_num: String,
@@ -20,49 +24,87 @@ pub struct JobProd {
     pub target_mtl: i32,
 }
 
-pub async fn get_make_direct_jobs(
-    job_num: &str,
-    asm: i32,
-    mtl: i32,
-) -> Result<Vec<JobProd>, anyhow::Error> {
-    let config = get_sql_config();
+/// Make direct links keyed by the job making the part
+pub type DirectLinks = HashMap<String, Vec<JobProd>>;
+
+/// Groups the make direct links by the job making the part, keeping their original order
+pub fn group_direct_links(job_prod: &[JobProd]) -> DirectLinks {
+    let mut direct_links: DirectLinks = HashMap::new();
 
-    // Create TCP TcpStream
-    let tcp = TcpStream::connect(&config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
+    for link in job_prod {
+        direct_links
+            .entry(link.job_num.to_owned())
+            .or_default()
+            .push(link.to_owned());
+    }
 
-    // Connect to server
-    let mut client = Client::connect(config, tcp).await?;
+    direct_links
+}
+
+/// Gets the make direct links (JobProd rows pointing at a job material) for the given target
+/// jobs, or every make direct link when no jobs are passed
+pub async fn get_make_direct_jobs(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+    target_jobs: Option<&[String]>,
+) -> Result<Vec<JobProd>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
 
     // Construct Query
-    let mut select = Query::new(
-        "
+    let mut query_string = "
             SELECT
                 JP.JobNum,
+                JH.Plant,
                 JP.TargetJobNum,
                 JP.TargetAssemblySeq,
                 JP.TargetMtlSeq,
                 JP.ProdQty,
                 JH.DueDate
 
-            FROM 
+            FROM
                 Erp.JobProd as JP
 
-            INNER JOIN Erp.JobHead as JH on 
+            INNER JOIN Erp.JobHead as JH on
                 JP.Company = JH.Company
                 and JP.JobNum = JH.JobNum
 
-            WHERE 
-                JP.TargetJobNum = @P1
-                and JP.TargetAssemblySeq = @P2
-                and JP.TargetMtlSeq = @P3
-                and JH.Company = 'AE'
-            ",
-    );
-
-    select.bind(job_num);
-    select.bind(asm);
-    select.bind(mtl);
+            INNER JOIN Erp.JobHead as TJH on
+                JP.Company = TJH.Company
+                and JP.TargetJobNum = TJH.JobNum
+
+            WHERE
+                JP.TargetJobNum <> ''
+                and JH.Company = @P1
+                and JH.JobClosed = 0
+                and JH.JobComplete = 0
+                and TJH.JobClosed = 0
+                and TJH.JobComplete = 0
+            "
+    .to_string();
+
+    // Only links between open jobs in the scope's plants are planned for
+    query_string.push_str(&format!(
+        "and JH.Plant IN ({})\n",
+        parameter_list(1, scope.plants.len())
+    ));
+
+    if let Some(jobs) = target_jobs {
+        query_string.push_str(&format!(
+            "and JP.TargetJobNum IN ({})",
+            parameter_list(1 + scope.plants.len(), jobs.len())
+        ));
+    }
+
+    let mut select = Query::new(query_string);
+
+    select.bind(scope.company.to_owned());
+    scope.plants.iter().for_each(|plant| select.bind(plant.to_owned()));
+    if let Some(jobs) = target_jobs {
+        jobs.iter().for_each(|job| {
+            select.bind(job.to_owned());
+        });
+    }
 
     let mut result: Vec<JobProd> = vec![];
 
@@ -74,6 +116,7 @@ pub async fn get_make_direct_jobs(
 
     row.iter().for_each(|val| {
         let job_num = val.get("JobNum").unwrap_or("").to_owned();
+        let plant = val.get("Plant").unwrap_or("").to_owned();
         let target_job_num = val.get("TargetJobNum").unwrap_or("").to_owned();
         let target_asm = val.get("TargetAssemblySeq").unwrap_or(0).to_owned();
         let target_mtl = val.get("TargetMtlSeq").unwrap_or(0).to_owned();
@@ -88,6 +131,7 @@ pub async fn get_make_direct_jobs(
 
         result.push(JobProd {
             job_num,
+            plant,
             target_job_num,
             target_asm,
             target_mtl,
@@ -96,15 +140,7 @@ pub async fn get_make_direct_jobs(
         });
     });
 
-    // println!("{:?}", rows);
-
-    // Close Client Connection
-    client
-        .close()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
-
     // Result set should be cached now
 
-    return Ok(result);
+    Ok(result)
 }
@@ -0,0 +1,130 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::peg::{ExcessSupply, Pegging};
+
+/// Query string filters for the excess report
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ExcessFilter {
+    pub part: Option<String>,
+    pub sourcefile: Option<String>,
+}
+
+/// Every excess supply of a part in one plant rolled up together
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct PartExcess {
+    pub part_number: String,
+    pub plant: String,
+    pub excess_qty: Decimal,
+    /// The part of the excess that is already on hand
+    pub on_hand_qty: Decimal,
+    pub supplies: usize,
+    /// Whether anything in the plant demands the part at all. On hand with no demand is
+    /// slow moving.
+    pub has_demand: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct ExcessReport {
+    pub parts: Vec<PartExcess>,
+    pub excess: Vec<ExcessSupply>,
+}
+
+/// Lists the supply left over once every demand is pegged, the largest part first
+pub fn get_excess(pegging: &Pegging, filter: &ExcessFilter) -> ExcessReport {
+    let mut excess: Vec<ExcessSupply> = pegging
+        .excess
+        .values()
+        .flatten()
+        .filter(|excess| filter.part.as_ref().is_none_or(|part| &excess.part_number == part))
+        .filter(|excess| {
+            filter
+                .sourcefile
+                .as_ref()
+                .is_none_or(|sourcefile| excess.sourcefile.eq_ignore_ascii_case(sourcefile))
+        })
+        .cloned()
+        .collect();
+
+    let mut parts: Vec<PartExcess> = vec![];
+    excess.sort_by(|a, b| {
+        (&a.part_number, &a.plant, a.due_date).cmp(&(&b.part_number, &b.plant, b.due_date))
+    });
+    excess.iter().for_each(|supply| {
+        let on_hand_qty = match supply.sourcefile.as_str() {
+            "OH" => supply.qty,
+            _ => Decimal::ZERO,
+        };
+
+        match parts.last_mut() {
+            Some(part) if part.part_number == supply.part_number && part.plant == supply.plant => {
+                part.excess_qty += supply.qty;
+                part.on_hand_qty += on_hand_qty;
+                part.supplies += 1;
+            }
+            _ => parts.push(PartExcess {
+                part_number: supply.part_number.to_owned(),
+                plant: supply.plant.to_owned(),
+                excess_qty: supply.qty,
+                on_hand_qty,
+                supplies: 1,
+                has_demand: pegging.demand.get(&supply.part_number).is_some_and(|demand| {
+                    demand.iter().any(|demand| demand.plant == supply.plant)
+                }),
+            }),
+        }
+    });
+
+    parts.sort_by(|a, b| {
+        b.excess_qty
+            .cmp(&a.excess_qty)
+            .then(a.part_number.cmp(&b.part_number))
+    });
+
+    ExcessReport { parts, excess }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{demand, excess, supply};
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+
+    #[test]
+    fn rolls_up_excess_by_part() {
+        let pegging = Pegging {
+            demand: HashMap::from([(
+                "RAW".to_owned(),
+                vec![demand("RAW", 5, dec!(1), vec![supply("OH", 1, 5, dec!(1))])],
+            )]),
+            excess: HashMap::from([
+                (
+                    "RAW".to_owned(),
+                    vec![excess("RAW", "OH", 10, dec!(2)), excess("RAW", "PO", 10, dec!(3))],
+                ),
+                ("OLD".to_owned(), vec![excess("OLD", "OH", 10, dec!(9))]),
+            ]),
+        };
+
+        let report = get_excess(&pegging, &ExcessFilter::default());
+        assert_eq!(report.excess.len(), 3);
+        assert_eq!(report.parts.len(), 2);
+        assert_eq!(report.parts[0].part_number, "OLD");
+        assert!(!report.parts[0].has_demand);
+        assert_eq!(report.parts[1].excess_qty, dec!(5));
+        assert_eq!(report.parts[1].on_hand_qty, dec!(2));
+        assert_eq!(report.parts[1].supplies, 2);
+        assert!(report.parts[1].has_demand);
+
+        let filter = ExcessFilter {
+            sourcefile: Some("po".to_owned()),
+            ..ExcessFilter::default()
+        };
+        let report = get_excess(&pegging, &filter);
+        assert_eq!(report.excess.len(), 1);
+        assert_eq!(report.parts[0].on_hand_qty, dec!(0));
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    directlinks::DirectLinks,
+    onhand::OnHand,
+    peg::{PegOptions, SafetyStock},
+    sql::SQLReturnRow,
+};
+
+/// A hash of everything pegging reads for each part, along with the options it was pegged
+/// with. PartDtl is a view without row versions, so parts are compared by the content of
+/// their rows rather than by SysRevID.
+#[derive(Debug, Clone, Default)]
+pub struct PegFingerprints {
+    pub options: PegOptions,
+    pub parts: HashMap<String, u64>,
+}
+
+impl PegFingerprints {
+    /// Hashes the PartDtl rows, on hand, safety stock and make direct links of each part
+    pub fn new(
+        part_numbers: &[String],
+        part_dtl: &[SQLReturnRow],
+        on_hand: &[OnHand],
+        safety_stock: &SafetyStock,
+        direct_links: &DirectLinks,
+        options: &PegOptions,
+    ) -> PegFingerprints {
+        let mut hashers: HashMap<&str, DefaultHasher> = HashMap::new();
+
+        part_dtl.iter().for_each(|row| {
+            let hasher = hashers.entry(&row.part_num).or_default();
+            row.hash(hasher);
+            if row.sourcefile == "JH" {
+                direct_links.get(&row.job_num).hash(hasher);
+            }
+        });
+        on_hand
+            .iter()
+            .for_each(|row| row.hash(hashers.entry(&row.part_num).or_default()));
+
+        // Safety stock is keyed by a hash map, so put it in plant order first
+        let mut safety: HashMap<&str, BTreeMap<&str, Decimal>> = HashMap::new();
+        safety_stock.iter().for_each(|((part_num, plant), qty)| {
+            safety.entry(part_num).or_default().insert(plant, *qty);
+        });
+
+        let parts = part_numbers
+            .iter()
+            .map(|part_num| {
+                let mut hasher = hashers.remove(part_num.as_str()).unwrap_or_default();
+                safety.get(part_num.as_str()).hash(&mut hasher);
+                (part_num.to_owned(), hasher.finish())
+            })
+            .collect();
+
+        PegFingerprints {
+            options: options.to_owned(),
+            parts,
+        }
+    }
+
+    /// Whether the part was pegged from the same inputs with the same options in `previous`
+    pub fn unchanged(&self, previous: &PegFingerprints, part_num: &str) -> bool {
+        self.options == previous.options
+            && self.parts.contains_key(part_num)
+            && self.parts.get(part_num) == previous.parts.get(part_num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn only_the_changed_part_gets_a_new_fingerprint() {
+        let part_numbers = vec!["FG".to_owned(), "RAW".to_owned()];
+        let mut part_dtl = vec![
+            SQLReturnRow::new_on_hand("FG", "MfgSys", dec!(5)),
+            SQLReturnRow::new_on_hand("RAW", "MfgSys", dec!(10)),
+        ];
+        let fingerprint = |part_dtl: &[SQLReturnRow]| {
+            PegFingerprints::new(
+                &part_numbers,
+                part_dtl,
+                &[],
+                &SafetyStock::new(),
+                &DirectLinks::new(),
+                &PegOptions::default(),
+            )
+        };
+        let previous = fingerprint(&part_dtl);
+
+        // Row ids shift whenever another part's rows change, so they are left out
+        part_dtl[0].id = 7;
+        part_dtl[1].qty = dec!(12);
+        let current = fingerprint(&part_dtl);
+
+        assert!(current.unchanged(&previous, "FG"));
+        assert!(!current.unchanged(&previous, "RAW"));
+        assert!(!current.unchanged(&PegFingerprints::default(), "FG"));
+    }
+}
@@ -0,0 +1,299 @@
+use std::fs::File;
+use std::path::Path;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    config::PlanningScope,
+    datasource::PlanningDataSource,
+    directlinks::JobProd,
+    jobmtl::JobMtl,
+    onhand::OnHand,
+    partmtl::PartMtl,
+    partplant::PartPlant,
+    partsubs::PartSub,
+    orderrelease::OrderRelease,
+    sql::{apply_net_qty, SQLReturnRow},
+    uom::UomConversion,
+};
+
+/// Serves planning data from memory, normally loaded from an exported snapshot.
+///
+/// A snapshot directory holds `partdtl`, `onhand`, `jobmtl`, `jobprod`, `partplant`,
+/// `partsubs`, `partmtl`, `uomconv` and `orderrel` files,
+/// each either as `.json` (an array of rows) or `.csv` (with a header row). Columns use the
+/// same snake_case names as the serialized structs. Missing files are treated as empty.
+///
+/// A fixture holds a single company, so every read is limited to the plants in the scope the
+/// same way the SQL queries are. Part substitutes, part methods and UOM conversions are kept
+/// per company and are not filtered. Fixtures built in code should be passed through `prepare`
+/// before use.
+#[derive(Default)]
+pub struct FixtureDataSource {
+    pub part_dtl: Vec<SQLReturnRow>,
+    pub on_hand: Vec<OnHand>,
+    pub job_mtl: Vec<JobMtl>,
+    pub job_prod: Vec<JobProd>,
+    pub part_plant: Vec<PartPlant>,
+    pub part_subs: Vec<PartSub>,
+    pub part_mtl: Vec<PartMtl>,
+    pub uom_conversions: Vec<UomConversion>,
+    pub order_rel: Vec<OrderRelease>,
+}
+
+impl FixtureDataSource {
+    /// Orders and numbers the PartDtl rows the way the PartDtl query would return them
+    pub fn prepare(mut self) -> FixtureDataSource {
+        // Match the ordering of the PartDtl query
+        self.part_dtl.sort_by(|a, b| {
+            (&a.part_num, a.due_date, a.requirement).cmp(&(&b.part_num, b.due_date, b.requirement))
+        });
+        self.part_dtl
+            .iter_mut()
+            .enumerate()
+            .for_each(|(id, row)| row.id = id as u32);
+        apply_net_qty(&mut self.part_dtl);
+
+        self
+    }
+
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<FixtureDataSource, anyhow::Error> {
+        let dir = dir.as_ref();
+
+        Ok(FixtureDataSource {
+            part_dtl: load_fixture(dir, "partdtl")?,
+            on_hand: load_fixture(dir, "onhand")?,
+            job_mtl: load_fixture(dir, "jobmtl")?,
+            job_prod: load_fixture(dir, "jobprod")?,
+            part_plant: load_fixture(dir, "partplant")?,
+            part_subs: load_fixture(dir, "partsubs")?,
+            part_mtl: load_fixture(dir, "partmtl")?,
+            uom_conversions: load_fixture(dir, "uomconv")?,
+            order_rel: load_fixture(dir, "orderrel")?,
+        }
+        .prepare())
+    }
+}
+
+fn load_fixture<T: DeserializeOwned>(dir: &Path, name: &str) -> Result<Vec<T>, anyhow::Error> {
+    let json_path = dir.join(format!("{}.json", name));
+    let csv_path = dir.join(format!("{}.csv", name));
+
+    if json_path.exists() {
+        let file = File::open(&json_path)?;
+        serde_json::from_reader(file)
+            .map_err(|e| anyhow::anyhow!("Could not read {}: {}", json_path.display(), e))
+    } else if csv_path.exists() {
+        let mut reader = csv::Reader::from_path(&csv_path)?;
+        reader
+            .deserialize()
+            .collect::<Result<Vec<T>, csv::Error>>()
+            .map_err(|e| anyhow::anyhow!("Could not read {}: {}", csv_path.display(), e))
+    } else {
+        Ok(vec![])
+    }
+}
+
+#[async_trait]
+impl PlanningDataSource for FixtureDataSource {
+    async fn part_dtl(
+        &self,
+        scope: &PlanningScope,
+        part_numbers: Option<&[String]>,
+    ) -> Result<Vec<SQLReturnRow>, anyhow::Error> {
+        Ok(self
+            .part_dtl
+            .iter()
+            .filter(|row| scope.plants.contains(&row.plant))
+            .filter(|row| part_numbers.is_none_or(|parts| parts.contains(&row.part_num)))
+            .cloned()
+            .collect())
+    }
+
+    async fn on_hand(&self, scope: &PlanningScope) -> Result<Vec<OnHand>, anyhow::Error> {
+        Ok(self
+            .on_hand
+            .iter()
+            .filter(|row| scope.plants.contains(&row.site))
+            .cloned()
+            .collect())
+    }
+
+    async fn job_mtl(
+        &self,
+        scope: &PlanningScope,
+        job_numbers: Option<&[String]>) -> Result<Vec<JobMtl>, anyhow::Error> {
+        Ok(self
+            .job_mtl
+            .iter()
+            .filter(|row| scope.plants.contains(&row.plant))
+            .filter(|row| job_numbers.is_none_or(|jobs| jobs.contains(&row.job_num)))
+            .cloned()
+            .collect())
+    }
+
+    async fn job_prod(
+        &self,
+        scope: &PlanningScope,
+        target_jobs: Option<&[String]>) -> Result<Vec<JobProd>, anyhow::Error> {
+        Ok(self
+            .job_prod
+            .iter()
+            .filter(|row| scope.plants.contains(&row.plant))
+            .filter(|row| target_jobs.is_none_or(|jobs| jobs.contains(&row.target_job_num)))
+            .cloned()
+            .collect())
+    }
+
+    async fn part_plant(&self, scope: &PlanningScope) -> Result<Vec<PartPlant>, anyhow::Error> {
+        Ok(self
+            .part_plant
+            .iter()
+            .filter(|row| scope.plants.contains(&row.plant))
+            .cloned()
+            .collect())
+    }
+
+    async fn part_subs(&self, _scope: &PlanningScope) -> Result<Vec<PartSub>, anyhow::Error> {
+        Ok(self.part_subs.clone())
+    }
+
+    async fn part_mtl(&self, _scope: &PlanningScope) -> Result<Vec<PartMtl>, anyhow::Error> {
+        Ok(self.part_mtl.clone())
+    }
+
+    async fn uom_conversions(
+        &self,
+        _scope: &PlanningScope,
+    ) -> Result<Vec<UomConversion>, anyhow::Error> {
+        Ok(self.uom_conversions.clone())
+    }
+
+    async fn order_rel(&self, scope: &PlanningScope) -> Result<Vec<OrderRelease>, anyhow::Error> {
+        Ok(self
+            .order_rel
+            .iter()
+            .filter(|row| scope.plants.contains(&row.plant))
+            .cloned()
+            .collect())
+    }
+
+    async fn order_release(
+        &self,
+        scope: &PlanningScope,
+        order: i32,
+        line: i32,
+        release: i32,
+    ) -> Result<Option<OrderRelease>, anyhow::Error> {
+        Ok(self
+            .order_rel
+            .iter()
+            .filter(|row| scope.plants.contains(&row.plant))
+            .find(|row| {
+                row.order == Some(order) && row.line == Some(line) && row.release == Some(release)
+            })
+            .cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PlanningConfig;
+    use rust_decimal_macros::dec;
+    use std::fs;
+
+    fn fixture_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("apollo-fixture-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn loads_csv_and_json_fixtures() {
+        let dir = fixture_dir("load");
+        fs::write(
+            dir.join("partdtl.csv"),
+            "requirement,part_num,plant,due_date,sourcefile,qty,job_num,asm,mtl,order,order_line,order_rel,po_num,po_line,po_rel,direct\n\
+             true,FG,MfgSys,2024-02-01,OR,5,,0,0,100,1,1,,,,false\n\
+             false,FG,MfgSys,2024-01-15,PO,8,,0,0,0,0,0,4567,1,1,false\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("onhand.json"),
+            r#"[{"part_num": "FG", "site": "MfgSys", "qty": "2"}]"#,
+        )
+        .unwrap();
+
+        let source = FixtureDataSource::from_dir(&dir).unwrap();
+        let scope = PlanningConfig::from_vars(|_| None).unwrap().default_scopes()[0].clone();
+
+        let part_dtl = source.part_dtl(&scope, Some(&["FG".to_owned()])).await.unwrap();
+        assert_eq!(part_dtl.len(), 2);
+        // Ordered by due date, so the PO comes first
+        assert_eq!(part_dtl[0].po_num, Some(4567));
+        assert_eq!(part_dtl[1].net_qty, dec!(3));
+
+        let on_hand = source.on_hand(&scope).await.unwrap();
+        assert_eq!(on_hand[0].qty, dec!(2));
+
+        assert!(source.job_mtl(&scope, None).await.unwrap().is_empty());
+        assert!(source.order_release(&scope, 100, 1, 1).await.unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_every_plant_table_to_the_scope() {
+        let dir = fixture_dir("scope");
+        fs::write(
+            dir.join("partdtl.csv"),
+            "requirement,part_num,plant,due_date,sourcefile,qty,job_num,asm,mtl,order,order_line,order_rel,po_num,po_line,po_rel,direct\n\
+             true,FG,MfgSys,2024-02-01,OR,5,,0,0,100,1,1,,,,false\n\
+             true,FG,West,2024-02-01,OR,7,,0,0,200,1,1,,,,false\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("jobmtl.csv"),
+            "job_num,plant,asm,mtl,jobop,part_num,description,direct,req_qty,issued_qty,req_date\n\
+             J100,MfgSys,0,10,10,RAW,Raw,false,10,0,2024-01-20\n\
+             J200,West,0,10,10,RAW,Raw,false,4,0,2024-01-20\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("jobprod.csv"),
+            "job_num,plant,due_date,prod_qty,target_job_num,target_asm,target_mtl\n\
+             J300,MfgSys,2024-01-10,1,J100,0,10\n\
+             J400,West,2024-01-10,1,J200,0,10\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("orderrel.csv"),
+            "order,line,release,plant,part_number,customer,due_date\n\
+             100,1,1,MfgSys,FG,ACME,2024-02-01\n\
+             200,1,1,West,FG,ACME,2024-02-01\n",
+        )
+        .unwrap();
+
+        let source = FixtureDataSource::from_dir(&dir).unwrap();
+        let scope = PlanningConfig::from_vars(|_| None).unwrap().default_scopes()[0].clone();
+
+        let part_dtl = source.part_dtl(&scope, None).await.unwrap();
+        assert_eq!(part_dtl.len(), 1);
+        assert_eq!(part_dtl[0].order, 100);
+
+        let job_mtl = source.job_mtl(&scope, None).await.unwrap();
+        assert_eq!(job_mtl.len(), 1);
+        assert_eq!(job_mtl[0].job_num, "J100");
+
+        let job_prod = source.job_prod(&scope, None).await.unwrap();
+        assert_eq!(job_prod.len(), 1);
+        assert_eq!(job_prod[0].job_num, "J300");
+
+        assert_eq!(source.order_rel(&scope).await.unwrap().len(), 1);
+        assert!(source.order_release(&scope, 200, 1, 1).await.unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
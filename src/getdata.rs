@@ -1,225 +1,180 @@
 use std::{
-    collections::HashMap,
+    collections::HashSet,
     sync::{Arc, Mutex},
-    time::Instant,
 };
 
 use crate::{
-    onhand::get_parts_on_hand,
-    parttimephase::{PartDtl, PartDtlCollection},
-    peg_part_dtl::multi_peg_part_dtl,
-    sql::{define_query_string, get_sql_config},
-    transformtozero::transform_zero_to_none,
+    directlinks::DirectLinks,
+    fingerprint::PegFingerprints,
+    onhand::OnHand,
+    partsubs::PartSubstitutes,
+    peg::{
+        get_unique_part_numbers, group_part_inputs, multi_peg_part_dtl, peg_substitutes,
+        PartInput, PegOptions, Pegging, SafetyStock,
+    },
+    sql::SQLReturnRow,
 };
-use async_std::net::TcpStream;
-use chrono::NaiveDate;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use rust_decimal::Decimal;
-use rust_decimal_macros::dec;
-use std::io::{Error, ErrorKind};
-use tiberius::{Client, Query, Row};
 
-async fn get_db_client() -> Result<Client<TcpStream>, anyhow::Error> {
-    let config = get_sql_config();
-
-    // Create TCP TcpStream
-    let tcp = TcpStream::connect(&config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    // Connect to server
-    let client = Client::connect(config, tcp).await?;
-
-    Ok(client)
-}
-
-async fn get_partdtl_rows() -> Result<Vec<Row>, anyhow::Error> {
-    // Connect to server
-    let mut client = get_db_client().await?;
-
-    // Getting on hand. Removing because it's not being used on this
-    // let _on_hand = get_parts_on_hand().await.unwrap();
-
-    // Construct Query
-    let query = define_query_string(None);
-
-    let new_query = Query::new(query);
-
-    // Stream Query
-    let stream = new_query.query(&mut client).await?;
-
-    // Consume stream
-    let rows = stream.into_first_result().await?;
+/// Pegs every part found in the PartDtl rows, then covers what is still short with
+/// substitutes when the options allow it
+pub fn peg_time_phase_data(
+    part_dtl: &[SQLReturnRow],
+    on_hand: &[OnHand],
+    safety_stock: &SafetyStock,
+    direct_links: &DirectLinks,
+    substitutes: &PartSubstitutes,
+    options: &PegOptions,
+) -> Pegging {
+    let unique_part_numbers = get_parts_to_peg(part_dtl, substitutes, options);
+    let mut pegging = peg_parts(
+        &unique_part_numbers,
+        part_dtl,
+        on_hand,
+        safety_stock,
+        direct_links,
+        options,
+    );
 
-    // Close Client Connection
-    client
-        .close()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+    // Substitutes only get what their own part's demand leaves over, so this waits until
+    // every part is pegged
+    if options.use_substitutes {
+        peg_substitutes(&mut pegging, substitutes, options);
+    }
 
-    Ok(rows)
+    pegging
 }
 
-pub async fn get_new_time_phase_details(
-) -> Result<Arc<Mutex<HashMap<String, Vec<PartDtl>>>>, anyhow::Error> {
-    let rows = get_partdtl_rows().await?;
-
-    // Transform rows into new data type
-    // let result: PartDtlCollection = PartDtlCollection(transform_rows_to_partdtl(rows));
-    let result: PartDtlCollection = PartDtlCollection(transform_rows_to_partdtl_par(rows));
-
-    let unique_part_numbers_par_timer_start = Instant::now();
-    let unique_part_numbers = result.get_unique_part_numbers_par();
-    let unique_part_numbers_par_timer_elapsed = unique_part_numbers_par_timer_start.elapsed();
-    println!(
-        "Getting unique part numbers in parallel took: {:?}",
-        unique_part_numbers_par_timer_elapsed
+/// Pegs like `peg_time_phase_data`, but only the parts whose inputs changed since the
+/// `previous` pegging was built. Every other part keeps its previous pegging. Returns the
+/// fingerprints to compare against next time.
+pub fn repeg_time_phase_data(
+    part_dtl: &[SQLReturnRow],
+    on_hand: &[OnHand],
+    safety_stock: &SafetyStock,
+    direct_links: &DirectLinks,
+    substitutes: &PartSubstitutes,
+    options: &PegOptions,
+    previous: Option<(&Pegging, &PegFingerprints)>,
+) -> (Pegging, PegFingerprints) {
+    let unique_part_numbers = get_parts_to_peg(part_dtl, substitutes, options);
+
+    let fingerprints = PegFingerprints::new(
+        &unique_part_numbers,
+        part_dtl,
+        on_hand,
+        safety_stock,
+        direct_links,
+        options,
     );
 
-    let on_hand = get_parts_on_hand().await.unwrap();
-
-    //Peg unique part numbers
-    let multi_results = Arc::new(Mutex::new(HashMap::new()));
-    unique_part_numbers.par_iter().for_each(|item| {
-        let multi_peg_data = multi_peg_part_dtl(&result.0, &on_hand, &item);
-        let mut multi_results = multi_results.lock().unwrap();
-        multi_results.insert(item.to_owned(), multi_peg_data);
-    });
-
-    println!("Returning result");
-    Ok(multi_results)
-}
-
-fn transform_rows_to_partdtl(rows: Vec<Row>) -> Vec<PartDtl> {
-    let transform_timer_start = Instant::now();
-    let mut net_qty = dec!(0.0);
-    let mut result: Vec<PartDtl> = vec![];
-
-    rows.iter().for_each(|val| {
-        let requirement = val
-            .get::<bool, _>("RequirementFlag")
-            .unwrap_or(false.to_owned())
-            .to_owned();
-        let direct = val
-            .get::<bool, _>("StockTrans")
-            .unwrap_or(false.to_owned())
-            .to_owned();
-        let sourcefile = val
-            .get::<&str, &str>("SourceFile")
-            .unwrap_or("ER")
-            .to_owned();
-        let part_number = val
-            .get::<&str, &str>("PartNum")
-            .unwrap_or("ERROR")
-            .to_owned();
-        let qty = val
-            .get::<Decimal, _>("Quantity")
-            .unwrap_or(dec!(0.0))
-            .to_owned();
-        let due_date = val
-            .get::<NaiveDate, _>("DueDate")
-            .unwrap_or(NaiveDate::from_ymd_opt(1999, 1, 1).unwrap())
-            .to_owned();
-        let job_num = val.get::<&str, &str>("JobNum").map(|s| s.to_owned());
-        let asm = val.get::<i32, _>("AssemblySeq");
-        let mtl = val.get::<i32, _>("JobSeq");
-        let order = val.get::<i32, _>("OrderNum");
-        let order_line = val.get::<i32, _>("OrderLine");
-        let order_rel = val.get::<i32, _>("OrderRelNum");
-        let po_num = transform_zero_to_none(val.get::<i32, _>("PONum").to_owned());
-        let po_line = transform_zero_to_none(val.get::<i32, _>("POLine").to_owned());
-        let po_rel = transform_zero_to_none(val.get::<i32, _>("PORelNum").to_owned());
-
-        if requirement {
-            net_qty = net_qty.saturating_sub(qty);
-        } else {
-            net_qty = net_qty.saturating_add(qty);
+    // Substitution changes both the short part's pegging and the substitute's left over
+    // supply, so neither can be taken as it was
+    let mut substituted: HashSet<&str> = HashSet::new();
+    if options.use_substitutes {
+        substitutes.iter().for_each(|(part_num, subs)| {
+            substituted.insert(part_num);
+            subs.iter().for_each(|sub| {
+                substituted.insert(&sub.sub_part);
+            });
+        });
+    }
+
+    let mut reused = Pegging::default();
+    let mut changed_part_numbers: Vec<String> = vec![];
+    for part_num in unique_part_numbers {
+        let previous_demand = previous
+            .filter(|(_, previous_fingerprints)| {
+                fingerprints.unchanged(previous_fingerprints, &part_num)
+                    && !substituted.contains(part_num.as_str())
+            })
+            .and_then(|(previous_pegging, _)| {
+                previous_pegging
+                    .demand
+                    .get(&part_num)
+                    .map(|demand| (demand, previous_pegging.excess.get(&part_num)))
+            });
+
+        match previous_demand {
+            Some((demand, excess)) => {
+                reused.demand.insert(part_num.to_owned(), demand.to_owned());
+                if let Some(excess) = excess {
+                    reused.excess.insert(part_num.to_owned(), excess.to_owned());
+                }
+            }
+            None => changed_part_numbers.push(part_num),
         }
+    }
+
+    let mut pegging = peg_parts(
+        &changed_part_numbers,
+        part_dtl,
+        on_hand,
+        safety_stock,
+        direct_links,
+        options,
+    );
+    pegging.demand.extend(reused.demand);
+    pegging.excess.extend(reused.excess);
 
-        result.push(PartDtl {
-            requirement,
-            part_number,
-            direct,
-            due_date,
-            sourcefile,
-            qty,
-            job_num,
-            asm,
-            mtl,
-            po_num,
-            po_line,
-            po_rel,
-            order,
-            order_line,
-            order_rel,
-            supply: vec![],
-            bom: vec![],
-        });
-    });
-    let transform_elapsed = transform_timer_start.elapsed();
-    println!("Transformation took: {:#?}", transform_elapsed);
+    if options.use_substitutes {
+        peg_substitutes(&mut pegging, substitutes, options);
+    }
 
-    result
+    (pegging, fingerprints)
 }
 
-fn transform_rows_to_partdtl_par(rows: Vec<Row>) -> Vec<PartDtl> {
-    let transform_timer_start = Instant::now();
-
-    // Parallel transformation of rows to PartDtl
-    let result: Vec<PartDtl> = rows
-        .par_iter()
-        .map(|val| {
-            let requirement = val.get::<bool, _>("RequirementFlag").unwrap_or(false);
-            let direct = val.get::<bool, _>("StockTrans").unwrap_or(false);
-            let sourcefile = val
-                .get::<&str, &str>("SourceFile")
-                .unwrap_or("ER")
-                .to_string();
-            let part_number = val
-                .get::<&str, &str>("PartNum")
-                .unwrap_or("ERROR")
-                .to_string();
-            let qty = val.get::<Decimal, _>("Quantity").unwrap_or(dec!(0.0));
-            let due_date = val
-                .get::<NaiveDate, _>("DueDate")
-                .unwrap_or(NaiveDate::from_ymd(1999, 1, 1));
-            let job_num = val.get::<&str, &str>("JobNum").map(|s| s.to_string());
-            let asm = val.get::<i32, _>("AssemblySeq");
-            let mtl = val.get::<i32, _>("JobSeq");
-            let order = val.get::<i32, _>("OrderNum");
-            let order_line = val.get::<i32, _>("OrderLine");
-            let order_rel = val.get::<i32, _>("OrderRelNum");
-            let po_num = transform_zero_to_none(val.get::<i32, _>("PONum"));
-            let po_line = transform_zero_to_none(val.get::<i32, _>("POLine"));
-            let po_rel = transform_zero_to_none(val.get::<i32, _>("PORelNum"));
-
-            // Removed net_qty calculation from here as it's not straightforwardly parallelizable
-
-            PartDtl {
-                requirement,
-                part_number,
-                direct,
-                due_date,
-                sourcefile,
-                qty,
-                job_num,
-                asm,
-                mtl,
-                po_num,
-                po_line,
-                po_rel,
-                order,
-                order_line,
-                order_rel,
-                supply: vec![],
-                bom: vec![],
+/// Every part found in the PartDtl rows, along with any substitutes when they are used
+fn get_parts_to_peg(
+    part_dtl: &[SQLReturnRow],
+    substitutes: &PartSubstitutes,
+    options: &PegOptions,
+) -> Vec<String> {
+    let mut unique_part_numbers = get_unique_part_numbers(part_dtl);
+    // A substitute may only be on hand, with no PartDtl rows of its own
+    if options.use_substitutes {
+        let mut seen: HashSet<String> = unique_part_numbers.iter().cloned().collect();
+        substitutes.values().flatten().for_each(|sub| {
+            if seen.insert(sub.sub_part.to_owned()) {
+                unique_part_numbers.push(sub.sub_part.to_owned());
             }
-        })
-        .collect();
+        });
+    }
 
-    let transform_elapsed = transform_timer_start.elapsed();
-    println!("Transformation took: {:#?}", transform_elapsed);
+    unique_part_numbers
+}
 
-    // Sequentially calculate net_qty if necessary, considering redesigning its calculation for parallel execution
+/// Pegs each of the parts on its own, in parallel
+fn peg_parts(
+    part_numbers: &[String],
+    part_dtl: &[SQLReturnRow],
+    on_hand: &[OnHand],
+    safety_stock: &SafetyStock,
+    direct_links: &DirectLinks,
+    options: &PegOptions,
+) -> Pegging {
+    let inputs = group_part_inputs(part_dtl, on_hand);
+
+    let multi_results = Arc::new(Mutex::new(Pegging::default()));
+    let no_input = PartInput::default();
+
+    // Peg unique part numbers
+    part_numbers.par_iter().for_each(|item| {
+        let input = inputs.get(item).unwrap_or(&no_input);
+        let multi_peg_data = multi_peg_part_dtl(input, safety_stock, direct_links, item, options);
+        let mut multi_results = multi_results.lock().unwrap();
+        multi_results
+            .demand
+            .insert(item.to_owned(), multi_peg_data.demand);
+        if !multi_peg_data.excess.is_empty() {
+            multi_results
+                .excess
+                .insert(item.to_owned(), multi_peg_data.excess);
+        }
+    });
 
-    result
+    Arc::try_unwrap(multi_results)
+        .expect("Lock still has multiple owners")
+        .into_inner()
+        .expect("Mutex cannot be unlocked")
 }
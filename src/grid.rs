@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, Duration, Months, NaiveDate};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::split_list, onhand::OnHand, sql::SQLReturnRow};
+
+// How far out the grid goes when no horizon is asked for
+const DEFAULT_HORIZON_DAYS: i64 = 84;
+// Keeps daily grids over every part to a sensible size
+const MAX_HORIZON_DAYS: i64 = 730;
+
+/// How long each bucket of the grid is
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BucketSize {
+    Day,
+    /// Monday to Sunday
+    #[default]
+    Week,
+    Month,
+}
+
+impl BucketSize {
+    /// The start of the bucket the date falls in
+    fn bucket_start(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            BucketSize::Day => date,
+            BucketSize::Week => date - Duration::days(date.weekday().num_days_from_monday() as i64),
+            BucketSize::Month => date.with_day(1).unwrap(),
+        }
+    }
+
+    fn next(&self, start: NaiveDate) -> NaiveDate {
+        match self {
+            BucketSize::Day => start + Duration::days(1),
+            BucketSize::Week => start + Duration::days(7),
+            BucketSize::Month => start + Months::new(1),
+        }
+    }
+}
+
+/// Query string parameters for the grid. `part` may hold several comma separated parts and
+/// `horizon` is a number of days from today.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct GridFilter {
+    pub part: Option<String>,
+    #[serde(default)]
+    pub bucket: BucketSize,
+    pub horizon: Option<i64>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct GridBucket {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub gross_requirements: Decimal,
+    pub scheduled_receipts: Decimal,
+    /// On hand at the end of the bucket, once every net requirement so far has been met
+    pub projected_on_hand: Decimal,
+    pub net_requirements: Decimal,
+}
+
+/// The MRP table of a part in one plant
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct PartGrid {
+    pub part_number: String,
+    pub plant: String,
+    pub on_hand: Decimal,
+    pub buckets: Vec<GridBucket>,
+}
+
+/// Buckets the PartDtl rows of the parts from the start of the current bucket out to the
+/// horizon. Past due rows fall into the first bucket and rows past the horizon are left out.
+pub fn get_grid(
+    part_dtl: &[SQLReturnRow],
+    on_hand: &[OnHand],
+    filter: &GridFilter,
+    today: NaiveDate,
+) -> Vec<PartGrid> {
+    let parts = filter.part.as_ref().map(|part| split_list(part));
+    let wanted = |part_num: &str| {
+        parts
+            .as_ref()
+            .is_none_or(|parts| parts.iter().any(|part| part == part_num))
+    };
+
+    let horizon = today
+        + Duration::days(
+            filter
+                .horizon
+                .unwrap_or(DEFAULT_HORIZON_DAYS)
+                .clamp(0, MAX_HORIZON_DAYS),
+        );
+    let mut starts: Vec<NaiveDate> = vec![filter.bucket.bucket_start(today)];
+    while filter.bucket.next(*starts.last().unwrap()) <= horizon {
+        starts.push(filter.bucket.next(*starts.last().unwrap()));
+    }
+    let empty_buckets: Vec<GridBucket> = starts
+        .iter()
+        .map(|&start| GridBucket {
+            start,
+            end: filter.bucket.next(start) - Duration::days(1),
+            gross_requirements: Decimal::ZERO,
+            scheduled_receipts: Decimal::ZERO,
+            projected_on_hand: Decimal::ZERO,
+            net_requirements: Decimal::ZERO,
+        })
+        .collect();
+    let last_day = empty_buckets.last().unwrap().end;
+
+    let mut grids: BTreeMap<(String, String), PartGrid> = BTreeMap::new();
+    part_dtl
+        .iter()
+        .filter(|row| wanted(&row.part_num))
+        .for_each(|row| {
+            let grid = grids
+                .entry((row.part_num.to_owned(), row.plant.to_owned()))
+                .or_insert(PartGrid {
+                    part_number: row.part_num.to_owned(),
+                    plant: row.plant.to_owned(),
+                    on_hand: Decimal::ZERO,
+                    buckets: empty_buckets.clone(),
+                });
+            if row.due_date > last_day {
+                return;
+            }
+
+            let index = starts
+                .partition_point(|&start| start <= row.due_date)
+                .saturating_sub(1);
+            match row.requirement {
+                true => grid.buckets[index].gross_requirements += row.qty,
+                false => grid.buckets[index].scheduled_receipts += row.qty,
+            }
+        });
+
+    // Parts that were asked for by name get a grid even when they only have on hand
+    on_hand
+        .iter()
+        .filter(|row| wanted(&row.part_num))
+        .for_each(|row| {
+            let key = (row.part_num.to_owned(), row.site.to_owned());
+            if parts.is_some() {
+                grids.entry(key.clone()).or_insert(PartGrid {
+                    part_number: row.part_num.to_owned(),
+                    plant: row.site.to_owned(),
+                    on_hand: Decimal::ZERO,
+                    buckets: empty_buckets.clone(),
+                });
+            }
+            if let Some(grid) = grids.get_mut(&key) {
+                grid.on_hand += row.qty
+            }
+        });
+
+    grids
+        .into_values()
+        .map(|mut grid| {
+            // Net requirements are planned lot for lot, so projected on hand never goes
+            // below zero
+            let mut projected = grid.on_hand;
+            grid.buckets.iter_mut().for_each(|bucket| {
+                let available = projected + bucket.scheduled_receipts - bucket.gross_requirements;
+                if available < Decimal::ZERO {
+                    bucket.net_requirements = -available;
+                    projected = Decimal::ZERO;
+                } else {
+                    projected = available;
+                }
+                bucket.projected_on_hand = projected;
+            });
+            grid
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{month_date, on_hand, row_due};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn buckets_supply_and_demand_by_week() {
+        // 2024-01-10 is a Wednesday
+        let today = month_date(1, 10);
+        let part_dtl = vec![
+            row_due("RAW", true, month_date(1, 2), dec!(1)),
+            row_due("RAW", true, month_date(1, 12), dec!(4)),
+            row_due("RAW", true, month_date(1, 16), dec!(6)),
+            row_due("RAW", false, month_date(1, 24), dec!(10)),
+            row_due("RAW", true, month_date(1, 25), dec!(3)),
+            row_due("RAW", true, month_date(6, 1), dec!(100)),
+        ];
+        let filter = GridFilter {
+            horizon: Some(20),
+            ..GridFilter::default()
+        };
+
+        let grids = get_grid(&part_dtl, &[on_hand("RAW", dec!(7))], &filter, today);
+        assert_eq!(grids.len(), 1);
+
+        let buckets = &grids[0].buckets;
+        let starts: Vec<NaiveDate> = buckets.iter().map(|b| b.start).collect();
+        assert_eq!(
+            starts,
+            vec![month_date(1, 8), month_date(1, 15), month_date(1, 22), month_date(1, 29)]
+        );
+        assert_eq!(buckets[0].end, month_date(1, 14));
+
+        // Past due falls into the first bucket
+        assert_eq!(buckets[0].gross_requirements, dec!(5));
+        assert_eq!(buckets[0].projected_on_hand, dec!(2));
+        assert_eq!(buckets[1].net_requirements, dec!(4));
+        assert_eq!(buckets[1].projected_on_hand, dec!(0));
+        assert_eq!(buckets[2].scheduled_receipts, dec!(10));
+        assert_eq!(buckets[2].projected_on_hand, dec!(7));
+        assert_eq!(buckets[3].gross_requirements, dec!(0));
+    }
+
+    #[test]
+    fn buckets_by_month_for_the_parts_asked_for() {
+        let part_dtl = vec![
+            row_due("RAW", true, month_date(2, 20), dec!(4)),
+            row_due("SUB", true, month_date(1, 20), dec!(1)),
+        ];
+        let filter = GridFilter {
+            part: Some("RAW, FG".to_owned()),
+            bucket: BucketSize::Month,
+            horizon: Some(60),
+        };
+
+        let grids = get_grid(&part_dtl, &[on_hand("FG", dec!(3))], &filter, month_date(1, 10));
+        let parts: Vec<&str> = grids.iter().map(|g| g.part_number.as_str()).collect();
+        assert_eq!(parts, vec!["FG", "RAW"]);
+
+        assert_eq!(grids[0].buckets.len(), 3);
+        assert_eq!(grids[0].buckets[2].projected_on_hand, dec!(3));
+        assert_eq!(grids[1].buckets[1].start, month_date(2, 1));
+        assert_eq!(grids[1].buckets[1].end, month_date(2, 29));
+        assert_eq!(grids[1].buckets[1].net_requirements, dec!(4));
+    }
+}
@@ -1,18 +1,21 @@
-use async_std::net::TcpStream;
-
 use chrono::NaiveDate;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use serde::Serialize;
-use std::io::{Error, ErrorKind};
-use tiberius::{Client, Query};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tiberius::Query;
 
-use crate::{parttimephase::Demand, sql::get_sql_config};
+use crate::{
+    config::PlanningScope,
+    parttimephase::Demand,
+    sql::{parameter_list, SqlPool},
+};
 
 #[allow(dead_code)]
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JobMtl {
     pub job_num: String,
+    pub plant: String,
     pub asm: i32,
     pub mtl: i32,
     pub jobop: i32,
@@ -21,50 +24,82 @@ pub struct JobMtl {
     pub direct: bool,
     pub req_qty: Decimal,
     pub issued_qty: Decimal,
+    /// The UOM the required quantity was loaded in, which is the part's inventory UOM once
+    /// normalized. The issued quantity is always in the inventory UOM.
+    #[serde(default)]
+    pub uom: String,
     pub req_date: NaiveDate,
+    #[serde(skip_deserializing)]
     pub demand: Vec<Demand>,
 }
 
-pub async fn get_all_job_boms() -> Result<Vec<JobMtl>, anyhow::Error> {
-    let config = get_sql_config();
+/// Groups job materials by job number, keeping the materials in their original order.
+pub fn group_job_boms(job_mtls: Vec<JobMtl>) -> HashMap<String, Vec<JobMtl>> {
+    let mut job_boms: HashMap<String, Vec<JobMtl>> = HashMap::new();
+
+    for job_mtl in job_mtls {
+        job_boms
+            .entry(job_mtl.job_num.to_owned())
+            .or_default()
+            .push(job_mtl);
+    }
 
-    // Create TCP TcpStream
-    let tcp = TcpStream::connect(&config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
+    job_boms
+}
 
-    // Connect to server
-    let mut client = Client::connect(config, tcp).await?;
+pub async fn get_all_job_boms(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+) -> Result<Vec<JobMtl>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
 
     // Construct Query
     let mut query_string = "
             SELECT
                 JM.JobNum,
+                JM.Plant,
                 JM.AssemblySeq,
                 JM.MtlSeq,
                 JM.PartNum,
                 JM.Description,
                 JM.Direct,
-                JM.RequiredQty,
+                JM.BaseRequiredQty,
                 JM.IssuedQty,
+                JM.BaseUOM,
                 JM.ReqDate,
                 JM.RelatedOperation
             FROM 
                 Erp.JobMtl as JM
+            INNER JOIN Erp.JobHead as JH on
+                JH.Company = JM.Company
+                and JH.JobNum = JM.JobNum
             WHERE 
-                JM.Company = 'AE'
+                JM.Company = @P1
+                and JH.JobClosed = 0
+                and JH.JobComplete = 0
             "
     .to_string();
 
+    // Only open jobs in the scope's plants are planned for
+    query_string.push_str(&format!(
+        "and JM.Plant IN ({})\n",
+        parameter_list(1, scope.plants.len())
+    ));
+
     query_string.push_str(
         "
             ORDER BY 
                 JM.JobNum,
+                JM.Plant,
                 JM.AssemblySeq,
                 JM.MtlSeq
         ",
     );
 
-    let select = Query::new(query_string);
+    let mut select = Query::new(query_string);
+    select.bind(scope.company.to_owned());
+    scope.plants.iter().for_each(|plant| select.bind(plant.to_owned()));
 
     let mut result: Vec<JobMtl> = vec![];
 
@@ -76,6 +111,7 @@ pub async fn get_all_job_boms() -> Result<Vec<JobMtl>, anyhow::Error> {
 
     row.iter().for_each(|val| {
         let job_num = val.get("JobNum").unwrap_or("").to_owned();
+        let plant = val.get("Plant").unwrap_or("").to_owned();
         let asm = val.get("AssemblySeq").unwrap_or(0).to_owned();
         let mtl = val.get("MtlSeq").unwrap_or(0).to_owned();
         let jobop = val.get("RelatedOperation").unwrap_or(0).to_owned();
@@ -83,13 +119,14 @@ pub async fn get_all_job_boms() -> Result<Vec<JobMtl>, anyhow::Error> {
         let description = val.get("Description").unwrap_or("").to_owned();
         let direct = val.get::<bool, _>("Direct").unwrap_or(false).to_owned();
         let req_qty = val
-            .get::<Decimal, _>("RequiredQty")
+            .get::<Decimal, _>("BaseRequiredQty")
             .unwrap_or(dec![0.0])
             .to_owned();
         let issued_qty = val
             .get::<Decimal, _>("IssuedQty")
             .unwrap_or(dec![0.0])
             .to_owned();
+        let uom = val.get("BaseUOM").unwrap_or("").to_owned();
         let req_date = val
             .get::<NaiveDate, _>("ReqDate")
             .unwrap_or(NaiveDate::from_ymd_opt(1999, 1, 1).unwrap())
@@ -97,6 +134,7 @@ pub async fn get_all_job_boms() -> Result<Vec<JobMtl>, anyhow::Error> {
 
         result.push(JobMtl {
             job_num,
+            plant,
             asm,
             mtl,
             part_num,
@@ -107,67 +145,66 @@ pub async fn get_all_job_boms() -> Result<Vec<JobMtl>, anyhow::Error> {
             req_date,
             jobop,
             issued_qty,
+            uom,
         });
     });
 
     // println!("{:?}", rows);
 
-    // Close Client Connection
-    client
-        .close()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
-
     // Result set should be cached now
 
-    return Ok(result);
+    Ok(result)
 }
 
-pub async fn get_job_boms(job_numbers: &Vec<&str>) -> Result<Vec<JobMtl>, anyhow::Error> {
-    let config = get_sql_config();
-
-    // Create TCP TcpStream
-    let tcp = TcpStream::connect(&config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    // Connect to server
-    let mut client = Client::connect(config, tcp).await?;
+pub async fn get_job_boms(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+    job_numbers: &[String],
+) -> Result<Vec<JobMtl>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
 
     // Construct Query
     let mut query_string = "
             SELECT
                 JM.JobNum,
+                JM.Plant,
                 JM.AssemblySeq,
                 JM.MtlSeq,
                 JM.PartNum,
                 JM.Description,
                 JM.Direct,
-                JM.RequiredQty,
+                JM.BaseRequiredQty,
                 JM.IssuedQty,
+                JM.BaseUOM,
                 JM.ReqDate,
                 JM.RelatedOperation
             FROM 
                 Erp.JobMtl as JM
+            INNER JOIN Erp.JobHead as JH on
+                JH.Company = JM.Company
+                and JH.JobNum = JM.JobNum
             WHERE 
-                JM.Company = 'AE'
-                and JM.JobNum IN (
+                JM.Company = @P1
+                and JH.JobClosed = 0
+                and JH.JobComplete = 0
             "
     .to_string();
 
-    job_numbers.iter().enumerate().for_each(|(i, _)| {
-        let next = job_numbers.get(i + 1);
-        match next {
-            Some(_) => query_string.push_str(&format!("@P{}, ", i + 1)),
-            None => query_string.push_str(&format!("@P{}", i + 1)),
-        }
-    });
-
-    query_string.push(')');
+    query_string.push_str(&format!(
+        "and JM.Plant IN ({})\n",
+        parameter_list(1, scope.plants.len())
+    ));
+    query_string.push_str(&format!(
+        "and JM.JobNum IN ({})",
+        parameter_list(1 + scope.plants.len(), job_numbers.len())
+    ));
 
     query_string.push_str(
         "
             ORDER BY 
                 JM.JobNum,
+                JM.Plant,
                 JM.AssemblySeq,
                 JM.MtlSeq
         ",
@@ -175,6 +212,8 @@ pub async fn get_job_boms(job_numbers: &Vec<&str>) -> Result<Vec<JobMtl>, anyhow
 
     let mut select = Query::new(query_string);
 
+    select.bind(scope.company.to_owned());
+    scope.plants.iter().for_each(|plant| select.bind(plant.to_owned()));
     job_numbers.iter().for_each(|job| {
         select.bind(job.to_owned());
     });
@@ -189,6 +228,7 @@ pub async fn get_job_boms(job_numbers: &Vec<&str>) -> Result<Vec<JobMtl>, anyhow
 
     row.iter().for_each(|val| {
         let job_num = val.get("JobNum").unwrap_or("").to_owned();
+        let plant = val.get("Plant").unwrap_or("").to_owned();
         let asm = val.get("AssemblySeq").unwrap_or(0).to_owned();
         let mtl = val.get("MtlSeq").unwrap_or(0).to_owned();
         let jobop = val.get("RelatedOperation").unwrap_or(0).to_owned();
@@ -196,104 +236,14 @@ pub async fn get_job_boms(job_numbers: &Vec<&str>) -> Result<Vec<JobMtl>, anyhow
         let description = val.get("Description").unwrap_or("").to_owned();
         let direct = val.get::<bool, _>("Direct").unwrap_or(false).to_owned();
         let req_qty = val
-            .get::<Decimal, _>("RequiredQty")
-            .unwrap_or(dec![0.0])
-            .to_owned();
-        let issued_qty = val
-            .get::<Decimal, _>("IssuedQty")
-            .unwrap_or(dec![0.0])
-            .to_owned();
-        let req_date = val
-            .get::<NaiveDate, _>("ReqDate")
-            .unwrap_or(NaiveDate::from_ymd_opt(1999, 1, 1).unwrap())
-            .to_owned();
-
-        result.push(JobMtl {
-            job_num,
-            asm,
-            mtl,
-            part_num,
-            description,
-            demand: vec![],
-            direct,
-            req_qty,
-            req_date,
-            jobop,
-            issued_qty,
-        });
-    });
-
-    // println!("{:?}", rows);
-
-    // Close Client Connection
-    client
-        .close()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
-
-    // Result set should be cached now
-
-    return Ok(result);
-}
-
-pub async fn get_job_bom(job_num: &str) -> Result<Vec<JobMtl>, anyhow::Error> {
-    let config = get_sql_config();
-
-    // Create TCP TcpStream
-    let tcp = TcpStream::connect(&config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    // Connect to server
-    let mut client = Client::connect(config, tcp).await?;
-
-    // Construct Query
-    let mut select = Query::new(
-        "
-            SELECT
-                JM.JobNum,
-                JM.AssemblySeq,
-                JM.MtlSeq,
-                JM.PartNum,
-                JM.Description,
-                JM.Direct,
-                JM.RequiredQty,
-                JM.ReqDate,
-                JM.RelatedOperation,
-                JM.IssuedQty
-            FROM 
-                Erp.JobMtl as JM
-            WHERE 
-                JM.JobNum = @P1
-                and JM.Company = 'AE'
-            ",
-    );
-
-    select.bind(job_num);
-
-    let mut result: Vec<JobMtl> = vec![];
-
-    // Stream Query
-    let stream = select.query(&mut client).await?;
-
-    // Consume stream
-    let row = stream.into_first_result().await?;
-
-    row.iter().for_each(|val| {
-        let job_num = val.get("JobNum").unwrap_or("").to_owned();
-        let asm = val.get("AssemblySeq").unwrap_or(0).to_owned();
-        let mtl = val.get("MtlSeq").unwrap_or(0).to_owned();
-        let jobop = val.get("RelatedOperation").unwrap_or(0).to_owned();
-        let part_num = val.get("PartNum").unwrap_or("").to_owned();
-        let description = val.get("Description").unwrap_or("").to_owned();
-        let direct = val.get::<bool, _>("Direct").unwrap_or(false).to_owned();
-        let req_qty = val
-            .get::<Decimal, _>("RequiredQty")
+            .get::<Decimal, _>("BaseRequiredQty")
             .unwrap_or(dec![0.0])
             .to_owned();
         let issued_qty = val
             .get::<Decimal, _>("IssuedQty")
             .unwrap_or(dec![0.0])
             .to_owned();
+        let uom = val.get("BaseUOM").unwrap_or("").to_owned();
         let req_date = val
             .get::<NaiveDate, _>("ReqDate")
             .unwrap_or(NaiveDate::from_ymd_opt(1999, 1, 1).unwrap())
@@ -301,6 +251,7 @@ pub async fn get_job_bom(job_num: &str) -> Result<Vec<JobMtl>, anyhow::Error> {
 
         result.push(JobMtl {
             job_num,
+            plant,
             asm,
             mtl,
             part_num,
@@ -311,18 +262,13 @@ pub async fn get_job_bom(job_num: &str) -> Result<Vec<JobMtl>, anyhow::Error> {
             req_date,
             jobop,
             issued_qty,
+            uom,
         });
     });
 
     // println!("{:?}", rows);
 
-    // Close Client Connection
-    client
-        .close()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
-
     // Result set should be cached now
 
-    return Ok(result);
+    Ok(result)
 }
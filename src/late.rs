@@ -0,0 +1,136 @@
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::peg::Pegging;
+
+/// Query string filters for the late peg report. Only pegs at least `min_days_late` days
+/// late are listed, which defaults to one.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct LatePegFilter {
+    pub part: Option<String>,
+    pub sourcefile: Option<String>,
+    pub min_days_late: Option<i64>,
+}
+
+/// A supply pegged to a demand that is due before the supply arrives
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct LatePeg {
+    pub part_number: String,
+    pub plant: String,
+    pub days_late: i64,
+    pub pegged_qty: Decimal,
+    pub supply_due_date: NaiveDate,
+    pub supply_sourcefile: String,
+    pub supply_job_num: String,
+    pub po_num: Option<i32>,
+    pub po_line: Option<i32>,
+    pub po_rel: Option<i32>,
+    pub demand_due_date: NaiveDate,
+    pub demand_sourcefile: String,
+    pub demand_job_num: String,
+    pub asm: i32,
+    pub mtl: i32,
+    pub order: i32,
+    pub order_line: i32,
+    pub order_rel: i32,
+}
+
+/// Lists every late peg, the latest first
+pub fn get_late_pegs(pegging: &Pegging, filter: &LatePegFilter) -> Vec<LatePeg> {
+    let min_days_late = filter.min_days_late.unwrap_or(1).max(1);
+
+    let mut late_pegs: Vec<LatePeg> = pegging
+        .demand
+        .values()
+        .flatten()
+        .filter(|demand| filter.part.as_ref().is_none_or(|part| &demand.part_number == part))
+        .flat_map(|demand| {
+            demand
+                .supply
+                .iter()
+                .filter(|supply| supply.days_late >= min_days_late)
+                .filter(|supply| {
+                    filter
+                        .sourcefile
+                        .as_ref()
+                        .is_none_or(|sourcefile| supply.sourcefile.eq_ignore_ascii_case(sourcefile))
+                })
+                .map(|supply| LatePeg {
+                    part_number: demand.part_number.to_owned(),
+                    plant: demand.plant.to_owned(),
+                    days_late: supply.days_late,
+                    pegged_qty: supply.pegged_qty,
+                    supply_due_date: supply.due_date,
+                    supply_sourcefile: supply.sourcefile.to_owned(),
+                    supply_job_num: supply.job_num.to_owned(),
+                    po_num: supply.po_num,
+                    po_line: supply.po_line,
+                    po_rel: supply.po_rel,
+                    demand_due_date: demand.due_date,
+                    demand_sourcefile: demand.sourcefile.to_owned(),
+                    demand_job_num: demand.job_num.to_owned(),
+                    asm: demand.asm,
+                    mtl: demand.mtl,
+                    order: demand.order,
+                    order_line: demand.order_line,
+                    order_rel: demand.order_rel,
+                })
+        })
+        .collect();
+
+    late_pegs.sort_by(|a, b| {
+        b.days_late
+            .cmp(&a.days_late)
+            .then(a.demand_due_date.cmp(&b.demand_due_date))
+            .then(a.part_number.cmp(&b.part_number))
+    });
+
+    late_pegs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{demand, supply};
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+
+    #[test]
+    fn lists_late_pegs_latest_first() {
+        let pegging = Pegging {
+            demand: HashMap::from([(
+                "RAW".to_owned(),
+                vec![
+                    demand(
+                        "RAW",
+                        10,
+                        dec!(3),
+                        vec![supply("OH", 1, 10, dec!(1)), supply("PO", 12, 10, dec!(2))],
+                    ),
+                    demand("RAW", 11, dec!(3), vec![supply("JH", 20, 11, dec!(3))]),
+                ],
+            )]),
+            excess: HashMap::new(),
+        };
+
+        let late_pegs = get_late_pegs(&pegging, &LatePegFilter::default());
+        assert_eq!(late_pegs.len(), 2);
+        assert_eq!(late_pegs[0].supply_sourcefile, "JH");
+        assert_eq!(late_pegs[0].days_late, 9);
+        assert_eq!(late_pegs[1].days_late, 2);
+
+        let filter = LatePegFilter {
+            sourcefile: Some("po".to_owned()),
+            ..LatePegFilter::default()
+        };
+        assert_eq!(get_late_pegs(&pegging, &filter).len(), 1);
+
+        let filter = LatePegFilter {
+            min_days_late: Some(5),
+            ..LatePegFilter::default()
+        };
+        assert_eq!(get_late_pegs(&pegging, &filter)[0].days_late, 9);
+    }
+}
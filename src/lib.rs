@@ -0,0 +1,36 @@
+//! Pegging of Epicor MRP data, served over HTTP by the `apollo` binary
+
+pub mod actions;
+pub mod atp;
+pub mod baq;
+pub mod config;
+pub mod ctp;
+pub mod datasource;
+pub mod directlinks;
+pub mod excess;
+pub mod fingerprint;
+pub mod fixture;
+pub mod jobmtl;
+pub mod late;
+pub mod onhand;
+pub mod parttimephase;
+pub mod sql;
+pub mod getdata;
+pub mod grid;
+pub mod transformtozero;
+pub mod uom;
+pub mod peg;
+pub mod orderrelease;
+pub mod partmtl;
+pub mod partplant;
+pub mod partsubs;
+pub mod backlog;
+pub mod peg_part_dtl;
+pub mod safety;
+pub mod shortage;
+pub mod snapshot;
+pub mod timeline;
+pub mod whatif;
+
+#[cfg(test)]
+mod test_support;
@@ -1,63 +1,61 @@
-extern crate chrono;
-
-mod baq;
-mod directlinks;
-mod jobmtl;
-mod onhand;
-mod parttimephase;
-mod sql;
-mod getdata;
-mod transformtozero;
-mod peg;
-mod orderrelease;
-mod backlog;
-mod peg_part_dtl;
-
 use actix_cors::Cors;
-use actix_web::body::BoxBody;
-use actix_web::http::header::ContentType;
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
-use async_std::net::TcpStream;
-use chrono::NaiveDate;
-use onhand::OnHand;
-use parttimephase::{Demand, Supply, PartDtl};
-use rayon::prelude::*;
-use rust_decimal::Decimal;
-use rust_decimal_macros::dec;
-use serde_json;
-use std::collections::{HashMap, HashSet};
-use std::io::{Error, ErrorKind};
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use chrono::{Local, NaiveDate};
+use serde::Serialize;
+use std::io::Error;
+use std::sync::Arc;
 use std::vec::Vec;
-use tiberius::{Client, Query};
 
-use crate::backlog::get_backlog_result;
-use crate::directlinks::get_make_direct_jobs;
-use crate::getdata::get_new_time_phase_details;
-use crate::jobmtl::{get_job_bom, get_job_boms, get_all_job_boms};
-use crate::onhand::get_parts_on_hand;
-use crate::sql::{get_sql_config, SQLReturnRow};
-use crate::transformtozero::transform_zero_to_none;
+use apollo::actions::{get_actions, ActionFilter};
+use apollo::atp::{get_part_atp, AtpQuery};
+use apollo::backlog::BacklogFilter;
+use apollo::config::{PlanningConfig, PlanningQuery};
+use apollo::ctp::{get_part_ctp, CtpQuery};
+use apollo::datasource::{get_data_source, PlanningDataSource};
+use apollo::excess::{get_excess, ExcessFilter};
+use apollo::grid::{get_grid, GridFilter};
+use apollo::jobmtl::JobMtl;
+use apollo::late::{get_late_pegs, LatePegFilter};
+use apollo::orderrelease::OrderRelease;
+use apollo::parttimephase::Demand;
+use apollo::peg::{release_demand, suggest_transfers, Pegging};
+use apollo::peg_part_dtl::release_tree;
+use apollo::safety::{get_safety_stock_report, SafetyStockFilter};
+use apollo::shortage::{get_shortages, ShortageFilter};
+use apollo::snapshot::{get_refresh_interval, spawn_refresh, Snapshot, SnapshotStore};
+use apollo::timeline::get_part_timeline;
+use apollo::whatif::{simulate, WhatIfRequest};
+
+// Every response served from a snapshot carries the time the snapshot was taken
+const SNAPSHOT_AS_OF_HEADER: &str = "X-Snapshot-As-Of";
 
-impl Responder for SQLReturnRow {
-    type Body = BoxBody;
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    dotenv::dotenv().ok();
 
-    fn respond_to(self, _req: &actix_web::HttpRequest) -> HttpResponse<Self::Body> {
-        let body = serde_json::to_string(&self).unwrap();
+    let config = PlanningConfig::from_env().map_err(|e| Error::other(e.to_string()))?;
+    let data_source = get_data_source().await.map_err(|e| Error::other(e.to_string()))?;
+    let snapshots = Arc::new(SnapshotStore::new(config.default_peg_options()));
 
-        HttpResponse::Ok()
-            .content_type(ContentType::json())
-            .body(body)
-    }
-}
+    // Build the first snapshots straight away and keep them fresh in the background
+    spawn_refresh(
+        snapshots.clone(),
+        data_source.clone(),
+        config.default_scopes(),
+        get_refresh_interval(),
+    );
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
+    let source: web::Data<dyn PlanningDataSource> = web::Data::from(data_source);
+    let snapshots: web::Data<SnapshotStore> = web::Data::from(snapshots);
+    let config = web::Data::new(config);
+
+    HttpServer::new(move || {
         let cors = Cors::permissive();
         App::new()
             .wrap(cors)
+            .app_data(source.clone())
+            .app_data(snapshots.clone())
+            .app_data(config.clone())
             // .service(index)
             .service(job)
             .service(jobs)
@@ -65,97 +63,78 @@ async fn main() -> std::io::Result<()> {
             .service(all)
             .service(all_new)
             .service(get_backlog)
+            .service(get_transfers)
+            .service(post_what_if)
+            .service(get_shortage_report)
+            .service(get_late_report)
+            .service(get_action_messages)
+            .service(get_excess_report)
+            .service(get_timeline)
+            .service(get_atp)
+            .service(get_ctp)
+            .service(get_part_grid)
+            .service(get_safety_stock)
+            .service(get_snapshot)
+            .service(refresh_snapshot)
     })
     .bind(("0.0.0.0", 8081))?
     .run()
     .await
 }
 
-#[get("/all/all")]
-async fn all() -> impl Responder {
-    let data = get_all_time_phase_data().await.unwrap();
-
-    let res = Arc::try_unwrap(data).expect("").into_inner().expect("");
-
-    let response = HttpResponse::Ok()
-        .content_type("application/json")
-        .body(serde_json::to_string(&res).unwrap());
-
-    // Get the data
-    // Filter the data by job_num
-    //      In order to do this, we need to get the entire BOM for the job. Need JobMtl table
-    //      Then filter entire list of data for each part on the BOM. Only return the result sets
-    //      where the Demand is for the related job
-
-    response
+/// Gets the snapshot of the company and plants picked on the request. The first request
+/// for a scope waits for its snapshot to be built.
+async fn scoped_snapshot(
+    source: &dyn PlanningDataSource,
+    snapshots: &SnapshotStore,
+    config: &PlanningConfig,
+    query: &PlanningQuery,
+) -> Result<Arc<Snapshot>, HttpResponse> {
+    let scope = config
+        .scope(query)
+        .map_err(|e| HttpResponse::BadRequest().body(e))?;
+
+    snapshots
+        .get_or_refresh(source, &scope)
+        .await
+        .map_err(|e| HttpResponse::InternalServerError().body(format!("Error building snapshot: {}", e)))
 }
 
-#[get("/all/new")]
-async fn all_new() -> impl Responder {
-    let data = get_new_time_phase_details().await.unwrap();
-
-
-    // let response = HttpResponse::Ok()
-    //     .content_type("application/json")
-    //     .body(serde_json::to_string(&data).unwrap());
-    //
-    let hashmap_lock = data.lock().unwrap();
-    let hashmap = hashmap_lock.clone();
-    drop(hashmap_lock);
-
-    let single_part = hashmap.get("853-305102-005(B)").unwrap();
-
-    let response = match serde_json::to_string(&single_part) {
-        Ok(json_string) => HttpResponse::Ok().content_type("application/json").body(json_string),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Error serializing data: {}", e)),
-    };
-
-    // Get the data
-    // Filter the data by job_num
-    //      In order to do this, we need to get the entire BOM for the job. Need JobMtl table
-    //      Then filter entire list of data for each part on the BOM. Only return the result sets
-    //      where the Demand is for the related job
+/// The snapshot's pegging with the options asked for on the request
+async fn snapshot_pegging(
+    snapshot: &Arc<Snapshot>,
+    config: &PlanningConfig,
+    query: &PlanningQuery,
+) -> Result<Arc<Pegging>, HttpResponse> {
+    let options = config
+        .peg_options(query)
+        .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    if options == snapshot.options {
+        return Ok(snapshot.pegging.clone());
+    }
 
-    response
+    // Re-pegging with options the snapshot has not cached yet is CPU bound, so keep it off
+    // the async workers
+    let snapshot = snapshot.clone();
+    web::block(move || snapshot.pegging_with(&options))
+        .await
+        .map_err(|e| HttpResponse::InternalServerError().body(format!("Error pegging data: {}", e)))
 }
 
-#[get("/{part}")]
-async fn index(path: web::Path<String>) -> impl Responder {
-    let part_num: String = path.into_inner();
-    let data = get_time_phase_data(None).await.unwrap();
-
-    let response = match Arc::try_unwrap(data)
-        .expect("Lock still has multiple owners")
-        .into_inner()
-        .expect("Mutex cannot be locked")
-        .get(&part_num)
-    {
-        Some(res) => HttpResponse::Ok()
+/// Serializes data read from a snapshot, stamped with the time the snapshot was taken
+fn snapshot_response<T: Serialize>(snapshot: &Snapshot, data: &T) -> HttpResponse {
+    match serde_json::to_string(data) {
+        Ok(res) => HttpResponse::Ok()
             .content_type("application/json")
-            .body(serde_json::to_string(&res).unwrap()),
-        None => HttpResponse::UnavailableForLegalReasons().finish(),
-    };
-
-    // Get the data
-    // Filter the data by job_num
-    //      In order to do this, we need to get the entire BOM for the job. Need JobMtl table
-    //      Then filter entire list of data for each part on the BOM. Only return the result sets
-    //      where the Demand is for the related job
-
-    response
+            .insert_header((SNAPSHOT_AS_OF_HEADER, snapshot.as_of.to_rfc3339()))
+            .body(res),
+        Err(_) => HttpResponse::UnavailableForLegalReasons().finish(),
+    }
 }
 
-#[get("order/{orderlinerel}")]
-async fn get_order(path: web::Path<String>) -> impl Responder {
-    let orderlinerel: String = path.into_inner();
-    let mut spl = orderlinerel.split("-");
-    let order_num = spl.next();
-    let _order_line = spl.next();
-    let _order_rel = spl.next();
-
-    let _time_phase_data = get_time_phase_data(None).await.unwrap();
-
-    let response = match serde_json::to_string(&order_num) {
+#[get("/snapshot")]
+async fn get_snapshot(snapshots: web::Data<SnapshotStore>) -> impl Responder {
+    let response = match serde_json::to_string(&snapshots.status()) {
         Ok(res) => HttpResponse::Ok()
             .content_type("application/json")
             .body(res),
@@ -165,94 +144,61 @@ async fn get_order(path: web::Path<String>) -> impl Responder {
     response
 }
 
+/// Refreshes the snapshot of the company and plants picked on the request, or every
+/// snapshot when none are picked
+#[post("/snapshot/refresh")]
+async fn refresh_snapshot(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+) -> impl Responder {
+    let refreshed = if query.is_empty() {
+        snapshots
+            .refresh_all(source.get_ref(), &config.default_scopes())
+            .await
+    } else {
+        let scope = match config.scope(&query) {
+            Ok(scope) => scope,
+            Err(e) => return HttpResponse::BadRequest().body(e),
+        };
+        snapshots.refresh(source.get_ref(), &scope).await.map(|_| ())
+    };
 
-#[get("jobs/{job_numbers}")]
-async fn jobs(path: web::Path<String>) -> impl Responder {
-    let url_str: String = path.into_inner();
-    let job_numbers = url_str.split("&").collect::<Vec<&str>>();
-
-    let mut job_bom = get_job_boms(&job_numbers).await.unwrap();
-    let mut seen = HashSet::new();
-
-    // Get the unique part numbers
-    let unique: Vec<_> = job_bom
-        .iter()
-        .filter(|item| seen.insert(item.part_num.clone()))
-        .map(|item| item.part_num.clone())
-        .collect();
-    //let part_numbers = job_bom.iter().map(|item| item.part_num.to_owned()).collect::<Vec<String>>();
-    
-    //Start the pegging process for the unique part numbers
-    let peg_process_start = Instant::now();
-    println!("Starting Pegging");
-    let time_phase_data = get_time_phase_data(Some(unique)).await.unwrap();
-    println!("Data retrieved: {:#?}", time_phase_data);
-    let peg_process_dur = peg_process_start.elapsed();
-    println!("Pegging took: {:#?}", peg_process_dur);
-
-    let new_time_phase_data = Arc::try_unwrap(time_phase_data)
-        .expect("Lock still has multiple owners")
-        .into_inner()
-        .expect("Mutex cannot be unlocked");
-
-    for job_num in job_numbers.iter() {
-
-    for job_mtl in &mut job_bom {
-        if job_mtl.direct {
-            println!("This line is make or purchase direct!");
-            let mut dmd = Demand {
-                part_number: job_mtl.part_num.to_owned(),
-                job_num: job_mtl.job_num.to_owned(),
-                asm: job_mtl.asm,
-                mtl: job_mtl.mtl,
-                demand_qty: job_mtl.req_qty,
-                pegged_demand: job_mtl.req_qty,
-                order: 0,
-                order_line: 0,
-                order_rel: 0,
-                due_date: job_mtl.req_date,
-                sourcefile: "JM".to_owned(),
-                supply: vec![],
-            };
-            let make_direct_jobs = get_make_direct_jobs(&job_mtl.job_num, job_mtl.asm, job_mtl.mtl)
-                .await
-                .unwrap();
-
-            for job_prod in make_direct_jobs {
-                dmd.supply.push(Supply {
-                    due_date: job_prod.due_date,
-                    sourcefile: "JH".to_owned(),
-                    pegged_qty: job_prod.prod_qty,
-                    job_num: job_prod.job_num,
-                    asm: 0,
-                    mtl: 0,
-                    po_num: None,
-                    po_rel: None,
-                    po_line: None,
-                })
-            }
-
-            job_mtl.demand.push(dmd);
-        } else {
-            //let pegged_demand = time_phase_data.get(&job_mtl.part_num).unwrap();
-            let pegged_demand = new_time_phase_data.get(&job_mtl.part_num).unwrap();
-
-            for demand_row in pegged_demand {
-                if &&demand_row.job_num == job_num {
-                    job_mtl.demand.push(demand_row.clone())
-                }
-            }
-        }
-    }
+    if let Err(e) = refreshed {
+        return HttpResponse::InternalServerError()
+            .body(format!("Error refreshing snapshot: {}", e));
     }
 
-    let response = match serde_json::to_string(&job_bom) {
+    let response = match serde_json::to_string(&snapshots.status()) {
         Ok(res) => HttpResponse::Ok()
             .content_type("application/json")
             .body(res),
         Err(_) => HttpResponse::UnavailableForLegalReasons().finish(),
     };
 
+    response
+}
+
+#[get("/all/all")]
+async fn all(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
+
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
+
+    let response = snapshot_response(&snapshot, &pegging.demand);
+
     // Get the data
     // Filter the data by job_num
     //      In order to do this, we need to get the entire BOM for the job. Need JobMtl table
@@ -262,57 +208,75 @@ async fn jobs(path: web::Path<String>) -> impl Responder {
     response
 }
 
-#[get("/backlog")]
-async fn get_backlog() -> impl Responder {
-    println!("Testing backlog path");
+/// The backlog with each release's pegged demand walked all the way down through the jobs
+/// supplying it, so every sub-assembly and material holding a release up can be seen
+#[get("/all/new")]
+async fn all_new(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    filter: web::Query<BacklogFilter>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    // Get the backlog of sales order releases
-    let mut backlog = get_backlog_result().await.unwrap();
-    println!("Backlog length: {:#?}", backlog.len());
-
-    // Then get all of the job get all of the job boms 
-    // This is a vec for now, but it really should be a HashMap
-    let job_bom = get_all_job_boms().await.unwrap();
-    
-    // Get all of the pegging data by part number
-    let time_phase_data = get_all_time_phase_data().await.unwrap();
-
-    let new_time_phase_data = Arc::try_unwrap(time_phase_data)
-        .expect("Lock still has multiple owners")
-        .into_inner()
-        .expect("Mutex cannot be unlocked");
-
-    // Peg sales all sales orders in the backlog 
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
+
+    let mut backlog: Vec<OrderRelease> = snapshot
+        .backlog
+        .iter()
+        .filter(|row| filter.matches(row))
+        .cloned()
+        .collect();
+
+    let today = Local::now().date_naive();
     backlog.iter_mut().for_each(|row| {
-        let part_number = row.part_number.clone();
-        let demand = new_time_phase_data.get(&part_number);
-        match demand {
-            Some(dmd) => {
-                let filtered_demand: Vec<&Demand> = dmd
-                    .iter()
-                    .filter(|demand| 
-                        demand.order == row.order.unwrap() 
-                        && demand.order_line == row.line.unwrap() 
-                        && demand.order_rel == row.release.unwrap()
-                    )
-                    .collect();
-
-                filtered_demand.iter().for_each(|d| {
-                    let new_dmd = d.to_owned().to_owned();
-                row.demand.push(new_dmd);
-                })
-            },
-            None => return
+        let demand: Vec<Demand> = match (row.order, row.line, row.release) {
+            (Some(order), Some(line), Some(release)) => release_tree(
+                &pegging,
+                &snapshot.job_boms,
+                &row.part_number,
+                order,
+                line,
+                release,
+            ),
+            _ => vec![],
         };
+
+        peg_order_release(&snapshot, &pegging, row, demand, today);
     });
 
+    snapshot_response(&snapshot, &backlog)
+}
 
+#[get("/{part}")]
+async fn index(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let part_num: String = path.into_inner();
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    let response = match serde_json::to_string(&new_time_phase_data) {
-        Ok(res) => HttpResponse::Ok()
-            .content_type("application/json")
-            .body(res),
-        Err(_) => HttpResponse::UnavailableForLegalReasons().finish(),
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
+
+    let response = match pegging.demand.get(&part_num) {
+        Some(res) => snapshot_response(&snapshot, res),
+        None => HttpResponse::UnavailableForLegalReasons().finish(),
     };
 
     // Get the data
@@ -324,97 +288,234 @@ async fn get_backlog() -> impl Responder {
     response
 }
 
-#[get("job/{job_num}")]
-async fn job(path: web::Path<String>) -> impl Responder {
-    let job_num: String = path.into_inner();
+/// The projected available balance of a part in each plant, event by event
+#[get("/parts/{part}/timeline")]
+async fn get_timeline(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let part_num: String = path.into_inner();
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    let mut job_bom = get_job_bom(&job_num).await.unwrap();
+    let timelines = get_part_timeline(&snapshot.part_dtl, &snapshot.on_hand, &part_num);
+    if timelines.is_empty() {
+        return HttpResponse::NotFound().finish();
+    }
 
-    let mut is_everything_issued = true;
-    job_bom.iter().for_each(|mtl| {
-        if mtl.issued_qty < mtl.req_qty {
-            is_everything_issued = false;
-        }
-    });
+    snapshot_response(&snapshot, &timelines)
+}
 
-    if is_everything_issued {
-        println!("Everything is issued complete!");
-        return HttpResponse::Ok()
-            .content_type("application/json")
-            .body(serde_json::to_string(&job_bom).unwrap());
+/// Cumulative available to promise of a part in each plant and the earliest date the
+/// requested quantity can be promised
+#[get("/parts/{part}/atp")]
+async fn get_atp(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    atp_query: web::Query<AtpQuery>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let part_num: String = path.into_inner();
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
     };
 
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
+    let atp = get_part_atp(
+        &pegging,
+        &snapshot.on_hand,
+        &part_num,
+        &atp_query,
+        Local::now().date_naive(),
+    );
+    if atp.is_empty() {
+        return HttpResponse::NotFound().finish();
+    }
 
+    snapshot_response(&snapshot, &atp)
+}
 
-    let part_numbers = job_bom.iter().map(|item| item.part_num.to_owned()).collect::<Vec<String>>();
+/// Whether a quantity of a part can be made in time when its ATP falls short, walking its
+/// BOM to find when the materials are in and which one holds the promise date back
+#[get("/parts/{part}/ctp")]
+async fn get_ctp(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    ctp_query: web::Query<CtpQuery>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let part_num: String = path.into_inner();
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
-    let time_phase_data = get_time_phase_data(Some(part_numbers)).await.unwrap();
+    let ctp = get_part_ctp(
+        &pegging,
+        &snapshot.on_hand,
+        &snapshot.boms,
+        &snapshot.part_plants,
+        &part_num,
+        &ctp_query,
+        Local::now().date_naive(),
+    );
+    if ctp.is_empty() {
+        return HttpResponse::NotFound().finish();
+    }
 
-    let new_time_phase_data = Arc::try_unwrap(time_phase_data)
-        .expect("Lock still has multiple owners")
-        .into_inner()
-        .expect("Mutex cannot be unlocked");
+    snapshot_response(&snapshot, &ctp)
+}
 
-    for job_mtl in &mut job_bom {
-        if job_mtl.issued_qty >= job_mtl.req_qty {
-            println!("Material is issued complete");
-            continue;
-        } else if job_mtl.direct {
-            println!("This line is make or purchase direct!");
-            let mut dmd = Demand {
-                part_number: job_mtl.part_num.to_owned(),
-                job_num: job_mtl.job_num.to_owned(),
-                asm: job_mtl.asm,
-                mtl: job_mtl.mtl,
-                demand_qty: job_mtl.req_qty,
-                pegged_demand: job_mtl.req_qty,
-                order: 0,
-                order_line: 0,
-                order_rel: 0,
-                due_date: job_mtl.req_date,
-                sourcefile: "JM".to_owned(),
-                supply: vec![],
-            };
-            let make_direct_jobs = get_make_direct_jobs(&job_mtl.job_num, job_mtl.asm, job_mtl.mtl)
-                .await
-                .unwrap();
-
-            for job_prod in make_direct_jobs {
-                dmd.supply.push(Supply {
-                    due_date: job_prod.due_date,
-                    sourcefile: "JH".to_owned(),
-                    pegged_qty: job_prod.prod_qty,
-                    job_num: job_prod.job_num,
-                    asm: 0,
-                    mtl: 0,
-                    po_line: None,
-                    po_rel: None,
-                    po_num: None,
-                })
-            }
+/// Gross requirements, scheduled receipts, projected on hand and net requirements of the
+/// parts in day, week or month buckets
+#[get("/grid")]
+async fn get_part_grid(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    filter: web::Query<GridFilter>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-            job_mtl.demand.push(dmd);
-        } else {
-            //let pegged_demand = time_phase_data.get(&job_mtl.part_num).unwrap();
-            let pegged_demand = new_time_phase_data.get(&job_mtl.part_num).unwrap();
+    let grid = get_grid(
+        &snapshot.part_dtl,
+        &snapshot.on_hand,
+        &filter,
+        Local::now().date_naive(),
+    );
 
-            for demand_row in pegged_demand {
-                if demand_row.job_num == job_num {
-                    job_mtl.demand.push(demand_row.clone())
-                }
-            }
+    snapshot_response(&snapshot, &grid)
+}
+
+#[get("order/{orderlinerel}")]
+async fn get_order(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let orderlinerel: String = path.into_inner();
+
+    let (order, line, release) = match OrderRelease::parse_key(&orderlinerel) {
+        Some(key) => key,
+        None => {
+            return HttpResponse::BadRequest().body(format!(
+                "Invalid order release '{}', expected {{order}}-{{line}}-{{rel}}",
+                orderlinerel
+            ))
         }
-    }
+    };
 
-    let response = match serde_json::to_string(&job_bom) {
-        Ok(res) => HttpResponse::Ok()
-            .content_type("application/json")
-            .body(res),
-        Err(_) => HttpResponse::UnavailableForLegalReasons().finish(),
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
+
+    // Open releases are in the snapshot's backlog. Anything else is looked up directly.
+    let backlog_release = snapshot
+        .backlog
+        .iter()
+        .find(|row| {
+            row.order == Some(order) && row.line == Some(line) && row.release == Some(release)
+        })
+        .cloned();
+
+    let mut order_release = match backlog_release {
+        Some(order_release) => order_release,
+        None => match source
+            .order_release(&snapshot.scope, order, line, release)
+            .await
+        {
+            Ok(Some(order_release)) => order_release,
+            Ok(None) => {
+                return HttpResponse::NotFound()
+                    .body(format!("Order release {} does not exist", orderlinerel))
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error getting order release: {}", e))
+            }
+        },
+    };
+
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
+
+    // Keep the pegged demand for this release, walked down through every job supplying it
+    let demand = release_tree(
+        &pegging,
+        &snapshot.job_boms,
+        &order_release.part_number,
+        order,
+        line,
+        release,
+    );
+    peg_order_release(
+        &snapshot,
+        &pegging,
+        &mut order_release,
+        demand,
+        Local::now().date_naive(),
+    );
+
+    snapshot_response(&snapshot, &order_release)
+}
+
+#[get("jobs/{job_numbers}")]
+async fn jobs(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let url_str: String = path.into_inner();
+    let job_numbers = url_str
+        .split('&')
+        .map(|job_num| job_num.to_owned())
+        .collect::<Vec<String>>();
+
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
     };
 
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
+
+    //Start the pegging process for the job materials
+    let job_bom = peg_job_materials(&snapshot, &pegging, &job_numbers);
+
+    let response = snapshot_response(&snapshot, &job_bom);
+
     // Get the data
     // Filter the data by job_num
     //      In order to do this, we need to get the entire BOM for the job. Need JobMtl table
@@ -424,548 +525,309 @@ async fn job(path: web::Path<String>) -> impl Responder {
     response
 }
 
-pub async fn get_all_time_phase_data() -> Result<Arc<Mutex<HashMap<String, Vec<Demand>>>>, anyhow::Error>
-{
-    let config = get_sql_config();
-
-    // Create TCP TcpStream
-    let tcp = TcpStream::connect(&config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    // Connect to server
-    let mut client = Client::connect(config, tcp).await?;
-    let on_hand = get_parts_on_hand().await.unwrap();
-
-    // Construct Query
-    let mut new_query = 
-        "
-            SELECT
-                PD.RequirementFlag,
-                PD.PartNum,
-                PD.SourceFile,
-                PD.Type,
-                PD.DueDate,
-                PD.Quantity,
-                PD.JobNum,
-                PD.AssemblySeq,
-                PD.JobSeq,
-                PD.OrderNum,
-                PD.OrderLine,
-                PD.OrderRelNum,
-                PD.PONum,
-                PD.POLine,
-                PD.PORelNum,
-                PD.StockTrans
-            FROM 
-                Erp.PartDtl as PD
-            LEFT OUTER JOIN Erp.Part as PART on 
-                PART.Company = PD.Company
-                and PART.PartNum = PD.PartNum
-                and (not PART.ProdCode = 'ETO' and not PART.ProdCode = 'RMA' and not PART.ProdCode = 'SAMPLE' and not PART.ProdCode = 'TOOL')
-            WHERE 
-                PD.Type <> 'Sub'
-                and PD.Plant = 'MfgSys'
-                and PD.Company = 'AE'".to_string();
-
-    new_query.push_str("
-            ORDER BY 
-                PD.PartNum,
-                PD.DueDate,
-                PD.RequirementFlag
-        ");
-
-    let mut result: Vec<SQLReturnRow> = vec![];
-
-    let qry_start = Instant::now();
-    let mut new_query = Query::new(new_query);
-
-    // Stream Query
-    let stream = new_query.query(&mut client).await?;
-
-    // Consume stream
-    let row = stream.into_first_result().await?;
-    let qry_dur = qry_start.elapsed();
-    println!("Query took: {:#?}", qry_dur);
-
-    let mut net_qty = dec!(0.0);
-    let mut id = 0;
-
-    let tf_start = Instant::now();
-    row.iter().for_each(|val| {
-        let requirement = val
-            .get::<bool, _>("RequirementFlag")
-            .unwrap_or(false.to_owned())
-            .to_owned();
-        let direct = val
-            .get::<bool, _>("StockTrans")
-            .unwrap_or(false.to_owned())
-            .to_owned();
-        let sourcefile = val
-            .get::<&str, &str>("SourceFile")
-            .unwrap_or("ER")
-            .to_owned();
-        let part_num = val
-            .get::<&str, &str>("PartNum")
-            .unwrap_or("ERROR")
-            .to_owned();
-        let qty = val
-            .get::<Decimal, _>("Quantity")
-            .unwrap_or(dec!(0.0))
-            .to_owned();
-        let due_date = val
-            .get::<NaiveDate, _>("DueDate")
-            .unwrap_or(NaiveDate::from_ymd_opt(1999, 1, 1).unwrap())
-            .to_owned();
-        let job_num = val.get::<&str, &str>("JobNum").unwrap().to_owned();
-        let asm = val.get::<i32, _>("AssemblySeq").unwrap().to_owned();
-        let mtl = val.get::<i32, _>("JobSeq").unwrap().to_owned();
-        let order = val.get::<i32, _>("OrderNum").unwrap().to_owned();
-        let order_line = val.get::<i32, _>("OrderLine").unwrap().to_owned();
-        let order_rel = val.get::<i32, _>("OrderRelNum").unwrap().to_owned();
-        let po_num = transform_zero_to_none(val.get::<i32, _>("PONum").to_owned());
-        let po_line = transform_zero_to_none(val.get::<i32, _>("POLine").to_owned());
-        let po_rel = transform_zero_to_none(val.get::<i32, _>("PORelNum").to_owned());
-
-        if requirement {
-            net_qty = net_qty.saturating_sub(qty);
-        } else {
-            net_qty = net_qty.saturating_add(qty);
-        }
-
-        result.push(SQLReturnRow {
-            id,
-            part_num,
-            job_num,
-            asm,
-            mtl,
-            requirement,
-            due_date,
-            sourcefile,
-            qty,
-            net_qty,
-            order,
-            order_line,
-            order_rel,
-            po_num,
-            po_line,
-            po_rel,
-            direct: !direct,
-        });
-
-        id += 1;
-    });
-    let tf_dur = tf_start.elapsed();
-    println!("Transform Took: {:#?}", tf_dur);
-
-    // Close Client Connection
-    client
-        .close()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+#[get("/backlog")]
+async fn get_backlog(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    filter: web::Query<BacklogFilter>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    println!("Getting unique parts");
-    let unq_start = Instant::now();
-    //let mut new_result = HashMap::new();
-    let multi_results = Arc::new(Mutex::new(HashMap::new()));
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
-    let unique_part_numbers = get_unique_part_numbers(&result);
-    let unq_dur = unq_start.elapsed();
-    println!("Getting Unique Parts took: {:#?}", unq_dur);
+    // Get the backlog of sales order releases
+    let mut backlog: Vec<OrderRelease> = snapshot
+        .backlog
+        .iter()
+        .filter(|row| filter.matches(row))
+        .cloned()
+        .collect();
 
-    // Peg unique part numbers
-    unique_part_numbers.par_iter().for_each(|item| {
-        let multi_peg_data = multi_peg_part_dtl(&result, &on_hand, &item);
-        let mut multi_results = multi_results.lock().unwrap();
-        multi_results.insert(item.to_owned(), multi_peg_data);
+    // Peg all sales orders in the backlog
+    let today = Local::now().date_naive();
+    backlog.iter_mut().for_each(|row| {
+        let demand: Vec<Demand> = match (row.order, row.line, row.release) {
+            (Some(order), Some(line), Some(release)) => {
+                release_demand(&pegging, &row.part_number, order, line, release)
+            }
+            _ => vec![],
+        };
 
+        peg_order_release(&snapshot, &pegging, row, demand, today);
     });
 
-    Ok(multi_results)
+    snapshot_response(&snapshot, &backlog)
 }
 
+#[get("/shortages")]
+async fn get_shortage_report(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    filter: web::Query<ShortageFilter>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-pub async fn get_time_phase_data(part_numbers: Option<Vec<String>>) -> Result<Arc<Mutex<HashMap<String, Vec<Demand>>>>, anyhow::Error>
-{
-    let parts = part_numbers.unwrap();
-    let config = get_sql_config();
-
-    // Create TCP TcpStream
-    let tcp = TcpStream::connect(&config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    // Connect to server
-    let mut client = Client::connect(config, tcp).await?;
-    let on_hand = get_parts_on_hand().await.unwrap();
-
-    // Construct Query
-
-    let mut new_query = 
-        "
-            SELECT
-                PD.RequirementFlag,
-                PD.PartNum,
-                PD.SourceFile,
-                PD.Type,
-                PD.DueDate,
-                PD.Quantity,
-                PD.JobNum,
-                PD.AssemblySeq,
-                PD.JobSeq,
-                PD.OrderNum,
-                PD.OrderLine,
-                PD.OrderRelNum,
-                PD.PONum,
-                PD.POLine,
-                PD.PORelNum,
-                PD.StockTrans
-            FROM 
-                Erp.PartDtl as PD
-            LEFT OUTER JOIN Erp.Part as PART on 
-                PART.Company = PD.Company
-                and PART.PartNum = PD.PartNum
-                and (not PART.ProdCode = 'ETO' and not PART.ProdCode = 'RMA' and not PART.ProdCode = 'SAMPLE' and not PART.ProdCode = 'TOOL')
-            WHERE 
-                PD.Type <> 'Sub'
-                and PD.Plant = 'MfgSys'
-                and PD.Company = 'AE'
-                and PD.PartNum IN (".to_string();
-
-    parts.iter().enumerate().for_each(|(i, _)| {
-        let next = parts.get(i + 1);
-        match next {
-            Some(_) => new_query.push_str(&format!("@P{}, ", i + 1)),
-            None => new_query.push_str(&format!("@P{}", i + 1))
-        }
-    });
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
-    new_query.push(')');
-    new_query.push_str("
-            ORDER BY 
-                PD.PartNum,
-                PD.DueDate,
-                PD.RequirementFlag
-        ");
+    let shortages = get_shortages(&pegging, &filter, Local::now().date_naive());
 
-    let mut result: Vec<SQLReturnRow> = vec![];
+    snapshot_response(&snapshot, &shortages)
+}
 
-    let qry_start = Instant::now();
-    let mut new_query = Query::new(new_query);
-    parts.iter().for_each(|part| {
-        new_query.bind(part.to_owned());
-    });
+/// Parts whose projected balance drops below safety stock and the demands pegged to it
+#[get("/safety-stock")]
+async fn get_safety_stock(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    filter: web::Query<SafetyStockFilter>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    // Stream Query
-    let stream = new_query.query(&mut client).await?;
-
-    // Consume stream
-    let row = stream.into_first_result().await?;
-    let qry_dur = qry_start.elapsed();
-    println!("Query took: {:#?}", qry_dur);
-
-    let mut net_qty = dec!(0.0);
-    let mut id = 0;
-
-    let tf_start = Instant::now();
-    row.iter().for_each(|val| {
-        let requirement = val
-            .get::<bool, _>("RequirementFlag")
-            .unwrap_or(false.to_owned())
-            .to_owned();
-        let direct = val
-            .get::<bool, _>("StockTrans")
-            .unwrap_or(false.to_owned())
-            .to_owned();
-        let sourcefile = val
-            .get::<&str, &str>("SourceFile")
-            .unwrap_or("ER")
-            .to_owned();
-        let part_num = val
-            .get::<&str, &str>("PartNum")
-            .unwrap_or("ERROR")
-            .to_owned();
-        let qty = val
-            .get::<Decimal, _>("Quantity")
-            .unwrap_or(dec!(0.0))
-            .to_owned();
-        let due_date = val
-            .get::<NaiveDate, _>("DueDate")
-            .unwrap_or(NaiveDate::from_ymd_opt(1999, 1, 1).unwrap())
-            .to_owned();
-        let job_num = val.get::<&str, &str>("JobNum").unwrap().to_owned();
-        let asm = val.get::<i32, _>("AssemblySeq").unwrap().to_owned();
-        let mtl = val.get::<i32, _>("JobSeq").unwrap().to_owned();
-        let order = val.get::<i32, _>("OrderNum").unwrap().to_owned();
-        let order_line = val.get::<i32, _>("OrderLine").unwrap().to_owned();
-        let order_rel = val.get::<i32, _>("OrderRelNum").unwrap().to_owned();
-        let po_num = transform_zero_to_none(val.get::<i32, _>("PONum").to_owned());
-        let po_line = transform_zero_to_none(val.get::<i32, _>("POLine").to_owned());
-        let po_rel = transform_zero_to_none(val.get::<i32, _>("PORelNum").to_owned());
-
-        if requirement {
-            net_qty = net_qty.saturating_sub(qty);
-        } else {
-            net_qty = net_qty.saturating_add(qty);
-        }
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
-        result.push(SQLReturnRow {
-            id,
-            part_num,
-            job_num,
-            asm,
-            mtl,
-            requirement,
-            due_date,
-            sourcefile,
-            qty,
-            net_qty,
-            order,
-            order_line,
-            order_rel,
-            po_num,
-            po_line,
-            po_rel,
-            direct: !direct,
-        });
-
-        id += 1;
-    });
-    let tf_dur = tf_start.elapsed();
-    println!("Transform Took: {:#?}", tf_dur);
+    let report = get_safety_stock_report(
+        &snapshot.safety_stock,
+        &snapshot.part_dtl,
+        &snapshot.on_hand,
+        &pegging,
+        &filter,
+        Local::now().date_naive(),
+    );
 
-    // Close Client Connection
-    client
-        .close()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+    snapshot_response(&snapshot, &report)
+}
 
-    println!("Getting unique parts");
-    let unq_start = Instant::now();
-    //let mut new_result = HashMap::new();
-    let multi_results = Arc::new(Mutex::new(HashMap::new()));
+/// Every supply pegged to a demand due before the supply arrives
+#[get("/late")]
+async fn get_late_report(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    filter: web::Query<LatePegFilter>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    let unique_part_numbers = get_unique_part_numbers(&result);
-    let unq_dur = unq_start.elapsed();
-    println!("Getting Unique Parts took: {:#?}", unq_dur);
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
-    // Peg unique part numbers
-    unique_part_numbers.par_iter().for_each(|item| {
-        let multi_peg_data = multi_peg_part_dtl(&result, &on_hand, &item);
-        let mut multi_results = multi_results.lock().unwrap();
-        multi_results.insert(item.to_owned(), multi_peg_data);
+    snapshot_response(&snapshot, &get_late_pegs(&pegging, &filter))
+}
 
-    });
+/// Expedite, defer, cancel, reduce and new supply suggestions drawn from the pegging
+#[get("/actions")]
+async fn get_action_messages(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    filter: web::Query<ActionFilter>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    Ok(multi_results)
-}
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
-fn get_unique_part_numbers(part_dtls: &Vec<SQLReturnRow>) -> Vec<String> {
-    let mut part_numbers: Vec<String> = Vec::new();
-    for part_dtl_row in part_dtls {
-        let mut does_exist = false;
-        let partnum = &part_dtl_row.part_num;
-        for part_number in &part_numbers {
-            if partnum == part_number {
-                does_exist = true
-            }
-        }
-        if does_exist == false {
-            part_numbers.push(partnum.to_string())
-        }
-    }
-    return part_numbers;
+    snapshot_response(
+        &snapshot,
+        &get_actions(&pegging, &snapshot.part_plants, &filter),
+    )
 }
 
-fn _peg_part_dtl(part_dtl: Vec<&SQLReturnRow>, on_hand: &Vec<OnHand>) -> Vec<Demand> {
-    let mut intermediate_pegging: Vec<Demand> = Vec::new();
+/// Supply that is left over once every demand is pegged
+#[get("/excess")]
+async fn get_excess_report(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    filter: web::Query<ExcessFilter>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    let mut remaining_supplies: Vec<SQLReturnRow> = vec![];
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
-    // Add remaining supplies from on hand quantity
-    on_hand.iter().for_each(|row| {
-        let new_oh = SQLReturnRow::new_on_hand(&row.part_num, row.qty);
-        remaining_supplies.push(new_oh);
-    });
+    snapshot_response(&snapshot, &get_excess(&pegging, &filter))
+}
 
-    let part_dtl_supplies: Vec<SQLReturnRow> = part_dtl
-        .clone()
-        .into_iter()
-        .filter(|a| a.requirement == false)
-        .cloned()
-        .collect();
+/// Transfer orders needed to move supply between plants, pegged with transfers allowed
+#[get("/transfers")]
+async fn get_transfers(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    for row in part_dtl_supplies {
-        remaining_supplies.push(row)
-    }
+    let transfer_query = PlanningQuery {
+        transfers: Some(true),
+        ..query.into_inner()
+    };
+    let pegging = match snapshot_pegging(&snapshot, &config, &transfer_query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
-    let mut sorted_demands: Vec<&SQLReturnRow> = part_dtl
-        .clone()
-        .into_iter()
-        .filter(|a| a.requirement == true)
-        .collect();
+    snapshot_response(&snapshot, &suggest_transfers(&pegging))
+}
 
-    sorted_demands.sort_by(|a, b| a.due_date.cmp(&b.due_date));
-
-    for demand in sorted_demands.iter() {
-        let mut pegged_demand = Demand {
-            part_number: demand.part_num.to_owned(),
-            due_date: demand.due_date,
-            sourcefile: demand.sourcefile.to_owned(),
-            demand_qty: demand.qty,
-            job_num: demand.job_num.to_owned(),
-            asm: demand.asm,
-            mtl: demand.mtl,
-            order: demand.order,
-            order_line: demand.order_line,
-            order_rel: demand.order_rel,
-            supply: vec![],
-            pegged_demand: dec!(0.0),
-        };
+/// Re-pegs the parts touched by the overrides in the body and reports how their shortages
+/// and late pegs differ from the snapshot. Nothing is written back to the ERP.
+#[post("/what-if")]
+async fn post_what_if(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    request: web::Json<WhatIfRequest>,
+) -> impl Responder {
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-        let mut demand_quantity_remaining = pegged_demand.demand_qty;
-
-        // While the remaining demand quantity is greater than zero and there is still open supply
-        while demand_quantity_remaining > dec!(0.0) && !remaining_supplies.is_empty() {
-            // Calculate the quantity to be used. This should be equal to either the remaining
-            // demand quantity if it is min, or the remaining supply quantity if it is min
-            let supply_used_quantity =
-                Decimal::min(remaining_supplies[0].qty, demand_quantity_remaining);
-
-            pegged_demand.pegged_demand += supply_used_quantity;
-
-            pegged_demand.supply.push(Supply {
-                due_date: remaining_supplies[0].due_date,
-                job_num: remaining_supplies[0].job_num.clone(),
-                sourcefile: remaining_supplies[0].sourcefile.clone(),
-                asm: remaining_supplies[0].asm,
-                mtl: remaining_supplies[0].mtl,
-                pegged_qty: supply_used_quantity,
-                po_num: remaining_supplies[0].po_num,
-                po_line: remaining_supplies[0].po_line,
-                po_rel: remaining_supplies[0].po_rel,
-            });
-
-            // Subtract any used quantity from the supply
-            demand_quantity_remaining -= supply_used_quantity;
-
-            if remaining_supplies[0].qty > supply_used_quantity {
-                let new_qty = remaining_supplies[0]
-                    .qty
-                    .checked_sub(supply_used_quantity)
-                    .unwrap();
-
-                remaining_supplies[0].qty = new_qty;
-            } else {
-                remaining_supplies.remove(0);
-            }
-        }
+    let options = match config.peg_options(&query) {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
 
-        intermediate_pegging.push(pegged_demand);
-    }
+    // Re-pegging with options the snapshot has not cached yet is CPU bound, so keep it off
+    // the async workers
+    let what_if_snapshot = snapshot.clone();
+    let today = Local::now().date_naive();
+    let result = web::block(move || {
+        simulate(&what_if_snapshot, &request.overrides, &options, today)
+    })
+    .await;
 
-    let result: Vec<Demand> = intermediate_pegging.into_iter().collect();
-    result
+    match result {
+        Ok(Ok(result)) => snapshot_response(&snapshot, &result),
+        Ok(Err(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error pegging data: {}", e)),
+    }
 }
 
-fn multi_peg_part_dtl(
-    part_dtl: &Vec<SQLReturnRow>,
-    on_hand: &Vec<OnHand>,
-    part_num: &str,
-) -> Vec<Demand> {
-    let filtered_parts: Vec<&SQLReturnRow> = part_dtl
-        .into_iter()
-        .filter(|part| &part.part_num == part_num)
-        .collect();
-    //println!("Part: {:#?}", filtered_parts);
-
-    let filtered_on_hand: Vec<&OnHand> = on_hand
-        .into_iter()
-        .filter(|row| &row.part_num == part_num)
-        .collect();
-
-    let mut intermediate_pegging: Vec<Demand> = Vec::new();
+#[get("job/{job_num}")]
+async fn job(
+    source: web::Data<dyn PlanningDataSource>,
+    snapshots: web::Data<SnapshotStore>,
+    config: web::Data<PlanningConfig>,
+    query: web::Query<PlanningQuery>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let job_num: String = path.into_inner();
 
-    let mut remaining_supplies: Vec<SQLReturnRow> = vec![];
+    let snapshot = match scoped_snapshot(source.get_ref(), &snapshots, &config, &query).await {
+        Ok(snapshot) => snapshot,
+        Err(response) => return response,
+    };
 
-    // Add remaining supplies from on hand quantity
-    filtered_on_hand.iter().for_each(|row| {
-        let new_oh = SQLReturnRow::new_on_hand(&row.part_num, row.qty);
-        remaining_supplies.push(new_oh);
-    });
+    let pegging = match snapshot_pegging(&snapshot, &config, &query).await {
+        Ok(pegging) => pegging,
+        Err(response) => return response,
+    };
 
-    let part_dtl_supplies: Vec<&&SQLReturnRow> = filtered_parts
-        .iter()
-        .filter(|a| a.requirement == false)
-        .collect();
+    let job_bom = peg_job_materials(&snapshot, &pegging, &[job_num]);
 
-    for row in part_dtl_supplies {
-        remaining_supplies.push(row.to_owned().to_owned())
-    }
+    snapshot_response(&snapshot, &job_bom)
+}
 
-    let mut sorted_demands: Vec<&&SQLReturnRow> = filtered_parts
+/// Gets the BOMs of the jobs with the pegged demand for each of their open materials
+fn peg_job_materials(snapshot: &Snapshot, pegging: &Pegging, job_numbers: &[String]) -> Vec<JobMtl> {
+    let mut job_bom: Vec<JobMtl> = job_numbers
         .iter()
-        .filter(|a| a.requirement == true)
+        .filter_map(|job_num| snapshot.job_boms.get(job_num))
+        .flatten()
+        .cloned()
         .collect();
 
-    sorted_demands.sort_by(|a, b| a.due_date.cmp(&b.due_date));
-
-    for demand in sorted_demands.iter() {
-        let mut pegged_demand = Demand {
-            part_number: demand.part_num.to_owned(),
-            due_date: demand.due_date,
-            sourcefile: demand.sourcefile.to_owned(),
-            demand_qty: demand.qty,
-            job_num: demand.job_num.to_owned(),
-            asm: demand.asm,
-            mtl: demand.mtl,
-            order: demand.order,
-            order_line: demand.order_line,
-            order_rel: demand.order_rel,
-            supply: vec![],
-            pegged_demand: dec!(0.0),
-        };
-
-        let mut demand_quantity_remaining = pegged_demand.demand_qty;
-
-        // While the remaining demand quantity is greater than zero and there is still open supply
-        while demand_quantity_remaining > dec!(0.0) && !remaining_supplies.is_empty() {
-            // Calculate the quantity to be used. This should be equal to either the remaining
-            // demand quantity if it is min, or the remaining supply quantity if it is min
-            let supply_used_quantity =
-                Decimal::min(remaining_supplies[0].qty, demand_quantity_remaining);
-
-            pegged_demand.pegged_demand += supply_used_quantity;
-
-            pegged_demand.supply.push(Supply {
-                due_date: remaining_supplies[0].due_date,
-                job_num: remaining_supplies[0].job_num.clone(),
-                sourcefile: remaining_supplies[0].sourcefile.clone(),
-                asm: remaining_supplies[0].asm,
-                mtl: remaining_supplies[0].mtl,
-                pegged_qty: supply_used_quantity,
-                po_num: remaining_supplies[0].po_num,
-                po_line: remaining_supplies[0].po_line,
-                po_rel: remaining_supplies[0].po_rel,
-            });
-
-            // Subtract any used quantity from the supply
-            demand_quantity_remaining -= supply_used_quantity;
-
-            if remaining_supplies[0].qty > supply_used_quantity {
-                let new_qty = remaining_supplies[0]
-                    .qty
-                    .checked_sub(supply_used_quantity)
-                    .unwrap();
-
-                remaining_supplies[0].qty = new_qty;
-            } else {
-                remaining_supplies.remove(0);
+    for job_mtl in &mut job_bom {
+        if job_mtl.issued_qty >= job_mtl.req_qty {
+            continue;
+        } else if let Some(pegged_demand) = pegging.demand.get(&job_mtl.part_num) {
+            // Make direct supply is pegged to its target material by the engine
+            for demand_row in pegged_demand {
+                if demand_row.job_num == job_mtl.job_num
+                    && demand_row.asm == job_mtl.asm
+                    && demand_row.mtl == job_mtl.mtl
+                {
+                    job_mtl.demand.push(demand_row.clone())
+                }
             }
         }
-
-        intermediate_pegging.push(pegged_demand);
     }
 
-    //let result: Vec<Demand> = intermediate_pegging.into_iter().collect();
-    intermediate_pegging
-    //result
+    job_bom
+}
+
+/// Pegs an order release, promising whatever its pegged supply falls short of by the CTP of
+/// the part in the release's plant
+fn peg_order_release(
+    snapshot: &Snapshot,
+    pegging: &Pegging,
+    order_release: &mut OrderRelease,
+    demand: Vec<Demand>,
+    today: NaiveDate,
+) {
+    let part_num = order_release.part_number.to_owned();
+    let plant = order_release.plant.to_owned();
+
+    order_release.peg(demand, today, |qty| {
+        get_part_ctp(
+            pegging,
+            &snapshot.on_hand,
+            &snapshot.boms,
+            &snapshot.part_plants,
+            &part_num,
+            &CtpQuery { qty, date: None },
+            today,
+        )
+        .into_iter()
+        .find(|ctp| ctp.plant == plant)
+        .map(|ctp| ctp.promise_date)
+    });
 }
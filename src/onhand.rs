@@ -1,34 +1,33 @@
-use async_std::net::TcpStream;
-
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use serde::Serialize;
-use std::io::{Error, ErrorKind};
-use tiberius::{Client, Query};
+use serde::{Deserialize, Serialize};
+use tiberius::Query;
 
-use crate::sql::get_sql_config;
+use crate::{
+    config::PlanningScope,
+    sql::{parameter_list, SqlPool},
+};
 
 #[allow(dead_code)]
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Hash)]
 pub struct OnHand {
     pub part_num: String,
     pub site: String,
     pub qty: Decimal,
+    /// The part's inventory UOM, which on hand is always kept in
+    #[serde(default)]
+    pub uom: String,
 }
 
-pub async fn get_parts_on_hand() -> Result<Vec<OnHand>, anyhow::Error> {
-    let config = get_sql_config();
-
-    // Create TCP TcpStream
-    let tcp = TcpStream::connect(&config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    // Connect to server
-    let mut client = Client::connect(config, tcp).await?;
+pub async fn get_parts_on_hand(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+) -> Result<Vec<OnHand>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
 
     // Construct Query
-    let select = Query::new(
-        "
+    let mut query_string = "
         select 
 	        [PartWhse].[PartNum] as [PartWhse_PartNum],
 	        [Warehse].[Plant] as [Warehse_Plant],
@@ -54,14 +53,42 @@ pub async fn get_parts_on_hand() -> Result<Vec<OnHand>, anyhow::Error> {
 	        and PartBin.BinNum = WhseBin.BinNum
 	        and ( WhseBin.NonNettable = 0  )
 
-        where (not Warehse.Plant like 'CONS%' and Warehse.Company = 'AE')
+        where Warehse.Company = @P1
+            "
+    .to_string();
+
+    let mut bound = 1;
+    query_string.push_str(&format!(
+        "and Warehse.Plant IN ({})\n",
+        parameter_list(bound, scope.plants.len())
+    ));
+    bound += scope.plants.len();
+
+    scope
+        .excluded_warehouse_plants
+        .iter()
+        .enumerate()
+        .for_each(|(i, _)| {
+            query_string.push_str(&format!("and not Warehse.Plant like @P{}\n", bound + i + 1))
+        });
 
+    query_string.push_str(
+        "
         group by 
             [PartWhse].[PartNum],
 	        [Warehse].[Plant]
             ",
     );
 
+    let mut select = Query::new(query_string);
+
+    select.bind(scope.company.to_owned());
+    scope.plants.iter().for_each(|plant| select.bind(plant.to_owned()));
+    scope
+        .excluded_warehouse_plants
+        .iter()
+        .for_each(|pattern| select.bind(pattern.to_owned()));
+
     let mut result: Vec<OnHand> = vec![];
 
     // Stream Query
@@ -82,18 +109,13 @@ pub async fn get_parts_on_hand() -> Result<Vec<OnHand>, anyhow::Error> {
             part_num,
             site,
             qty,
+            uom: "".to_owned(),
         });
     });
 
     // println!("{:?}", rows);
 
-    // Close Client Connection
-    client
-        .close()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
-
     // Result set should be cached now
 
-    return Ok(result);
+    Ok(result)
 }
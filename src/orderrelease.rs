@@ -1,13 +1,247 @@
-use serde::Serialize;
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
 
 use crate::parttimephase::Demand;
 
+/// How much of an order release is covered by the pegged supply
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Coverage {
+    FullyCovered,
+    PartiallyCovered,
+    #[default]
+    Uncovered,
+}
+
 #[allow(dead_code)]
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OrderRelease {
     pub order: Option<i32>,
     pub line: Option<i32>,
     pub release: Option<i32>,
+    pub plant: String,
     pub part_number: String,
+    pub customer: Option<String>,
+    pub due_date: Option<NaiveDate>,
+    #[serde(skip_deserializing)]
+    pub coverage: Coverage,
+    #[serde(skip_deserializing)]
+    pub satisfied_date: Option<NaiveDate>,
+    #[serde(skip_deserializing)]
     pub demand: Vec<Demand>,
 }
+
+impl OrderRelease {
+    /// Attaches the pegged demand for this release and works out its coverage.
+    ///
+    /// A release can be satisfied once its pegged supply has all arrived. On hand supply is
+    /// available `today`. When the pegged supply falls short, `cover_shortfall` gives the date
+    /// the rest can be promised, normally its CTP date, and `None` when it never can be.
+    pub fn peg(
+        &mut self,
+        demand: Vec<Demand>,
+        today: NaiveDate,
+        cover_shortfall: impl FnOnce(Decimal) -> Option<NaiveDate>,
+    ) {
+        let demand_qty: Decimal = demand.iter().map(|dmd| dmd.demand_qty).sum();
+        let pegged_qty: Decimal = demand.iter().map(|dmd| dmd.pegged_demand).sum();
+
+        self.coverage = if pegged_qty <= dec!(0.0) {
+            Coverage::Uncovered
+        } else if pegged_qty < demand_qty {
+            Coverage::PartiallyCovered
+        } else {
+            Coverage::FullyCovered
+        };
+
+        let pegged_date = demand
+            .iter()
+            .flat_map(|dmd| dmd.supply.iter())
+            .map(|supply| {
+                if supply.sourcefile == "OH" {
+                    today
+                } else {
+                    NaiveDate::max(supply.due_date, today)
+                }
+            })
+            .max();
+
+        self.satisfied_date = match self.coverage {
+            Coverage::FullyCovered => pegged_date,
+            _ => cover_shortfall(demand_qty - pegged_qty)
+                .map(|date| pegged_date.map_or(date, |pegged| NaiveDate::max(pegged, date))),
+        };
+
+        self.demand = demand;
+    }
+
+    /// Parses an `{order}-{line}-{rel}` key, e.g. `12345-1-1`
+    pub fn parse_key(key: &str) -> Option<(i32, i32, i32)> {
+        let mut spl = key.split('-');
+        let order = spl.next()?.trim().parse::<i32>().ok()?;
+        let line = spl.next()?.trim().parse::<i32>().ok()?;
+        let release = spl.next()?.trim().parse::<i32>().ok()?;
+
+        match spl.next() {
+            Some(_) => None,
+            None => Some((order, line, release)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parttimephase::Supply;
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, month, day).unwrap()
+    }
+
+    fn release() -> OrderRelease {
+        OrderRelease {
+            order: Some(12345),
+            line: Some(1),
+            release: Some(1),
+            plant: "MfgSys".to_owned(),
+            part_number: "FG".to_owned(),
+            customer: Some("ACME".to_owned()),
+            due_date: Some(date(3, 1)),
+            coverage: Coverage::Uncovered,
+            satisfied_date: None,
+            demand: vec![],
+        }
+    }
+
+    fn supply(sourcefile: &str, due_date: NaiveDate, pegged_qty: Decimal) -> Supply {
+        Supply {
+            due_date,
+            plant: "MfgSys".to_owned(),
+            sourcefile: sourcefile.to_owned(),
+            pegged_qty,
+            days_late: 0,
+            job_num: "".to_owned(),
+            asm: 0,
+            mtl: 0,
+            po_num: None,
+            po_line: None,
+            po_rel: None,
+            substitute_part: None,
+            conversion_factor: None,
+            uom: "".to_owned(),
+            uom_qty: pegged_qty,
+            bom: vec![],
+        }
+    }
+
+    fn demand(demand_qty: Decimal, supply: Vec<Supply>) -> Demand {
+        Demand {
+            part_number: "FG".to_owned(),
+            plant: "MfgSys".to_owned(),
+            due_date: date(3, 1),
+            sourcefile: "OR".to_owned(),
+            demand_qty,
+            job_num: "".to_owned(),
+            asm: 0,
+            mtl: 0,
+            order: 12345,
+            order_line: 1,
+            order_rel: 1,
+            pegged_demand: supply.iter().map(|s| s.pegged_qty).sum(),
+            supply,
+        }
+    }
+
+    #[test]
+    fn fully_covered_release_is_satisfied_by_its_latest_supply() {
+        let mut order_release = release();
+        order_release.peg(
+            vec![demand(
+                dec!(10),
+                vec![
+                    supply("OH", date(1, 1), dec!(4)),
+                    supply("PO", date(2, 15), dec!(6)),
+                ],
+            )],
+            date(1, 10),
+            |_| None,
+        );
+
+        assert_eq!(order_release.coverage, Coverage::FullyCovered);
+        assert_eq!(order_release.satisfied_date, Some(date(2, 15)));
+    }
+
+    #[test]
+    fn on_hand_is_available_today() {
+        let mut order_release = release();
+        order_release.peg(
+            vec![demand(dec!(10), vec![supply("OH", date(1, 1), dec!(10))])],
+            date(1, 10),
+            |_| None,
+        );
+
+        assert_eq!(order_release.satisfied_date, Some(date(1, 10)));
+    }
+
+    #[test]
+    fn short_release_is_satisfied_once_the_shortfall_can_be_promised() {
+        let mut order_release = release();
+        order_release.peg(
+            vec![demand(dec!(10), vec![supply("PO", date(2, 15), dec!(6))])],
+            date(1, 10),
+            |shortfall| {
+                assert_eq!(shortfall, dec!(4));
+                Some(date(2, 20))
+            },
+        );
+
+        assert_eq!(order_release.coverage, Coverage::PartiallyCovered);
+        assert_eq!(order_release.satisfied_date, Some(date(2, 20)));
+
+        // A shortfall promised before the pegged supply arrives waits for that supply
+        let mut order_release = release();
+        order_release.peg(
+            vec![demand(dec!(10), vec![supply("PO", date(2, 15), dec!(6))])],
+            date(1, 10),
+            |_| Some(date(1, 31)),
+        );
+
+        assert_eq!(order_release.satisfied_date, Some(date(2, 15)));
+    }
+
+    #[test]
+    fn short_release_is_never_satisfied_when_the_shortfall_cannot_be_promised() {
+        let mut order_release = release();
+        order_release.peg(
+            vec![demand(dec!(10), vec![supply("PO", date(2, 15), dec!(6))])],
+            date(1, 10),
+            |_| None,
+        );
+
+        assert_eq!(order_release.coverage, Coverage::PartiallyCovered);
+        assert_eq!(order_release.satisfied_date, None);
+
+        let mut order_release = release();
+        order_release.peg(vec![demand(dec!(10), vec![])], date(1, 10), |_| None);
+
+        assert_eq!(order_release.coverage, Coverage::Uncovered);
+        assert_eq!(order_release.satisfied_date, None);
+    }
+
+    #[test]
+    fn parses_order_line_release() {
+        assert_eq!(OrderRelease::parse_key("12345-1-1"), Some((12345, 1, 1)));
+        assert_eq!(OrderRelease::parse_key("12345-10-2"), Some((12345, 10, 2)));
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert_eq!(OrderRelease::parse_key("12345"), None);
+        assert_eq!(OrderRelease::parse_key("12345-1"), None);
+        assert_eq!(OrderRelease::parse_key("12345-1-1-1"), None);
+        assert_eq!(OrderRelease::parse_key("abc-1-1"), None);
+        assert_eq!(OrderRelease::parse_key("12345--1"), None);
+    }
+}
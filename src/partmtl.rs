@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use tiberius::Query;
+
+use crate::{config::PlanningScope, sql::SqlPool};
+
+/// A material on the method of manufacture of a part. `qty_per` is how many of the material
+/// make one of the part, in the material's inventory UOM once normalized.
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PartMtl {
+    pub part_num: String,
+    pub mtl_part_num: String,
+    #[serde(default = "default_qty_per")]
+    pub qty_per: Decimal,
+    /// The UOM `qty_per` is in
+    #[serde(default)]
+    pub uom: String,
+}
+
+fn default_qty_per() -> Decimal {
+    dec!(1)
+}
+
+/// Materials keyed by the part they make, in material sequence
+pub type Boms = HashMap<String, Vec<PartMtl>>;
+
+/// Groups the materials by the part they make, keeping their original order and dropping
+/// any that take nothing
+pub fn group_part_mtls(part_mtls: &[PartMtl]) -> Boms {
+    let mut boms: Boms = HashMap::new();
+
+    for part_mtl in part_mtls
+        .iter()
+        .filter(|part_mtl| part_mtl.qty_per > dec!(0))
+    {
+        boms.entry(part_mtl.part_num.to_owned())
+            .or_default()
+            .push(part_mtl.to_owned());
+    }
+
+    boms
+}
+
+/// Gets the materials of the latest effective, approved revision of every part
+pub async fn get_part_mtls(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+) -> Result<Vec<PartMtl>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
+
+    // Construct Query
+    let mut select = Query::new(
+        "
+            SELECT
+                PM.PartNum,
+                PM.MtlPartNum,
+                PM.QtyPer,
+                PM.UOMCode
+            FROM
+                Erp.PartMtl as PM
+            INNER JOIN Erp.PartRev as PR on
+                PR.Company = PM.Company
+                and PR.PartNum = PM.PartNum
+                and PR.RevisionNum = PM.RevisionNum
+                and PR.AltMethod = PM.AltMethod
+            WHERE
+                PM.Company = @P1
+                and PR.Approved = 1
+                and PR.AltMethod = ''
+                and PR.EffectiveDate = (
+                    SELECT max(LR.EffectiveDate)
+                    FROM Erp.PartRev as LR
+                    WHERE
+                        LR.Company = PR.Company
+                        and LR.PartNum = PR.PartNum
+                        and LR.Approved = 1
+                        and LR.AltMethod = ''
+                        and LR.EffectiveDate <= GETDATE()
+                )
+            ORDER BY
+                PM.PartNum,
+                PM.MtlSeq
+            ",
+    );
+
+    select.bind(scope.company.to_owned());
+
+    // Stream Query
+    let stream = select.query(&mut client).await?;
+
+    // Consume stream
+    let row = stream.into_first_result().await?;
+
+    let result: Vec<PartMtl> = row
+        .iter()
+        .map(|val| PartMtl {
+            part_num: val.get("PartNum").unwrap_or("").to_owned(),
+            mtl_part_num: val.get("MtlPartNum").unwrap_or("").to_owned(),
+            qty_per: val.get::<Decimal, _>("QtyPer").unwrap_or(dec!(1)),
+            uom: val.get("UOMCode").unwrap_or("").to_owned(),
+        })
+        .collect();
+
+    Ok(result)
+}
@@ -0,0 +1,83 @@
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use tiberius::Query;
+
+use crate::{
+    config::PlanningScope,
+    sql::{parameter_list, SqlPool},
+};
+
+/// Site level planning details of a part
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PartPlant {
+    pub part_num: String,
+    pub plant: String,
+    #[serde(default)]
+    pub buyer: String,
+    #[serde(default)]
+    pub planner: String,
+    #[serde(default)]
+    pub safety_qty: Decimal,
+    /// Days to buy the part
+    #[serde(default)]
+    pub lead_time: i32,
+    /// Days to make the part once its materials are in
+    #[serde(default)]
+    pub mfg_lead_time: i32,
+}
+
+pub async fn get_part_plants(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+) -> Result<Vec<PartPlant>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
+
+    // Construct Query
+    let query_string = format!(
+        "
+            SELECT
+                PP.PartNum,
+                PP.Plant,
+                PP.BuyerID,
+                PP.PersonID,
+                PP.SafetyQty,
+                PP.LeadTime,
+                PP.TotMfgLeadTimeSys
+            FROM 
+                Erp.PartPlant as PP
+            WHERE 
+                PP.Company = @P1
+                and PP.Plant IN ({})
+            ",
+        parameter_list(1, scope.plants.len())
+    );
+
+    let mut select = Query::new(query_string);
+
+    select.bind(scope.company.to_owned());
+    scope.plants.iter().for_each(|plant| select.bind(plant.to_owned()));
+
+    // Stream Query
+    let stream = select.query(&mut client).await?;
+
+    // Consume stream
+    let row = stream.into_first_result().await?;
+
+    let result: Vec<PartPlant> = row
+        .iter()
+        .map(|val| PartPlant {
+            part_num: val.get("PartNum").unwrap_or("").to_owned(),
+            plant: val.get("Plant").unwrap_or("").to_owned(),
+            buyer: val.get("BuyerID").unwrap_or("").to_owned(),
+            planner: val.get("PersonID").unwrap_or("").to_owned(),
+            safety_qty: val.get::<Decimal, _>("SafetyQty").unwrap_or(dec!(0.0)),
+            lead_time: val.get::<i32, _>("LeadTime").unwrap_or(0),
+            mfg_lead_time: val.get::<i32, _>("TotMfgLeadTimeSys").unwrap_or(0),
+        })
+        .collect();
+
+    Ok(result)
+}
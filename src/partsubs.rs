@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use tiberius::Query;
+
+use crate::{config::PlanningScope, sql::SqlPool};
+
+/// A part that may stand in for another. `qty_per` is how many of the substitute replace
+/// one of the part.
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PartSub {
+    pub part_num: String,
+    pub sub_part: String,
+    #[serde(default = "default_qty_per")]
+    pub qty_per: Decimal,
+}
+
+fn default_qty_per() -> Decimal {
+    dec!(1.0)
+}
+
+/// Substitutes keyed by the part they stand in for, in the order they are tried
+pub type PartSubstitutes = HashMap<String, Vec<PartSub>>;
+
+/// Groups the substitutes by the part they stand in for, keeping their original order and
+/// dropping any without a usable conversion factor
+pub fn group_part_subs(part_subs: &[PartSub]) -> PartSubstitutes {
+    let mut substitutes: PartSubstitutes = HashMap::new();
+
+    for sub in part_subs.iter().filter(|sub| sub.qty_per > dec!(0.0)) {
+        substitutes
+            .entry(sub.part_num.to_owned())
+            .or_default()
+            .push(sub.to_owned());
+    }
+
+    substitutes
+}
+
+pub async fn get_part_subs(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+) -> Result<Vec<PartSub>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
+
+    // Construct Query
+    let mut select = Query::new(
+        "
+            SELECT
+                PS.PartNum,
+                PS.SubPart,
+                PS.QtyPer
+            FROM
+                Erp.PartSubs as PS
+            WHERE
+                PS.Company = @P1
+            ORDER BY
+                PS.PartNum,
+                PS.SubPart
+            ",
+    );
+
+    select.bind(scope.company.to_owned());
+
+    // Stream Query
+    let stream = select.query(&mut client).await?;
+
+    // Consume stream
+    let row = stream.into_first_result().await?;
+
+    let result: Vec<PartSub> = row
+        .iter()
+        .map(|val| PartSub {
+            part_num: val.get("PartNum").unwrap_or("").to_owned(),
+            sub_part: val.get("SubPart").unwrap_or("").to_owned(),
+            qty_per: val.get::<Decimal, _>("QtyPer").unwrap_or(dec!(1.0)),
+        })
+        .collect();
+
+    Ok(result)
+}
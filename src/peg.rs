@@ -1,8 +1,1169 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::NaiveDate;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use serde::Serialize;
 
 use crate::{
+    directlinks::DirectLinks,
     onhand::OnHand,
-    parttimephase::{Demand, PartDtl, Supply},
+    partsubs::{PartSub, PartSubstitutes},
+    parttimephase::{Demand, Supply},
     sql::SQLReturnRow,
+    uom::to_loaded_uom,
 };
+
+/// Every part number in the rows, in the order each is first seen
+pub fn get_unique_part_numbers(part_dtls: &[SQLReturnRow]) -> Vec<String> {
+    let mut seen: HashSet<&str> = HashSet::new();
+    part_dtls
+        .iter()
+        .filter(|row| seen.insert(&row.part_num))
+        .map(|row| row.part_num.to_owned())
+        .collect()
+}
+
+/// Everything pegging reads for a single part, in the order it was loaded
+#[derive(Debug, Clone, Default)]
+pub struct PartInput<'a> {
+    pub part_dtl: Vec<&'a SQLReturnRow>,
+    pub on_hand: Vec<&'a OnHand>,
+}
+
+/// Pegging input keyed by part number
+pub type PartInputs<'a> = HashMap<String, PartInput<'a>>;
+
+/// Groups the PartDtl rows and on hand by part in a single pass over each, so pegging a
+/// part does not have to search every row for its own
+pub fn group_part_inputs<'a>(
+    part_dtl: &'a [SQLReturnRow],
+    on_hand: &'a [OnHand],
+) -> PartInputs<'a> {
+    let mut inputs: PartInputs = HashMap::new();
+
+    // PartDtl comes back ordered by part, so each part's rows are taken as one run
+    for rows in part_dtl.chunk_by(|a, b| a.part_num == b.part_num) {
+        inputs
+            .entry(rows[0].part_num.to_owned())
+            .or_default()
+            .part_dtl
+            .extend(rows);
+    }
+    for row in on_hand {
+        inputs
+            .entry(row.part_num.to_owned())
+            .or_default()
+            .on_hand
+            .push(row);
+    }
+
+    inputs
+}
+
+/// Pegged demand and left over supply of every part, keyed by part number
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct Pegging {
+    pub demand: HashMap<String, Vec<Demand>>,
+    pub excess: HashMap<String, Vec<ExcessSupply>>,
+}
+
+/// Pegged demand and left over supply of a single part
+#[derive(Debug, Clone, Default)]
+pub struct PartPegging {
+    pub demand: Vec<Demand>,
+    pub excess: Vec<ExcessSupply>,
+}
+
+/// Supply that is still unpegged once every demand for its part has been pegged
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct ExcessSupply {
+    pub part_number: String,
+    pub plant: String,
+    pub due_date: NaiveDate,
+    pub sourcefile: String,
+    pub qty: Decimal,
+    pub job_num: String,
+    pub asm: i32,
+    pub mtl: i32,
+    pub po_num: Option<i32>,
+    pub po_line: Option<i32>,
+    pub po_rel: Option<i32>,
+    /// The UOM the supply was loaded in, along with the left over quantity in it
+    pub uom: String,
+    pub uom_qty: Decimal,
+}
+
+impl From<&SQLReturnRow> for ExcessSupply {
+    fn from(row: &SQLReturnRow) -> Self {
+        ExcessSupply {
+            part_number: row.part_num.to_owned(),
+            plant: row.plant.to_owned(),
+            due_date: row.due_date,
+            sourcefile: row.sourcefile.to_owned(),
+            qty: row.qty,
+            job_num: row.job_num.to_owned(),
+            asm: row.asm,
+            mtl: row.mtl,
+            po_num: row.po_num,
+            po_line: row.po_line,
+            po_rel: row.po_rel,
+            uom: row.uom.to_owned(),
+            uom_qty: to_loaded_uom(row.qty, row.uom_factor),
+        }
+    }
+}
+
+/// The plant, target job, assembly and material that make direct supply is held for
+type DirectKey = (String, String, i32, i32);
+
+/// Safety stock of each part in each plant, keyed by part and plant
+pub type SafetyStock = HashMap<(String, String), Decimal>;
+
+/// A rule deciding which of two demands is pegged first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityRule {
+    /// The earlier due date first
+    DueDate,
+    /// Sales orders, then job material, then anything else and forecasts last
+    SourceFile,
+    /// Firm demand before unfirm
+    Firm,
+    /// Sales orders in the order of the customer priority list, then everything else
+    Customer,
+}
+
+impl PriorityRule {
+    pub fn parse(rule: &str) -> Option<PriorityRule> {
+        match rule.trim().to_ascii_lowercase().as_str() {
+            "due_date" => Some(PriorityRule::DueDate),
+            "source" => Some(PriorityRule::SourceFile),
+            "firm" => Some(PriorityRule::Firm),
+            "customer" => Some(PriorityRule::Customer),
+            _ => None,
+        }
+    }
+}
+
+/// How a demand picks which supply to take next
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SupplyStrategy {
+    /// On hand and then supply in due date order
+    #[default]
+    EarliestDue,
+    /// Supply in due date order, only using on hand once that has run out
+    OnHandLast,
+    /// On hand, then jobs and then everything else, each in due date order
+    PreferJob,
+    /// On hand and then the supply due closest to the demand, earlier winning a tie
+    ClosestDate,
+    /// On hand and then supply in due date order, never taking supply due after the demand
+    NoLateSupply,
+}
+
+impl SupplyStrategy {
+    pub fn parse(strategy: &str) -> Option<SupplyStrategy> {
+        match strategy.trim().to_ascii_lowercase().as_str() {
+            "earliest_due" => Some(SupplyStrategy::EarliestDue),
+            "on_hand_last" => Some(SupplyStrategy::OnHandLast),
+            "prefer_job" => Some(SupplyStrategy::PreferJob),
+            "closest_date" => Some(SupplyStrategy::ClosestDate),
+            "no_late_supply" => Some(SupplyStrategy::NoLateSupply),
+            _ => None,
+        }
+    }
+
+    /// Orders a plant's supply before any demand is pegged to it
+    fn sort(&self, supplies: &mut [SQLReturnRow]) {
+        match self {
+            SupplyStrategy::OnHandLast => {
+                supplies.sort_by_key(|supply| (supply.sourcefile == "OH", supply.due_date))
+            }
+            SupplyStrategy::PreferJob => supplies.sort_by_key(|supply| {
+                let rank = match supply.sourcefile.as_str() {
+                    "OH" => 0,
+                    "JH" => 1,
+                    _ => 2,
+                };
+                (rank, supply.due_date)
+            }),
+            // On hand is dated well in the past, so it sorts first
+            _ => supplies.sort_by_key(|supply| supply.due_date),
+        }
+    }
+
+    /// The index of the supply the demand takes next, if it may take any
+    fn pick(&self, demand: &Demand, supplies: &[SQLReturnRow]) -> Option<usize> {
+        match self {
+            SupplyStrategy::ClosestDate => supplies
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, supply)| {
+                    (
+                        supply.sourcefile != "OH",
+                        (supply.due_date - demand.due_date).num_days().abs(),
+                        supply.due_date,
+                    )
+                })
+                .map(|(index, _)| index),
+            SupplyStrategy::NoLateSupply => supplies
+                .iter()
+                .position(|supply| supply.due_date <= demand.due_date),
+            _ => match supplies.is_empty() {
+                true => None,
+                false => Some(0),
+            },
+        }
+    }
+}
+
+/// How the pegging engine may use supply
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PegOptions {
+    /// Let demand that is still short once its own plant's supply is used up take whatever
+    /// supply other plants have left over, as a transfer between plants
+    pub allow_transfers: bool,
+    /// Hold safety stock back out of on hand, and out of the earliest receipts when on hand
+    /// is short of it, until every other supply has been used. Safety stock that ends up
+    /// pegged shows as "SS" supply.
+    pub reserve_safety_stock: bool,
+    /// Rules applied in turn to decide which demand is pegged first. The earlier due date
+    /// always settles whatever the rules leave tied.
+    pub priority: Vec<PriorityRule>,
+    /// Customer IDs, the most important first, for the customer rule
+    pub customer_priority: Vec<String>,
+    pub supply_strategy: SupplyStrategy,
+    /// Cover demand still short once every part is pegged with the left over supply of the
+    /// part's substitutes
+    pub use_substitutes: bool,
+}
+
+/// A transfer order needed to move pegged supply to the plant of the demand it covers
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct TransferSuggestion {
+    pub part_number: String,
+    pub from_plant: String,
+    pub to_plant: String,
+    pub qty: Decimal,
+    pub need_date: NaiveDate,
+    pub supply_due_date: NaiveDate,
+    pub sourcefile: String,
+    pub job_num: String,
+    pub po_num: Option<i32>,
+    pub po_line: Option<i32>,
+    pub po_rel: Option<i32>,
+    pub demand_sourcefile: String,
+    pub demand_job_num: String,
+    pub order: i32,
+    pub order_line: i32,
+    pub order_rel: i32,
+}
+
+pub fn multi_peg_part_dtl(
+    input: &PartInput,
+    safety_stock: &SafetyStock,
+    direct_links: &DirectLinks,
+    part_num: &str,
+    options: &PegOptions,
+) -> PartPegging {
+    let filtered_parts = &input.part_dtl;
+    let filtered_on_hand = &input.on_hand;
+
+    // Supply is pooled by plant, on hand first and then PartDtl supply in query order
+    let mut remaining_supplies: BTreeMap<String, Vec<SQLReturnRow>> = BTreeMap::new();
+
+    // Add remaining supplies from on hand quantity
+    filtered_on_hand.iter().for_each(|row| {
+        let new_oh = SQLReturnRow {
+            uom: row.uom.to_owned(),
+            ..SQLReturnRow::new_on_hand(&row.part_num, &row.site, row.qty)
+        };
+        remaining_supplies
+            .entry(row.site.to_owned())
+            .or_default()
+            .push(new_oh);
+    });
+
+    filtered_parts
+        .iter()
+        .filter(|a| !a.requirement)
+        .for_each(|row| {
+            remaining_supplies
+                .entry(row.plant.to_owned())
+                .or_default()
+                .push(row.to_owned().to_owned())
+        });
+
+    // Job supply made direct for another job's material is held for that material alone,
+    // keyed by the plant, target job, assembly and material. Anything the job makes beyond
+    // its links stays in the plant's supply.
+    let mut direct_supplies: HashMap<DirectKey, Vec<SQLReturnRow>> = HashMap::new();
+    for plant_supplies in remaining_supplies.values_mut() {
+        for row in plant_supplies.iter_mut().filter(|row| row.sourcefile == "JH") {
+            let links = match direct_links.get(&row.job_num) {
+                Some(links) => links,
+                None => continue,
+            };
+
+            for link in links.iter().filter(|link| link.plant == row.plant) {
+                let linked_qty = Decimal::min(row.qty, link.prod_qty);
+                if linked_qty > dec!(0.0) {
+                    row.qty -= linked_qty;
+                    direct_supplies
+                        .entry((
+                            row.plant.to_owned(),
+                            link.target_job_num.to_owned(),
+                            link.target_asm,
+                            link.target_mtl,
+                        ))
+                        .or_default()
+                        .push(SQLReturnRow {
+                            qty: linked_qty,
+                            ..row.clone()
+                        });
+                }
+            }
+        }
+        plant_supplies.retain(|row| row.qty > dec!(0.0));
+    }
+
+    let mut sorted_demands: Vec<&&SQLReturnRow> = filtered_parts
+        .iter()
+        .filter(|a| a.requirement)
+        .collect();
+
+    sorted_demands.sort_by(|a, b| compare_demand(a, b, options));
+
+    let mut intermediate_pegging: Vec<Demand> = sorted_demands
+        .iter()
+        .map(|demand| Demand {
+            part_number: demand.part_num.to_owned(),
+            plant: demand.plant.to_owned(),
+            due_date: demand.due_date,
+            sourcefile: demand.sourcefile.to_owned(),
+            demand_qty: demand.qty,
+            job_num: demand.job_num.to_owned(),
+            asm: demand.asm,
+            mtl: demand.mtl,
+            order: demand.order,
+            order_line: demand.order_line,
+            order_rel: demand.order_rel,
+            supply: vec![],
+            pegged_demand: dec!(0.0),
+        })
+        .collect();
+
+    // Job material takes the supply made direct for it before anything else
+    for pegged_demand in intermediate_pegging.iter_mut() {
+        let key = (
+            pegged_demand.plant.to_owned(),
+            pegged_demand.job_num.to_owned(),
+            pegged_demand.asm,
+            pegged_demand.mtl,
+        );
+        if let Some(linked_supplies) = direct_supplies.get_mut(&key) {
+            peg_demand(pegged_demand, linked_supplies, SupplyStrategy::EarliestDue);
+        }
+    }
+
+    // Direct supply its target material doesn't use, or whose target material isn't being
+    // pegged, goes back into its plant's supply
+    for row in direct_supplies.into_values().flatten() {
+        let plant_supplies = remaining_supplies.entry(row.plant.to_owned()).or_default();
+        match plant_supplies
+            .iter_mut()
+            .find(|supply| supply.sourcefile == "JH" && supply.id == row.id)
+        {
+            Some(supply) => supply.qty += row.qty,
+            None => {
+                let index = plant_supplies
+                    .iter()
+                    .position(|supply| supply.sourcefile != "OH" && supply.id > row.id)
+                    .unwrap_or(plant_supplies.len());
+                plant_supplies.insert(index, row);
+            }
+        }
+    }
+
+    // Safety stock is kept apart from the rest of the supply. It comes out of on hand first
+    // and, when on hand falls short of it, out of the earliest receipts after that.
+    let mut reserved_supplies: BTreeMap<String, Vec<SQLReturnRow>> = BTreeMap::new();
+    if options.reserve_safety_stock {
+        for (plant, plant_supplies) in remaining_supplies.iter_mut() {
+            let mut to_reserve = safety_stock
+                .get(&(part_num.to_owned(), plant.to_owned()))
+                .copied()
+                .unwrap_or(dec!(0.0));
+
+            for row in plant_supplies.iter_mut() {
+                let reserved_qty = Decimal::min(row.qty, to_reserve);
+                if reserved_qty > dec!(0.0) {
+                    row.qty -= reserved_qty;
+                    to_reserve -= reserved_qty;
+                    reserved_supplies
+                        .entry(plant.to_owned())
+                        .or_default()
+                        .push(SQLReturnRow {
+                            sourcefile: "SS".to_owned(),
+                            qty: reserved_qty,
+                            net_qty: reserved_qty,
+                            ..row.clone()
+                        });
+                }
+            }
+            plant_supplies.retain(|row| row.qty > dec!(0.0));
+        }
+    }
+
+    remaining_supplies
+        .values_mut()
+        .for_each(|plant_supplies| options.supply_strategy.sort(plant_supplies));
+
+    // Demand is only covered by supply in its own plant
+    for pegged_demand in intermediate_pegging.iter_mut() {
+        if let Some(plant_supplies) = remaining_supplies.get_mut(&pegged_demand.plant) {
+            peg_demand(pegged_demand, plant_supplies, options.supply_strategy);
+        }
+    }
+
+    // Whatever other plants have left over can then be transferred in, earliest demand first
+    if options.allow_transfers {
+        for pegged_demand in intermediate_pegging.iter_mut() {
+            for (plant, plant_supplies) in remaining_supplies.iter_mut() {
+                if *plant != pegged_demand.plant {
+                    peg_demand(pegged_demand, plant_supplies, options.supply_strategy);
+                }
+            }
+        }
+    }
+
+    // Only once all other supply is gone does demand dip into its own plant's safety stock
+    for pegged_demand in intermediate_pegging.iter_mut() {
+        if let Some(plant_supplies) = reserved_supplies.get_mut(&pegged_demand.plant) {
+            peg_demand(pegged_demand, plant_supplies, options.supply_strategy);
+        }
+    }
+
+    // Keep whatever supply is left rather than dropping it. Unused safety stock is not excess.
+    let excess: Vec<ExcessSupply> = remaining_supplies
+        .values()
+        .flatten()
+        .filter(|row| row.qty > dec!(0.0))
+        .map(ExcessSupply::from)
+        .collect();
+
+    PartPegging {
+        demand: intermediate_pegging,
+        excess,
+    }
+}
+
+/// Orders two demands by the priority rules in turn and then by due date
+fn compare_demand(a: &SQLReturnRow, b: &SQLReturnRow, options: &PegOptions) -> Ordering {
+    let source_rank = |demand: &SQLReturnRow| match demand.sourcefile.as_str() {
+        "OR" => 0,
+        "JM" => 1,
+        "FC" => 3,
+        _ => 2,
+    };
+    let customer_rank = |demand: &SQLReturnRow| {
+        options
+            .customer_priority
+            .iter()
+            .position(|customer| {
+                demand.sourcefile == "OR" && customer.eq_ignore_ascii_case(&demand.customer)
+            })
+            .unwrap_or(options.customer_priority.len())
+    };
+
+    options
+        .priority
+        .iter()
+        .chain([PriorityRule::DueDate].iter())
+        .fold(Ordering::Equal, |ordering, rule| {
+            ordering.then_with(|| match rule {
+                PriorityRule::DueDate => a.due_date.cmp(&b.due_date),
+                PriorityRule::SourceFile => source_rank(a).cmp(&source_rank(b)),
+                PriorityRule::Firm => b.firm.cmp(&a.firm),
+                PriorityRule::Customer => customer_rank(a).cmp(&customer_rank(b)),
+            })
+        })
+}
+
+/// Pegs the uncovered quantity of the demand to the supplies the strategy picks
+fn peg_demand(
+    pegged_demand: &mut Demand,
+    remaining_supplies: &mut Vec<SQLReturnRow>,
+    strategy: SupplyStrategy,
+) {
+    let mut demand_quantity_remaining = pegged_demand.demand_qty - pegged_demand.pegged_demand;
+
+    // While the remaining demand quantity is greater than zero and there is still open supply
+    while demand_quantity_remaining > dec!(0.0) {
+        let index = match strategy.pick(pegged_demand, remaining_supplies) {
+            Some(index) => index,
+            None => break,
+        };
+        let supply = &mut remaining_supplies[index];
+
+        // Calculate the quantity to be used. This should be equal to either the remaining
+        // demand quantity if it is min, or the remaining supply quantity if it is min
+        let supply_used_quantity = Decimal::min(supply.qty, demand_quantity_remaining);
+
+        pegged_demand.pegged_demand += supply_used_quantity;
+
+        pegged_demand.supply.push(Supply {
+            due_date: supply.due_date,
+            plant: supply.plant.clone(),
+            job_num: supply.job_num.clone(),
+            sourcefile: supply.sourcefile.clone(),
+            asm: supply.asm,
+            mtl: supply.mtl,
+            pegged_qty: supply_used_quantity,
+            days_late: (supply.due_date - pegged_demand.due_date).num_days(),
+            po_num: supply.po_num,
+            po_line: supply.po_line,
+            po_rel: supply.po_rel,
+            substitute_part: None,
+            conversion_factor: None,
+            uom: supply.uom.clone(),
+            uom_qty: to_loaded_uom(supply_used_quantity, supply.uom_factor),
+            bom: vec![],
+        });
+
+        // Subtract any used quantity from the supply
+        demand_quantity_remaining -= supply_used_quantity;
+
+        if supply.qty > supply_used_quantity {
+            supply.qty = supply.qty.checked_sub(supply_used_quantity).unwrap();
+        } else {
+            remaining_supplies.remove(index);
+        }
+    }
+}
+
+/// Covers demand that is still short once every part has been pegged with whatever supply
+/// of its substitutes is left over in its own plant, the earliest short demand first.
+/// Substitutes are tried in turn and the supply they give up is no longer excess.
+pub fn peg_substitutes(pegging: &mut Pegging, substitutes: &PartSubstitutes, options: &PegOptions) {
+    let mut short_demands: Vec<(NaiveDate, String, usize)> = pegging
+        .demand
+        .iter()
+        .filter(|(part_num, _)| substitutes.contains_key(*part_num))
+        .flat_map(|(part_num, demands)| {
+            demands
+                .iter()
+                .enumerate()
+                .filter(|(_, demand)| demand.pegged_demand < demand.demand_qty)
+                .map(move |(index, demand)| (demand.due_date, part_num.to_owned(), index))
+        })
+        .collect();
+    short_demands.sort();
+
+    for (_, part_num, index) in short_demands {
+        let pegged_demand = &mut pegging.demand.get_mut(&part_num).unwrap()[index];
+        for sub in &substitutes[&part_num] {
+            if let Some(sub_supplies) = pegging.excess.get_mut(&sub.sub_part) {
+                peg_substitute(pegged_demand, sub, sub_supplies, options.supply_strategy);
+            }
+        }
+    }
+
+    pegging.excess.retain(|_, excess| !excess.is_empty());
+}
+
+/// Pegs the uncovered quantity of the demand to the left over supply of one substitute,
+/// converting between the two parts with the substitute's quantity per
+fn peg_substitute(
+    pegged_demand: &mut Demand,
+    sub: &PartSub,
+    sub_supplies: &mut Vec<ExcessSupply>,
+    strategy: SupplyStrategy,
+) {
+    let mut index = 0;
+    while index < sub_supplies.len() && pegged_demand.pegged_demand < pegged_demand.demand_qty {
+        let supply = &mut sub_supplies[index];
+        if supply.plant != pegged_demand.plant
+            || (strategy == SupplyStrategy::NoLateSupply && supply.due_date > pegged_demand.due_date)
+        {
+            index += 1;
+            continue;
+        }
+
+        let uom_factor = supply.qty.checked_div(supply.uom_qty).unwrap_or(dec!(1));
+        let demand_quantity_remaining = pegged_demand.demand_qty - pegged_demand.pegged_demand;
+        let sub_used_quantity = Decimal::min(supply.qty, demand_quantity_remaining * sub.qty_per);
+        let supply_used_quantity = sub_used_quantity / sub.qty_per;
+
+        pegged_demand.pegged_demand += supply_used_quantity;
+        pegged_demand.supply.push(Supply {
+            due_date: supply.due_date,
+            plant: supply.plant.clone(),
+            job_num: supply.job_num.clone(),
+            sourcefile: supply.sourcefile.clone(),
+            asm: supply.asm,
+            mtl: supply.mtl,
+            pegged_qty: supply_used_quantity,
+            days_late: (supply.due_date - pegged_demand.due_date).num_days(),
+            po_num: supply.po_num,
+            po_line: supply.po_line,
+            po_rel: supply.po_rel,
+            substitute_part: Some(sub.sub_part.to_owned()),
+            conversion_factor: Some(sub.qty_per),
+            uom: supply.uom.clone(),
+            uom_qty: to_loaded_uom(sub_used_quantity, uom_factor),
+            bom: vec![],
+        });
+
+        supply.qty -= sub_used_quantity;
+        supply.uom_qty = to_loaded_uom(supply.qty, uom_factor);
+        if supply.qty > dec!(0.0) {
+            index += 1;
+        } else {
+            sub_supplies.remove(index);
+        }
+    }
+}
+
+/// The pegged demand for a single sales order release
+pub fn release_demand(
+    pegging: &Pegging,
+    part_num: &str,
+    order: i32,
+    line: i32,
+    release: i32,
+) -> Vec<Demand> {
+    match pegging.demand.get(part_num) {
+        Some(dmd) => dmd
+            .iter()
+            .filter(|demand| {
+                demand.order == order && demand.order_line == line && demand.order_rel == release
+            })
+            .cloned()
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// Every peg where the supply sits in a different plant from the demand, earliest need first
+pub fn suggest_transfers(pegging: &Pegging) -> Vec<TransferSuggestion> {
+    let mut transfers: Vec<TransferSuggestion> = pegging
+        .demand
+        .values()
+        .flatten()
+        .flat_map(|demand| {
+            demand
+                .supply
+                .iter()
+                .filter(|supply| supply.plant != demand.plant)
+                .map(|supply| TransferSuggestion {
+                    part_number: demand.part_number.to_owned(),
+                    from_plant: supply.plant.to_owned(),
+                    to_plant: demand.plant.to_owned(),
+                    qty: supply.pegged_qty,
+                    need_date: demand.due_date,
+                    supply_due_date: supply.due_date,
+                    sourcefile: supply.sourcefile.to_owned(),
+                    job_num: supply.job_num.to_owned(),
+                    po_num: supply.po_num,
+                    po_line: supply.po_line,
+                    po_rel: supply.po_rel,
+                    demand_sourcefile: demand.sourcefile.to_owned(),
+                    demand_job_num: demand.job_num.to_owned(),
+                    order: demand.order,
+                    order_line: demand.order_line,
+                    order_rel: demand.order_rel,
+                })
+        })
+        .collect();
+
+    transfers.sort_by(|a, b| {
+        (a.need_date, &a.part_number, &a.to_plant).cmp(&(b.need_date, &b.part_number, &b.to_plant))
+    });
+
+    transfers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directlinks::JobProd;
+    use chrono::Datelike;
+
+    fn row(requirement: bool, sourcefile: &str, plant: &str, day: u32, qty: Decimal) -> SQLReturnRow {
+        SQLReturnRow {
+            id: 0,
+            requirement,
+            part_num: "RAW".to_owned(),
+            plant: plant.to_owned(),
+            due_date: NaiveDate::from_ymd_opt(2024, 1, day).unwrap(),
+            sourcefile: sourcefile.to_owned(),
+            qty,
+            net_qty: dec!(0),
+            job_num: "".to_owned(),
+            asm: 0,
+            mtl: 0,
+            order: 0,
+            order_line: 0,
+            order_rel: 0,
+            po_num: None,
+            po_line: None,
+            po_rel: None,
+            direct: false,
+            customer: "".to_owned(),
+            firm: true,
+            uom: "".to_owned(),
+            uom_factor: dec!(1),
+        }
+    }
+
+    // RAW's input when every row is for RAW
+    fn part_input<'a>(rows: &'a [SQLReturnRow], on_hand: &'a [OnHand]) -> PartInput<'a> {
+        PartInput {
+            part_dtl: rows.iter().collect(),
+            on_hand: on_hand.iter().collect(),
+        }
+    }
+
+    // Pegs RAW without any safety stock or direct links
+    fn peg_raw(rows: &[SQLReturnRow], on_hand: &[OnHand], options: &PegOptions) -> PartPegging {
+        multi_peg_part_dtl(
+            &part_input(rows, on_hand),
+            &SafetyStock::new(),
+            &DirectLinks::new(),
+            "RAW",
+            options,
+        )
+    }
+
+    fn on_hand(site: &str, qty: Decimal) -> OnHand {
+        OnHand {
+            part_num: "RAW".to_owned(),
+            site: site.to_owned(),
+            qty,
+            uom: "".to_owned(),
+        }
+    }
+
+    #[test]
+    fn groups_input_by_part_in_load_order() {
+        let fg = |day: u32| SQLReturnRow {
+            part_num: "FG".to_owned(),
+            ..row(true, "OR", "East", day, dec!(1))
+        };
+        // A part's rows are grouped even when they are not all together
+        let rows = vec![fg(1), row(true, "JM", "East", 2, dec!(1)), fg(3)];
+        let on_hand = vec![OnHand {
+            part_num: "ALT".to_owned(),
+            ..on_hand("East", dec!(2))
+        }];
+
+        let inputs = group_part_inputs(&rows, &on_hand);
+        assert_eq!(inputs.len(), 3);
+        let fg_days: Vec<NaiveDate> =
+            inputs["FG"].part_dtl.iter().map(|row| row.due_date).collect();
+        assert_eq!(fg_days, vec![rows[0].due_date, rows[2].due_date]);
+        assert_eq!(inputs["RAW"].part_dtl.len(), 1);
+        assert!(inputs["ALT"].part_dtl.is_empty());
+        assert_eq!(inputs["ALT"].on_hand.len(), 1);
+        assert_eq!(get_unique_part_numbers(&rows), vec!["FG", "RAW"]);
+    }
+
+    #[test]
+    fn only_pegs_supply_from_the_same_plant() {
+        let rows = vec![
+            row(true, "JM", "East", 5, dec!(6)),
+            row(true, "JM", "West", 6, dec!(4)),
+            row(false, "PO", "West", 1, dec!(10)),
+        ];
+        let on_hand = vec![on_hand("East", dec!(2))];
+
+        let pegged = peg_raw(&rows, &on_hand, &PegOptions::default()).demand;
+
+        assert_eq!(pegged[0].plant, "East");
+        assert_eq!(pegged[0].pegged_demand, dec!(2));
+        assert_eq!(pegged[0].supply[0].sourcefile, "OH");
+        assert_eq!(pegged[1].plant, "West");
+        assert_eq!(pegged[1].pegged_demand, dec!(4));
+        assert_eq!(pegged[1].supply[0].plant, "West");
+    }
+
+    #[test]
+    fn keeps_left_over_supply_as_excess() {
+        let rows = vec![
+            row(true, "JM", "East", 5, dec!(3)),
+            row(false, "PO", "East", 2, dec!(2)),
+            row(false, "PO", "East", 9, dec!(4)),
+            row(false, "PO", "West", 9, dec!(5)),
+        ];
+
+        let pegged = peg_raw(&rows, &[], &PegOptions::default());
+
+        assert_eq!(pegged.excess.len(), 2);
+        assert_eq!(pegged.excess[0].plant, "East");
+        assert_eq!(pegged.excess[0].qty, dec!(3));
+        assert_eq!(pegged.excess[1].plant, "West");
+        assert_eq!(pegged.excess[1].qty, dec!(5));
+    }
+
+    #[test]
+    fn measures_how_late_each_supply_is() {
+        let rows = vec![
+            row(true, "JM", "East", 5, dec!(6)),
+            row(false, "PO", "East", 2, dec!(2)),
+            row(false, "PO", "East", 9, dec!(4)),
+        ];
+
+        let pegged = peg_raw(&rows, &[], &PegOptions::default()).demand;
+
+        assert_eq!(pegged[0].supply[0].days_late, -3);
+        assert_eq!(pegged[0].supply[1].days_late, 4);
+    }
+
+    #[test]
+    fn transfers_left_over_supply_after_own_plant_supply() {
+        let rows = vec![
+            row(true, "JM", "East", 5, dec!(6)),
+            row(true, "JM", "West", 6, dec!(4)),
+            row(false, "PO", "West", 1, dec!(10)),
+        ];
+        let on_hand = vec![on_hand("East", dec!(2))];
+        let options = PegOptions {
+            allow_transfers: true,
+            ..PegOptions::default()
+        };
+
+        let pegged = peg_raw(&rows, &on_hand, &options).demand;
+
+        // West keeps what it needs for its own demand, even though East's is due first
+        assert_eq!(pegged[1].pegged_demand, dec!(4));
+        assert_eq!(pegged[0].pegged_demand, dec!(6));
+        assert_eq!(pegged[0].supply[1].plant, "West");
+        assert_eq!(pegged[0].supply[1].pegged_qty, dec!(4));
+
+        let transfers = suggest_transfers(&Pegging {
+            demand: HashMap::from([("RAW".to_owned(), pegged)]),
+            excess: HashMap::new(),
+        });
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].from_plant, "West");
+        assert_eq!(transfers[0].to_plant, "East");
+        assert_eq!(transfers[0].qty, dec!(4));
+    }
+
+    #[test]
+    fn only_uses_safety_stock_once_other_supply_is_gone() {
+        let rows = vec![
+            row(true, "JM", "East", 5, dec!(4)),
+            row(true, "JM", "East", 8, dec!(4)),
+            row(false, "PO", "East", 20, dec!(3)),
+        ];
+        let on_hand = vec![on_hand("East", dec!(6))];
+        let safety_stock = SafetyStock::from([(("RAW".to_owned(), "East".to_owned()), dec!(4))]);
+        let options = PegOptions {
+            reserve_safety_stock: true,
+            ..PegOptions::default()
+        };
+
+        let pegged = multi_peg_part_dtl(
+            &part_input(&rows, &on_hand),
+            &safety_stock,
+            &DirectLinks::new(),
+            "RAW",
+            &options,
+        );
+
+        // The first demand takes the free on hand and then the late PO rather than safety stock
+        let sourcefiles: Vec<&str> = pegged.demand[0]
+            .supply
+            .iter()
+            .map(|supply| supply.sourcefile.as_str())
+            .collect();
+        assert_eq!(sourcefiles, vec!["OH", "PO"]);
+        assert_eq!(pegged.demand[1].supply[0].sourcefile, "PO");
+        assert_eq!(pegged.demand[1].supply[1].sourcefile, "SS");
+        assert_eq!(pegged.demand[1].supply[1].pegged_qty, dec!(3));
+        assert!(pegged.excess.is_empty());
+
+        // Without the reservation on hand is used first as before
+        let pegged = multi_peg_part_dtl(
+            &part_input(&rows, &on_hand),
+            &safety_stock,
+            &DirectLinks::new(),
+            "RAW",
+            &PegOptions::default(),
+        );
+        assert_eq!(pegged.demand[0].supply[0].pegged_qty, dec!(4));
+        assert_eq!(pegged.demand[1].supply[0].sourcefile, "OH");
+    }
+
+    #[test]
+    fn reserves_the_safety_stock_on_hand_lacks_from_the_earliest_receipts() {
+        let rows = vec![
+            row(true, "JM", "East", 5, dec!(4)),
+            row(false, "PO", "East", 3, dec!(5)),
+            row(false, "PO", "East", 9, dec!(5)),
+        ];
+        let on_hand = vec![on_hand("East", dec!(2))];
+        let safety_stock = SafetyStock::from([(("RAW".to_owned(), "East".to_owned()), dec!(4))]);
+        let options = PegOptions {
+            reserve_safety_stock: true,
+            ..PegOptions::default()
+        };
+
+        let pegged = multi_peg_part_dtl(
+            &part_input(&rows, &on_hand),
+            &safety_stock,
+            &DirectLinks::new(),
+            "RAW",
+            &options,
+        );
+
+        // On hand only covers 2 of the 4, so 2 of the first PO is held back as well
+        let supply = &pegged.demand[0].supply;
+        assert_eq!(supply[0].sourcefile, "PO");
+        assert_eq!(supply[0].due_date.day(), 3);
+        assert_eq!(supply[0].pegged_qty, dec!(3));
+        assert_eq!(supply[1].due_date.day(), 9);
+        assert_eq!(supply[1].pegged_qty, dec!(1));
+        assert_eq!(pegged.excess.len(), 1);
+        assert_eq!(pegged.excess[0].qty, dec!(4));
+    }
+
+    #[test]
+    fn pegs_demand_in_the_order_of_the_priority_rules() {
+        let rows = vec![
+            SQLReturnRow {
+                firm: false,
+                ..row(true, "JM", "East", 5, dec!(4))
+            },
+            SQLReturnRow {
+                customer: "SMALL".to_owned(),
+                ..row(true, "OR", "East", 5, dec!(4))
+            },
+            SQLReturnRow {
+                customer: "BIG".to_owned(),
+                ..row(true, "OR", "East", 7, dec!(4))
+            },
+            row(false, "PO", "East", 1, dec!(4)),
+        ];
+        let peg = |priority: Vec<PriorityRule>| {
+            let options = PegOptions {
+                priority,
+                customer_priority: vec!["big".to_owned()],
+                ..PegOptions::default()
+            };
+            peg_raw(&rows, &[], &options)
+                .demand
+                .into_iter()
+                .find(|demand| demand.pegged_demand > dec!(0))
+                .unwrap()
+        };
+
+        // Due date alone keeps the query order for the tie
+        assert_eq!(peg(vec![]).sourcefile, "JM");
+        assert_eq!(peg(vec![PriorityRule::SourceFile]).sourcefile, "OR");
+        assert_eq!(peg(vec![PriorityRule::SourceFile]).due_date.day(), 5);
+        assert_eq!(peg(vec![PriorityRule::DueDate, PriorityRule::Firm]).sourcefile, "OR");
+        assert_eq!(peg(vec![PriorityRule::Customer]).due_date.day(), 7);
+    }
+
+    #[test]
+    fn picks_supply_by_strategy() {
+        let rows = vec![
+            row(true, "JM", "East", 10, dec!(2)),
+            SQLReturnRow {
+                job_num: "J200".to_owned(),
+                ..row(false, "JH", "East", 9, dec!(2))
+            },
+            row(false, "PO", "East", 2, dec!(2)),
+            row(false, "PO", "East", 12, dec!(2)),
+        ];
+        let on_hand = vec![on_hand("East", dec!(2))];
+        let peg = |supply_strategy: SupplyStrategy| {
+            let options = PegOptions {
+                supply_strategy,
+                ..PegOptions::default()
+            };
+            let pegged = peg_raw(&rows, &on_hand, &options);
+            let supply = &pegged.demand[0].supply[0];
+            (supply.sourcefile.to_owned(), supply.due_date.day())
+        };
+
+        assert_eq!(peg(SupplyStrategy::EarliestDue), ("OH".to_owned(), 1));
+        assert_eq!(peg(SupplyStrategy::OnHandLast), ("PO".to_owned(), 2));
+        assert_eq!(peg(SupplyStrategy::PreferJob), ("OH".to_owned(), 1));
+
+        let on_hand = vec![];
+        let peg = |supply_strategy: SupplyStrategy| {
+            let options = PegOptions {
+                supply_strategy,
+                ..PegOptions::default()
+            };
+            peg_raw(&rows, &on_hand, &options).demand
+        };
+        assert_eq!(peg(SupplyStrategy::PreferJob)[0].supply[0].sourcefile, "JH");
+        assert_eq!(peg(SupplyStrategy::ClosestDate)[0].supply[0].due_date.day(), 9);
+
+        // Only supply due on or before day 4 may cover a demand due then
+        let rows = vec![
+            row(true, "JM", "East", 4, dec!(4)),
+            row(false, "PO", "East", 2, dec!(2)),
+            row(false, "PO", "East", 12, dec!(2)),
+        ];
+        let options = PegOptions {
+            supply_strategy: SupplyStrategy::NoLateSupply,
+            ..PegOptions::default()
+        };
+        let pegged = peg_raw(&rows, &[], &options);
+        assert_eq!(pegged.demand[0].pegged_demand, dec!(2));
+        assert_eq!(pegged.excess[0].due_date.day(), 12);
+    }
+
+    #[test]
+    fn holds_direct_supply_for_its_target_material() {
+        let rows = vec![
+            row(true, "JM", "East", 3, dec!(4)),
+            SQLReturnRow {
+                job_num: "J100".to_owned(),
+                mtl: 20,
+                ..row(true, "JM", "East", 8, dec!(3))
+            },
+            SQLReturnRow {
+                job_num: "J200".to_owned(),
+                ..row(false, "JH", "East", 1, dec!(5))
+            },
+        ];
+        let direct_links = DirectLinks::from([(
+            "J200".to_owned(),
+            vec![JobProd {
+                job_num: "J200".to_owned(),
+                plant: "East".to_owned(),
+                due_date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                prod_qty: dec!(3),
+                target_job_num: "J100".to_owned(),
+                target_asm: 0,
+                target_mtl: 20,
+            }],
+        )]);
+
+        let pegged = multi_peg_part_dtl(
+            &part_input(&rows, &[]),
+            &SafetyStock::new(),
+            &direct_links,
+            "RAW",
+            &PegOptions::default(),
+        );
+
+        // The earlier demand only gets what J200 makes beyond its link
+        assert_eq!(pegged.demand[0].pegged_demand, dec!(2));
+        assert_eq!(pegged.demand[1].pegged_demand, dec!(3));
+        assert_eq!(pegged.demand[1].supply[0].job_num, "J200");
+    }
+
+    #[test]
+    fn returns_direct_supply_its_target_material_does_not_use() {
+        let rows = vec![
+            row(true, "JM", "East", 3, dec!(4)),
+            SQLReturnRow {
+                job_num: "J100".to_owned(),
+                mtl: 20,
+                ..row(true, "JM", "East", 8, dec!(1))
+            },
+            SQLReturnRow {
+                job_num: "J200".to_owned(),
+                ..row(false, "JH", "East", 1, dec!(5))
+            },
+        ];
+        let link = |target_job_num: &str, plant: &str| JobProd {
+            job_num: "J200".to_owned(),
+            plant: plant.to_owned(),
+            due_date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            prod_qty: dec!(2),
+            target_job_num: target_job_num.to_owned(),
+            target_asm: 0,
+            target_mtl: 20,
+        };
+        // J100 only needs 1 of its 2, J300 isn't being pegged and West isn't J200's plant
+        let direct_links = DirectLinks::from([(
+            "J200".to_owned(),
+            vec![link("J100", "East"), link("J300", "East"), link("J100", "West")],
+        )]);
+
+        let pegged = multi_peg_part_dtl(
+            &part_input(&rows, &[]),
+            &SafetyStock::new(),
+            &direct_links,
+            "RAW",
+            &PegOptions::default(),
+        );
+
+        assert_eq!(pegged.demand[0].pegged_demand, dec!(4));
+        assert_eq!(pegged.demand[1].pegged_demand, dec!(1));
+        assert!(pegged.excess.is_empty());
+    }
+
+    #[test]
+    fn covers_shortages_with_left_over_substitute_supply() {
+        let rows = vec![row(true, "JM", "MfgSys", 5, dec!(10))];
+        let raw = peg_raw(&rows, &[on_hand("MfgSys", dec!(4))], &PegOptions::default());
+
+        let alt = |plant: &str, qty: Decimal| {
+            ExcessSupply::from(&SQLReturnRow {
+                part_num: "ALT".to_owned(),
+                ..row(false, "PO", plant, 3, qty)
+            })
+        };
+        let mut pegging = Pegging {
+            demand: HashMap::from([("RAW".to_owned(), raw.demand)]),
+            excess: HashMap::from([(
+                "ALT".to_owned(),
+                vec![alt("West", dec!(20)), alt("MfgSys", dec!(9))],
+            )]),
+        };
+        let substitutes = HashMap::from([(
+            "RAW".to_owned(),
+            vec![PartSub {
+                part_num: "RAW".to_owned(),
+                sub_part: "ALT".to_owned(),
+                qty_per: dec!(2),
+            }],
+        )]);
+
+        peg_substitutes(&mut pegging, &substitutes, &PegOptions::default());
+
+        // Two ALT replace one RAW, so the 9 ALT in the demand's plant only cover 4.5
+        let demand = &pegging.demand["RAW"][0];
+        assert_eq!(demand.pegged_demand, dec!(8.5));
+        assert_eq!(demand.supply[1].substitute_part.as_deref(), Some("ALT"));
+        assert_eq!(demand.supply[1].conversion_factor, Some(dec!(2)));
+        assert_eq!(demand.supply[1].pegged_qty, dec!(4.5));
+
+        assert_eq!(pegging.excess["ALT"].len(), 1);
+        assert_eq!(pegging.excess["ALT"][0].plant, "West");
+    }
+
+    #[test]
+    fn keeps_the_uom_supply_was_loaded_in() {
+        // Two boxes of 12 normalized to 24 each
+        let rows = vec![
+            row(true, "JM", "MfgSys", 5, dec!(30)),
+            SQLReturnRow {
+                uom: "BOX".to_owned(),
+                uom_factor: dec!(12),
+                ..row(false, "PO", "MfgSys", 1, dec!(24))
+            },
+        ];
+
+        let pegged = peg_raw(&rows, &[on_hand("MfgSys", dec!(10))], &PegOptions::default());
+        let supply = &pegged.demand[0].supply[1];
+        assert_eq!(supply.pegged_qty, dec!(20));
+        assert_eq!(supply.uom, "BOX");
+        assert_eq!(supply.uom_qty, dec!(20) / dec!(12));
+        assert_eq!(pegged.excess[0].qty, dec!(4));
+        assert_eq!(pegged.excess[0].uom_qty, dec!(4) / dec!(12));
+    }
+}
@@ -1,150 +1,192 @@
-use rust_decimal::Decimal;
-use rust_decimal_macros::dec;
-
-use crate::{onhand::OnHand, parttimephase::PartDtl};
-
-pub fn multi_peg_part_dtl(
-    part_dtl: &Vec<PartDtl>,
-    on_hand: &Vec<OnHand>,
+use std::collections::HashMap;
+
+use crate::{
+    jobmtl::JobMtl,
+    parttimephase::Demand,
+    peg::{release_demand, Pegging},
+};
+
+// Deepest job level that will be followed when walking down through job BOMs.
+// This only exists to protect against bad data (a job consuming its own output).
+const MAX_BOM_DEPTH: usize = 32;
+
+/// The pegged demand of an order release, with every job supplying it walked down to the
+/// pegged demand of its materials, and so on down through sub-assembly jobs
+pub fn release_tree(
+    pegging: &Pegging,
+    job_boms: &HashMap<String, Vec<JobMtl>>,
     part_num: &str,
-) -> Vec<PartDtl> {
-    let filtered_parts: Vec<&PartDtl> = part_dtl
-        .into_iter()
-        .filter(|part| &part.part_number == part_num)
-        .collect();
-    //println!("Part: {:#?}", filtered_parts);
-
-    let filtered_on_hand: Vec<&OnHand> = on_hand
-        .into_iter()
-        .filter(|row| &row.part_num == part_num)
-        .collect();
-
-    let mut intermediate_pegging: Vec<PartDtl> = Vec::new();
-
-    let mut remaining_supplies: Vec<PartDtl> = vec![];
-
-    // Add remaining supplies from on hand quantity
-    filtered_on_hand.iter().for_each(|row| {
-        let new_oh = PartDtl::new_on_hand(&row.part_num, row.qty);
-        remaining_supplies.push(new_oh);
-    });
-
-    let part_dtl_supplies: Vec<&&PartDtl> = filtered_parts
+    order: i32,
+    line: i32,
+    release: i32,
+) -> Vec<Demand> {
+    release_demand(pegging, part_num, order, line, release)
         .iter()
-        .filter(|a| a.requirement == false)
-        .collect();
+        .map(|demand| expand_demand(demand, pegging, job_boms, &mut vec![]))
+        .collect()
+}
 
-    for row in part_dtl_supplies {
-        remaining_supplies.push(row.to_owned().to_owned())
-    }
+/// Expands a single pegged demand. Whenever it is covered by a job ("JH" supply), the job's
+/// materials are looked up in `job_boms` and the pegged demand for each material is attached
+/// to that supply's `bom`, expanded the same way. `path` holds the jobs already being
+/// expanded above this demand so that a job is never followed into itself.
+pub fn expand_demand(
+    demand: &Demand,
+    pegging: &Pegging,
+    job_boms: &HashMap<String, Vec<JobMtl>>,
+    path: &mut Vec<String>,
+) -> Demand {
+    let mut expanded = demand.clone();
+
+    for supply in expanded.supply.iter_mut() {
+        if supply.sourcefile != "JH" {
+            continue;
+        }
 
-    let mut sorted_demands: Vec<&&PartDtl> = filtered_parts
-        .iter()
-        .filter(|a| a.requirement == true)
-        .collect();
-
-    sorted_demands.sort_by(|a, b| a.due_date.cmp(&b.due_date));
-
-    for demand in sorted_demands.iter() {
-        let mut pegged_demand = PartDtl {
-            part_number: demand.part_number.to_owned(),
-            due_date: demand.due_date,
-            sourcefile: demand.sourcefile.to_owned(),
-            qty: demand.qty,
-            job_num: demand.job_num.to_owned(),
-            asm: demand.asm,
-            mtl: demand.mtl,
-            order: demand.order,
-            order_line: demand.order_line,
-            order_rel: demand.order_rel,
-            supply: vec![],
-            requirement: demand.requirement,
-            direct: demand.direct,
-            po_num: demand.po_num,
-            po_line: demand.po_line,
-            po_rel: demand.po_rel,
-            bom: vec![],
+        let job_num = supply.job_num.to_owned();
+        if path.len() >= MAX_BOM_DEPTH || path.contains(&job_num) {
+            continue;
+        }
+
+        let materials = match job_boms.get(&job_num) {
+            Some(materials) => materials,
+            None => continue,
         };
 
-        let mut demand_quantity_remaining = pegged_demand.qty;
-
-        // While the remaining demand quantity is greater than zero and there is still open supply
-        while demand_quantity_remaining > dec!(0.0) && !remaining_supplies.is_empty() {
-            // Calculate the quantity to be used. This should be equal to either the remaining
-            // demand quantity if it is min, or the remaining supply quantity if it is min
-            let supply_used_quantity =
-                Decimal::min(remaining_supplies[0].qty, demand_quantity_remaining);
-
-            pegged_demand.supply.push(PartDtl {
-                due_date: remaining_supplies[0].due_date,
-                job_num: remaining_supplies[0].job_num.clone(),
-                sourcefile: remaining_supplies[0].sourcefile.clone(),
-                asm: remaining_supplies[0].asm,
-                mtl: remaining_supplies[0].mtl,
-                qty: remaining_supplies[0].qty,
-                po_num: remaining_supplies[0].po_num,
-                po_line: remaining_supplies[0].po_line,
-                po_rel: remaining_supplies[0].po_rel,
-                part_number: remaining_supplies[0].part_number.to_string(),
-                requirement: remaining_supplies[0].requirement,
-                direct: remaining_supplies[0].direct,
-                order: remaining_supplies[0].order,
-                order_line: remaining_supplies[0].order_line,
-                order_rel: remaining_supplies[0].order_rel,
-                supply: vec![],
-                bom: vec![],
-            });
-
-            // Subtract any used quantity from the supply
-            demand_quantity_remaining -= supply_used_quantity;
-
-            if remaining_supplies[0].qty > supply_used_quantity {
-                let new_qty = remaining_supplies[0]
-                    .qty
-                    .checked_sub(supply_used_quantity)
-                    .unwrap();
-
-                remaining_supplies[0].qty = new_qty;
-            } else {
-                remaining_supplies.remove(0);
-            }
+        path.push(job_num.to_owned());
+
+        for job_mtl in materials {
+            let component_demands = match pegging.demand.get(&job_mtl.part_num) {
+                Some(component_demands) => component_demands,
+                None => continue,
+            };
+
+            component_demands
+                .iter()
+                .filter(|row| {
+                    row.job_num == job_num && row.asm == job_mtl.asm && row.mtl == job_mtl.mtl
+                })
+                .for_each(|row| {
+                    supply.bom.push(expand_demand(row, pegging, job_boms, path));
+                });
         }
 
-        intermediate_pegging.push(pegged_demand);
+        path.pop();
     }
 
-    //let result: Vec<Demand> = intermediate_pegging.into_iter().collect();
-    intermediate_pegging
-    //result
+    expanded
 }
 
-// This is going to be a test set of data to validate it is as expected
-
 #[cfg(test)]
 mod tests {
-    use super::*; // Import the outer module to the test module
+    use super::*;
+    use crate::directlinks::DirectLinks;
+    use crate::getdata::peg_time_phase_data;
+    use crate::partsubs::PartSubstitutes;
+    use crate::peg::{PegOptions, SafetyStock};
+    use crate::sql::SQLReturnRow;
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    fn job_mtl(job_num: &str, mtl: i32, part_num: &str) -> JobMtl {
+        JobMtl {
+            job_num: job_num.to_owned(),
+            plant: "MfgSys".to_owned(),
+            asm: 0,
+            mtl,
+            jobop: 10,
+            part_num: part_num.to_owned(),
+            description: "".to_owned(),
+            direct: false,
+            req_qty: dec!(1),
+            issued_qty: dec!(0),
+            uom: "".to_owned(),
+            req_date: date(1),
+            demand: vec![],
+        }
+    }
+
+    fn row(
+        part_num: &str,
+        requirement: bool,
+        sourcefile: &str,
+        job_num: &str,
+        qty: Decimal,
+    ) -> SQLReturnRow {
+        SQLReturnRow {
+            requirement,
+            due_date: date(1),
+            sourcefile: sourcefile.to_owned(),
+            job_num: job_num.to_owned(),
+            mtl: if sourcefile == "JM" { 10 } else { 0 },
+            order: if sourcefile == "OR" { 100 } else { 0 },
+            order_line: 1,
+            order_rel: 1,
+            ..SQLReturnRow::new_on_hand(part_num, "MfgSys", qty)
+        }
+    }
+
+    fn pegging(rows: &[SQLReturnRow]) -> Pegging {
+        peg_time_phase_data(
+            rows,
+            &[],
+            &SafetyStock::new(),
+            &DirectLinks::new(),
+            &PartSubstitutes::new(),
+            &PegOptions::default(),
+        )
+    }
+
+    #[test]
+    fn walks_down_through_job_boms() {
+        let rows = vec![
+            row("FG", true, "OR", "", dec!(2)),
+            row("FG", false, "JH", "JOB-FG", dec!(2)),
+            row("SUB", true, "JM", "JOB-FG", dec!(2)),
+            row("SUB", false, "JH", "JOB-SUB", dec!(2)),
+            row("RAW", true, "JM", "JOB-SUB", dec!(4)),
+            row("RAW", false, "PO", "", dec!(4)),
+        ];
+        let job_boms = HashMap::from([
+            ("JOB-FG".to_owned(), vec![job_mtl("JOB-FG", 10, "SUB")]),
+            ("JOB-SUB".to_owned(), vec![job_mtl("JOB-SUB", 10, "RAW")]),
+        ]);
+
+        let tree = release_tree(&pegging(&rows), &job_boms, "FG", 100, 1, 1);
+
+        assert_eq!(tree.len(), 1);
+        let fg_job = &tree[0].supply[0];
+        assert_eq!(fg_job.job_num, "JOB-FG");
+        assert_eq!(fg_job.bom.len(), 1);
+
+        let sub_demand = &fg_job.bom[0];
+        assert_eq!(sub_demand.part_number, "SUB");
+        assert_eq!(sub_demand.supply[0].job_num, "JOB-SUB");
+
+        let raw_demand = &sub_demand.supply[0].bom[0];
+        assert_eq!(raw_demand.part_number, "RAW");
+        assert_eq!(raw_demand.supply[0].sourcefile, "PO");
+        assert!(raw_demand.supply[0].bom.is_empty());
+    }
 
     #[test]
-    fn test_add_two() {
-        let test_part_dtl = vec![PartDtl {
-            part_number: todo!(),
-            requirement: todo!(),
-            direct: todo!(),
-            due_date: todo!(),
-            sourcefile: todo!(),
-            qty: todo!(),
-            job_num: todo!(),
-            asm: todo!(),
-            mtl: todo!(),
-            po_num: todo!(),
-            po_line: todo!(),
-            po_rel: todo!(),
-            order: todo!(),
-            order_line: todo!(),
-            order_rel: todo!(),
-            supply: todo!(),
-            bom: todo!(),
-        }];
-        assert_eq!(multi_peg_part_dtl(2), 4);
+    fn does_not_follow_a_job_into_itself() {
+        let rows = vec![
+            row("FG", true, "OR", "", dec!(1)),
+            row("FG", false, "JH", "JOB-FG", dec!(2)),
+            row("FG", true, "JM", "JOB-FG", dec!(1)),
+        ];
+        let job_boms =
+            HashMap::from([("JOB-FG".to_owned(), vec![job_mtl("JOB-FG", 10, "FG")])]);
+
+        let tree = release_tree(&pegging(&rows), &job_boms, "FG", 100, 1, 1);
+
+        let recursive_demand = &tree[0].supply[0].bom[0];
+        assert!(recursive_demand.supply[0].bom.is_empty());
     }
 }
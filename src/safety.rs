@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    onhand::OnHand,
+    peg::{Pegging, SafetyStock},
+    sql::SQLReturnRow,
+};
+
+/// Query string filters for the safety stock report
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SafetyStockFilter {
+    pub part: Option<String>,
+}
+
+/// How the projected balance of a part in one plant measures up to its safety stock
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct SafetyStockLevel {
+    pub part_number: String,
+    pub plant: String,
+    pub safety_qty: Decimal,
+    pub on_hand: Decimal,
+    pub min_balance: Decimal,
+    /// When the projected balance first drops below safety stock. Today when on hand is
+    /// already below it.
+    pub first_below_date: Option<NaiveDate>,
+}
+
+/// A demand pegged to safety stock
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct SafetyStockUse {
+    pub part_number: String,
+    pub plant: String,
+    pub qty: Decimal,
+    pub due_date: NaiveDate,
+    pub sourcefile: String,
+    pub job_num: String,
+    pub asm: i32,
+    pub mtl: i32,
+    pub order: i32,
+    pub order_line: i32,
+    pub order_rel: i32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct SafetyStockReport {
+    pub levels: Vec<SafetyStockLevel>,
+    pub uses: Vec<SafetyStockUse>,
+}
+
+/// Lists every part with safety stock, the soonest to drop below it first, along with the
+/// demands that eat into it
+pub fn get_safety_stock_report(
+    safety_stock: &SafetyStock,
+    part_dtl: &[SQLReturnRow],
+    on_hand: &[OnHand],
+    pegging: &Pegging,
+    filter: &SafetyStockFilter,
+    today: NaiveDate,
+) -> SafetyStockReport {
+    let wanted = |part_num: &str| filter.part.as_ref().is_none_or(|part| part == part_num);
+
+    let mut levels: BTreeMap<(String, String), SafetyStockLevel> = safety_stock
+        .iter()
+        .filter(|((part_num, _), _)| wanted(part_num))
+        .map(|((part_num, plant), &safety_qty)| {
+            (
+                (part_num.to_owned(), plant.to_owned()),
+                SafetyStockLevel {
+                    part_number: part_num.to_owned(),
+                    plant: plant.to_owned(),
+                    safety_qty,
+                    on_hand: Decimal::ZERO,
+                    min_balance: Decimal::ZERO,
+                    first_below_date: None,
+                },
+            )
+        })
+        .collect();
+
+    on_hand.iter().for_each(|row| {
+        if let Some(level) = levels.get_mut(&(row.part_num.to_owned(), row.site.to_owned())) {
+            level.on_hand += row.qty;
+        }
+    });
+    levels.values_mut().for_each(|level| {
+        level.min_balance = level.on_hand;
+        if level.on_hand < level.safety_qty {
+            level.first_below_date = Some(today);
+        }
+    });
+
+    // Rows are in PartDtl query order, so net_qty is the running balance before on hand
+    part_dtl.iter().for_each(|row| {
+        if let Some(level) = levels.get_mut(&(row.part_num.to_owned(), row.plant.to_owned())) {
+            let balance = level.on_hand + row.net_qty;
+            level.min_balance = Decimal::min(level.min_balance, balance);
+            if balance < level.safety_qty && level.first_below_date.is_none() {
+                level.first_below_date = Some(row.due_date);
+            }
+        }
+    });
+
+    let mut levels: Vec<SafetyStockLevel> = levels.into_values().collect();
+    levels.sort_by(|a, b| {
+        (a.first_below_date.is_none(), a.first_below_date, &a.part_number)
+            .cmp(&(b.first_below_date.is_none(), b.first_below_date, &b.part_number))
+    });
+
+    let mut uses: Vec<SafetyStockUse> = pegging
+        .demand
+        .values()
+        .flatten()
+        .filter(|demand| wanted(&demand.part_number))
+        .flat_map(|demand| {
+            demand
+                .supply
+                .iter()
+                .filter(|supply| supply.sourcefile == "SS")
+                .map(|supply| SafetyStockUse {
+                    part_number: demand.part_number.to_owned(),
+                    plant: supply.plant.to_owned(),
+                    qty: supply.pegged_qty,
+                    due_date: demand.due_date,
+                    sourcefile: demand.sourcefile.to_owned(),
+                    job_num: demand.job_num.to_owned(),
+                    asm: demand.asm,
+                    mtl: demand.mtl,
+                    order: demand.order,
+                    order_line: demand.order_line,
+                    order_rel: demand.order_rel,
+                })
+        })
+        .collect();
+    uses.sort_by(|a, b| (a.due_date, &a.part_number).cmp(&(b.due_date, &b.part_number)));
+
+    SafetyStockReport { levels, uses }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peg::PegOptions;
+    use crate::sql::apply_net_qty;
+    use crate::test_support::{date, on_hand, peg, row};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn reports_when_and_by_whom_safety_stock_is_used() {
+        let mut part_dtl = vec![
+            row("RAW", true, 5, dec!(3)),
+            row("RAW", true, 9, dec!(4)),
+            row("SUB", true, 5, dec!(1)),
+        ];
+        apply_net_qty(&mut part_dtl);
+        let on_hand = vec![on_hand("RAW", dec!(8)), on_hand("SUB", dec!(1))];
+        let safety_stock = SafetyStock::from([
+            (("RAW".to_owned(), "MfgSys".to_owned()), dec!(5)),
+            (("SUB".to_owned(), "MfgSys".to_owned()), dec!(2)),
+        ]);
+        let pegging = peg(
+            &part_dtl,
+            &on_hand,
+            &safety_stock,
+            &PegOptions {
+                reserve_safety_stock: true,
+                ..PegOptions::default()
+            },
+        );
+
+        let report = get_safety_stock_report(
+            &safety_stock,
+            &part_dtl,
+            &on_hand,
+            &pegging,
+            &SafetyStockFilter::default(),
+            date(1),
+        );
+
+        // SUB is already below safety stock, RAW only drops below it with its second demand
+        assert_eq!(report.levels[0].part_number, "SUB");
+        assert_eq!(report.levels[0].first_below_date, Some(date(1)));
+        assert_eq!(report.levels[1].first_below_date, Some(date(9)));
+        assert_eq!(report.levels[1].min_balance, dec!(1));
+
+        assert_eq!(report.uses.len(), 2);
+        assert_eq!(report.uses[0].part_number, "SUB");
+        assert_eq!(report.uses[1].due_date, date(9));
+        assert_eq!(report.uses[1].qty, dec!(4));
+    }
+}
@@ -0,0 +1,222 @@
+use chrono::{Duration, NaiveDate};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{parttimephase::Demand, peg::Pegging};
+
+/// How the shortage report is ordered
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortageSort {
+    /// Earliest shortage first
+    #[default]
+    Date,
+    /// Largest shortage quantity first
+    Qty,
+}
+
+/// Query string filters for the shortage report. Due dates are inclusive and `horizon` is a
+/// number of days from today.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ShortageFilter {
+    pub part: Option<String>,
+    pub due_from: Option<NaiveDate>,
+    pub due_to: Option<NaiveDate>,
+    pub horizon: Option<i64>,
+    #[serde(default)]
+    pub sort: ShortageSort,
+}
+
+impl ShortageFilter {
+    /// The last due date included in the report, whichever of `due_to` and the horizon is
+    /// earlier
+    fn cutoff(&self, today: NaiveDate) -> Option<NaiveDate> {
+        let horizon = self.horizon.map(|days| today + Duration::days(days));
+
+        match (self.due_to, horizon) {
+            (Some(due_to), Some(horizon)) => Some(NaiveDate::min(due_to, horizon)),
+            (due_to, horizon) => due_to.or(horizon),
+        }
+    }
+
+    fn matches(&self, demand: &Demand, cutoff: Option<NaiveDate>) -> bool {
+        if let Some(part) = &self.part {
+            if &demand.part_number != part {
+                return false;
+            }
+        }
+
+        !(self.due_from.is_some_and(|from| demand.due_date < from)
+            || cutoff.is_some_and(|to| demand.due_date > to))
+    }
+}
+
+/// A demand that is not fully covered by pegged supply
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct Shortage {
+    pub part_number: String,
+    pub plant: String,
+    pub due_date: NaiveDate,
+    pub sourcefile: String,
+    pub demand_qty: Decimal,
+    pub pegged_qty: Decimal,
+    pub shortage_qty: Decimal,
+    pub job_num: String,
+    pub asm: i32,
+    pub mtl: i32,
+    pub order: i32,
+    pub order_line: i32,
+    pub order_rel: i32,
+}
+
+impl From<&Demand> for Shortage {
+    fn from(demand: &Demand) -> Self {
+        Shortage {
+            part_number: demand.part_number.to_owned(),
+            plant: demand.plant.to_owned(),
+            due_date: demand.due_date,
+            sourcefile: demand.sourcefile.to_owned(),
+            demand_qty: demand.demand_qty,
+            pegged_qty: demand.pegged_demand,
+            shortage_qty: demand.demand_qty - demand.pegged_demand,
+            job_num: demand.job_num.to_owned(),
+            asm: demand.asm,
+            mtl: demand.mtl,
+            order: demand.order,
+            order_line: demand.order_line,
+            order_rel: demand.order_rel,
+        }
+    }
+}
+
+/// Every shortage of a part in one plant rolled up together
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct PartShortage {
+    pub part_number: String,
+    pub plant: String,
+    pub shortage_qty: Decimal,
+    pub shortages: usize,
+    pub first_shortage_date: NaiveDate,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct ShortageReport {
+    pub parts: Vec<PartShortage>,
+    pub shortages: Vec<Shortage>,
+}
+
+/// Lists every unpegged or partially pegged demand along with a roll up by part
+pub fn get_shortages(pegging: &Pegging, filter: &ShortageFilter, today: NaiveDate) -> ShortageReport {
+    let cutoff = filter.cutoff(today);
+
+    let mut shortages: Vec<Shortage> = pegging
+        .demand
+        .values()
+        .flatten()
+        .filter(|demand| demand.pegged_demand < demand.demand_qty)
+        .filter(|demand| filter.matches(demand, cutoff))
+        .map(Shortage::from)
+        .collect();
+
+    let mut parts: Vec<PartShortage> = vec![];
+    shortages.sort_by(|a, b| {
+        (&a.part_number, &a.plant, a.due_date).cmp(&(&b.part_number, &b.plant, b.due_date))
+    });
+    shortages.iter().for_each(|shortage| match parts.last_mut() {
+        Some(part)
+            if part.part_number == shortage.part_number && part.plant == shortage.plant =>
+        {
+            part.shortage_qty += shortage.shortage_qty;
+            part.shortages += 1;
+        }
+        _ => parts.push(PartShortage {
+            part_number: shortage.part_number.to_owned(),
+            plant: shortage.plant.to_owned(),
+            shortage_qty: shortage.shortage_qty,
+            shortages: 1,
+            first_shortage_date: shortage.due_date,
+        }),
+    });
+
+    match filter.sort {
+        ShortageSort::Date => {
+            shortages.sort_by(|a, b| (a.due_date, &a.part_number).cmp(&(b.due_date, &b.part_number)));
+            parts.sort_by(|a, b| {
+                (a.first_shortage_date, &a.part_number).cmp(&(b.first_shortage_date, &b.part_number))
+            });
+        }
+        ShortageSort::Qty => {
+            shortages.sort_by(|a, b| {
+                b.shortage_qty
+                    .cmp(&a.shortage_qty)
+                    .then(a.due_date.cmp(&b.due_date))
+            });
+            parts.sort_by(|a, b| {
+                b.shortage_qty
+                    .cmp(&a.shortage_qty)
+                    .then(a.first_shortage_date.cmp(&b.first_shortage_date))
+            });
+        }
+    }
+
+    ShortageReport { parts, shortages }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{date, demand, supply};
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+
+    fn pegging() -> Pegging {
+        Pegging {
+            demand: HashMap::from([
+                (
+                    "RAW".to_owned(),
+                    vec![
+                        demand("RAW", 5, dec!(4), vec![supply("OH", 1, 5, dec!(4))]),
+                        demand("RAW", 10, dec!(4), vec![supply("OH", 1, 10, dec!(1))]),
+                        demand("RAW", 20, dec!(2), vec![]),
+                    ],
+                ),
+                ("SUB".to_owned(), vec![demand("SUB", 8, dec!(10), vec![])]),
+            ]),
+            excess: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn lists_short_demand_earliest_first_with_a_roll_up() {
+        let report = get_shortages(&pegging(), &ShortageFilter::default(), date(1));
+
+        let dates: Vec<NaiveDate> = report.shortages.iter().map(|s| s.due_date).collect();
+        assert_eq!(dates, vec![date(8), date(10), date(20)]);
+        assert_eq!(report.shortages[1].shortage_qty, dec!(3));
+
+        assert_eq!(report.parts.len(), 2);
+        assert_eq!(report.parts[0].part_number, "SUB");
+        assert_eq!(report.parts[1].part_number, "RAW");
+        assert_eq!(report.parts[1].shortage_qty, dec!(5));
+        assert_eq!(report.parts[1].shortages, 2);
+        assert_eq!(report.parts[1].first_shortage_date, date(10));
+    }
+
+    #[test]
+    fn limits_shortages_to_the_horizon() {
+        let filter = ShortageFilter {
+            horizon: Some(9),
+            sort: ShortageSort::Qty,
+            ..ShortageFilter::default()
+        };
+
+        let report = get_shortages(&pegging(), &filter, date(1));
+
+        assert_eq!(report.shortages.len(), 2);
+        assert_eq!(report.shortages[0].part_number, "SUB");
+        assert_eq!(report.shortages[1].due_date, date(10));
+    }
+}
@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    config::PlanningScope,
+    datasource::PlanningDataSource,
+    directlinks::{group_direct_links, DirectLinks},
+    fingerprint::PegFingerprints,
+    getdata::{peg_time_phase_data, repeg_time_phase_data},
+    jobmtl::{group_job_boms, JobMtl},
+    onhand::OnHand,
+    orderrelease::OrderRelease,
+    partmtl::{group_part_mtls, Boms},
+    partplant::PartPlant,
+    partsubs::{group_part_subs, PartSubstitutes},
+    peg::{PegOptions, Pegging, SafetyStock},
+    sql::SQLReturnRow,
+    uom::{
+        normalize_job_mtl, normalize_on_hand, normalize_part_dtl, normalize_part_mtl,
+        MissingUomConversion, UomConversions,
+    },
+};
+
+// How often the snapshot is rebuilt when SNAPSHOT_REFRESH_SECS is not set
+const DEFAULT_REFRESH_SECS: u64 = 300;
+
+// How many sets of options other than the snapshot's own keep their pegging, the oldest
+// being dropped first
+const PEGGING_CACHE_SIZE: usize = 8;
+
+/// Everything read from the ERP at one point in time, along with the pegging built from it.
+///
+/// The inputs are kept so that anything needing to re-peg can work from the same data the
+/// pegging was built from.
+pub struct Snapshot {
+    pub scope: PlanningScope,
+    pub options: PegOptions,
+    pub as_of: DateTime<Local>,
+    pub part_dtl: Vec<SQLReturnRow>,
+    pub on_hand: Vec<OnHand>,
+    pub job_boms: HashMap<String, Vec<JobMtl>>,
+    pub direct_links: DirectLinks,
+    /// Planning details keyed by part and plant
+    pub part_plants: HashMap<(String, String), PartPlant>,
+    pub safety_stock: SafetyStock,
+    pub substitutes: PartSubstitutes,
+    /// Current method of manufacture keyed by the part it makes
+    pub boms: Boms,
+    pub backlog: Vec<OrderRelease>,
+    pub pegging: Arc<Pegging>,
+    /// What each part was pegged from, so the next load only re-pegs what changed
+    pub fingerprints: PegFingerprints,
+    /// UOMs the data is stated in that have no conversion to the part's inventory UOM
+    pub missing_uom_conversions: Vec<MissingUomConversion>,
+    /// Pegging asked for with other options, kept for as long as the snapshot is
+    pegging_cache: RwLock<Vec<(PegOptions, Arc<Pegging>)>>,
+}
+
+impl Snapshot {
+    /// Reads every input in the scope from the data source and pegs all parts. Parts whose
+    /// inputs are the same as in the `previous` snapshot keep their previous pegging.
+    pub async fn load(
+        source: &dyn PlanningDataSource,
+        scope: &PlanningScope,
+        options: &PegOptions,
+        previous: Option<Arc<Snapshot>>,
+    ) -> Result<Snapshot, anyhow::Error> {
+        let as_of = Local::now();
+
+        // Every quantity is pegged in its part's inventory UOM
+        let uom_conversions = UomConversions::new(&source.uom_conversions(scope).await?);
+        let mut part_dtl = source.part_dtl(scope, None).await?;
+        let mut missing_conversions = normalize_part_dtl(&mut part_dtl, &uom_conversions);
+        let mut on_hand = source.on_hand(scope).await?;
+        normalize_on_hand(&mut on_hand, &uom_conversions);
+        let mut job_mtl = source.job_mtl(scope, None).await?;
+        missing_conversions.extend(normalize_job_mtl(&mut job_mtl, &uom_conversions));
+        let job_boms = group_job_boms(job_mtl);
+        let direct_links = group_direct_links(&source.job_prod(scope, None).await?);
+        let part_plants: HashMap<(String, String), PartPlant> = source
+            .part_plant(scope)
+            .await?
+            .into_iter()
+            .map(|part_plant| {
+                ((part_plant.part_num.to_owned(), part_plant.plant.to_owned()), part_plant)
+            })
+            .collect();
+        let safety_stock = get_safety_stock(scope, &part_plants);
+        let substitutes = group_part_subs(&source.part_subs(scope).await?);
+        let mut part_mtl = source.part_mtl(scope).await?;
+        missing_conversions.extend(normalize_part_mtl(&mut part_mtl, &uom_conversions));
+        let boms = group_part_mtls(&part_mtl);
+        let backlog = source.order_rel(scope).await?;
+
+        // Pegging is CPU bound, so keep it off the async workers
+        let peg_options = options.clone();
+        let (part_dtl, on_hand, safety_stock, direct_links, substitutes, pegging, fingerprints) =
+            tokio::task::spawn_blocking(move || {
+                let (pegging, fingerprints) = repeg_time_phase_data(
+                    &part_dtl,
+                    &on_hand,
+                    &safety_stock,
+                    &direct_links,
+                    &substitutes,
+                    &peg_options,
+                    previous
+                        .as_ref()
+                        .map(|previous| (previous.pegging.as_ref(), &previous.fingerprints)),
+                );
+                (part_dtl, on_hand, safety_stock, direct_links, substitutes, pegging, fingerprints)
+            })
+            .await?;
+
+        Ok(Snapshot {
+            scope: scope.to_owned(),
+            options: options.to_owned(),
+            as_of,
+            part_dtl,
+            on_hand,
+            job_boms,
+            direct_links,
+            part_plants,
+            safety_stock,
+            substitutes,
+            boms,
+            backlog,
+            pegging: Arc::new(pegging),
+            fingerprints,
+            missing_uom_conversions: missing_conversions.into_iter().collect(),
+            pegging_cache: RwLock::default(),
+        })
+    }
+
+    /// The pegging built with the given options. Anything other than the options the
+    /// snapshot was built with is re-pegged from the snapshot's inputs the first time it
+    /// is asked for, and cached on the snapshot after that.
+    pub fn pegging_with(&self, options: &PegOptions) -> Arc<Pegging> {
+        if *options == self.options {
+            return self.pegging.clone();
+        }
+
+        if let Some(pegging) = self.cached_pegging(options) {
+            return pegging;
+        }
+
+        let pegging = Arc::new(peg_time_phase_data(
+            &self.part_dtl,
+            &self.on_hand,
+            &self.safety_stock,
+            &self.direct_links,
+            &self.substitutes,
+            options,
+        ));
+
+        let mut cache = self.pegging_cache.write().unwrap();
+        // Another request may have pegged the same options in the meantime
+        if let Some((_, cached)) = cache.iter().find(|(cached, _)| cached == options) {
+            return cached.clone();
+        }
+        if cache.len() >= PEGGING_CACHE_SIZE {
+            cache.remove(0);
+        }
+        cache.push((options.to_owned(), pegging.clone()));
+
+        pegging
+    }
+
+    fn cached_pegging(&self, options: &PegOptions) -> Option<Arc<Pegging>> {
+        self.pegging_cache
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(cached, _)| cached == options)
+            .map(|(_, pegging)| pegging.clone())
+    }
+}
+
+/// Safety stock from PartPlant, with the configured levels winning over it
+fn get_safety_stock(
+    scope: &PlanningScope,
+    part_plants: &HashMap<(String, String), PartPlant>,
+) -> SafetyStock {
+    let mut safety_stock: SafetyStock = part_plants
+        .iter()
+        .filter(|(_, part_plant)| part_plant.safety_qty > Decimal::ZERO)
+        .map(|(key, part_plant)| (key.to_owned(), part_plant.safety_qty))
+        .collect();
+
+    scope.safety_stock.iter().for_each(|level| {
+        let plants = match &level.plant {
+            Some(plant) => vec![plant.to_owned()],
+            None => scope.plants.clone(),
+        };
+        plants.into_iter().for_each(|plant| {
+            safety_stock.insert((level.part_num.to_owned(), plant), level.qty);
+        })
+    });
+
+    safety_stock
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotStatus {
+    pub company: String,
+    pub plants: Vec<String>,
+    pub as_of: Option<DateTime<Local>>,
+    pub parts: usize,
+    pub last_error: Option<String>,
+    pub missing_uom_conversions: Vec<MissingUomConversion>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotStoreStatus {
+    pub refreshing: bool,
+    pub snapshots: Vec<SnapshotStatus>,
+}
+
+/// Holds the latest snapshot of each scope. Readers get a cheap handle to it while a
+/// refresh builds the next one in the background.
+pub struct SnapshotStore {
+    options: PegOptions,
+    latest: RwLock<HashMap<PlanningScope, Arc<Snapshot>>>,
+    last_error: RwLock<HashMap<PlanningScope, String>>,
+    // Only one refresh runs at a time
+    refresh_lock: Mutex<()>,
+}
+
+impl SnapshotStore {
+    /// Snapshots are pegged with `options`
+    pub fn new(options: PegOptions) -> SnapshotStore {
+        SnapshotStore {
+            options,
+            latest: RwLock::new(HashMap::new()),
+            last_error: RwLock::new(HashMap::new()),
+            refresh_lock: Mutex::new(()),
+        }
+    }
+
+    /// The latest snapshot of the scope, or `None` until it has been built
+    pub fn latest(&self, scope: &PlanningScope) -> Option<Arc<Snapshot>> {
+        self.latest.read().unwrap().get(scope).cloned()
+    }
+
+    /// The latest snapshot of the scope, building it first when the scope has never been
+    /// asked for before
+    pub async fn get_or_refresh(
+        &self,
+        source: &dyn PlanningDataSource,
+        scope: &PlanningScope,
+    ) -> Result<Arc<Snapshot>, anyhow::Error> {
+        if let Some(snapshot) = self.latest(scope) {
+            return Ok(snapshot);
+        }
+
+        let _guard = self.refresh_lock.lock().await;
+
+        // Another request may have built it while this one was waiting
+        match self.latest(scope) {
+            Some(snapshot) => Ok(snapshot),
+            None => self.load(source, scope).await,
+        }
+    }
+
+    /// Builds a new snapshot of the scope and makes it the latest. A failed refresh leaves
+    /// the previous snapshot in place.
+    pub async fn refresh(
+        &self,
+        source: &dyn PlanningDataSource,
+        scope: &PlanningScope,
+    ) -> Result<Arc<Snapshot>, anyhow::Error> {
+        let _guard = self.refresh_lock.lock().await;
+        self.load(source, scope).await
+    }
+
+    /// Refreshes the given scopes along with every other scope that has been asked for
+    pub async fn refresh_all(
+        &self,
+        source: &dyn PlanningDataSource,
+        scopes: &[PlanningScope],
+    ) -> Result<(), anyhow::Error> {
+        let mut scopes = scopes.to_vec();
+        self.latest.read().unwrap().keys().for_each(|scope| {
+            if !scopes.contains(scope) {
+                scopes.push(scope.to_owned());
+            }
+        });
+
+        let mut errors: Vec<String> = vec![];
+        for scope in &scopes {
+            if let Err(e) = self.refresh(source, scope).await {
+                errors.push(format!("{} {:?}: {}", scope.company, scope.plants, e));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!(errors.join("; "))),
+        }
+    }
+
+    async fn load(
+        &self,
+        source: &dyn PlanningDataSource,
+        scope: &PlanningScope,
+    ) -> Result<Arc<Snapshot>, anyhow::Error> {
+        match Snapshot::load(source, scope, &self.options, self.latest(scope)).await {
+            Ok(snapshot) => {
+                let snapshot = Arc::new(snapshot);
+                self.latest
+                    .write()
+                    .unwrap()
+                    .insert(scope.to_owned(), snapshot.clone());
+                self.last_error.write().unwrap().remove(scope);
+                Ok(snapshot)
+            }
+            Err(e) => {
+                self.last_error
+                    .write()
+                    .unwrap()
+                    .insert(scope.to_owned(), e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    pub fn status(&self) -> SnapshotStoreStatus {
+        let latest = self.latest.read().unwrap();
+        let last_error = self.last_error.read().unwrap();
+
+        let mut scopes: Vec<&PlanningScope> = latest.keys().chain(last_error.keys()).collect();
+        scopes.sort_by(|a, b| (&a.company, &a.plants).cmp(&(&b.company, &b.plants)));
+        scopes.dedup();
+
+        SnapshotStoreStatus {
+            refreshing: self.refresh_lock.try_lock().is_err(),
+            snapshots: scopes
+                .into_iter()
+                .map(|scope| SnapshotStatus {
+                    company: scope.company.to_owned(),
+                    plants: scope.plants.clone(),
+                    as_of: latest.get(scope).map(|snapshot| snapshot.as_of),
+                    parts: latest.get(scope).map_or(0, |snapshot| snapshot.pegging.demand.len()),
+                    last_error: last_error.get(scope).cloned(),
+                    missing_uom_conversions: latest
+                        .get(scope)
+                        .map_or(vec![], |snapshot| snapshot.missing_uom_conversions.clone()),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Reads the refresh interval from the SNAPSHOT_REFRESH_SECS environmental variable
+pub fn get_refresh_interval() -> Duration {
+    let secs = env::var("SNAPSHOT_REFRESH_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(DEFAULT_REFRESH_SECS);
+
+    Duration::from_secs(secs)
+}
+
+/// Refreshes the snapshots straight away and then on every interval after that
+pub fn spawn_refresh(
+    store: Arc<SnapshotStore>,
+    source: Arc<dyn PlanningDataSource>,
+    scopes: Vec<PlanningScope>,
+    every: Duration,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = store.refresh_all(source.as_ref(), &scopes).await {
+                println!("Snapshot refresh failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PlanningConfig;
+    use crate::fixture::FixtureDataSource;
+    use crate::peg::release_demand;
+    use chrono::NaiveDate;
+    use rust_decimal_macros::dec;
+
+    fn row(requirement: bool, sourcefile: &str, order: i32) -> SQLReturnRow {
+        SQLReturnRow {
+            id: 0,
+            requirement,
+            part_num: "FG".to_owned(),
+            plant: "MfgSys".to_owned(),
+            due_date: NaiveDate::from_ymd_opt(2024, 1, 10).unwrap(),
+            sourcefile: sourcefile.to_owned(),
+            qty: dec!(4),
+            net_qty: dec!(0),
+            job_num: "".to_owned(),
+            asm: 0,
+            mtl: 0,
+            order,
+            order_line: 1,
+            order_rel: 1,
+            po_num: None,
+            po_line: None,
+            po_rel: None,
+            direct: false,
+            customer: "".to_owned(),
+            firm: true,
+            uom: "".to_owned(),
+            uom_factor: dec!(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_replaces_the_latest_snapshot() {
+        let source = FixtureDataSource {
+            part_dtl: vec![row(true, "OR", 100), row(false, "PO", 0)],
+            ..FixtureDataSource::default()
+        }
+        .prepare();
+        let scope = PlanningConfig::from_vars(|_| None).unwrap().default_scopes()[0].clone();
+        let store = SnapshotStore::new(PegOptions::default());
+        assert!(store.latest(&scope).is_none());
+
+        let first = store.get_or_refresh(&source, &scope).await.unwrap();
+        let demand = release_demand(&first.pegging, "FG", 100, 1, 1);
+        assert_eq!(demand.len(), 1);
+        assert_eq!(demand[0].pegged_demand, dec!(4));
+
+        // Already built, so the same snapshot comes back
+        let cached = store.get_or_refresh(&source, &scope).await.unwrap();
+        assert!(Arc::ptr_eq(&cached, &first));
+
+        let second = store.refresh(&source, &scope).await.unwrap();
+        assert!(second.as_of >= first.as_of);
+        assert!(Arc::ptr_eq(&store.latest(&scope).unwrap(), &second));
+        assert!(Arc::ptr_eq(&second.pegging_with(&PegOptions::default()), &second.pegging));
+
+        // Other options are only pegged once per snapshot
+        let transfers = PegOptions {
+            allow_transfers: true,
+            ..PegOptions::default()
+        };
+        let pegging = second.pegging_with(&transfers);
+        assert!(!Arc::ptr_eq(&pegging, &second.pegging));
+        assert!(Arc::ptr_eq(&second.pegging_with(&transfers), &pegging));
+
+        let status = store.status();
+        assert!(!status.refreshing);
+        assert_eq!(status.snapshots.len(), 1);
+        assert_eq!(status.snapshots[0].parts, 1);
+        assert!(status.snapshots[0].last_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn reload_only_repegs_the_parts_that_changed() {
+        let raw = |requirement: bool, sourcefile: &str| SQLReturnRow {
+            part_num: "RAW".to_owned(),
+            ..row(requirement, sourcefile, 0)
+        };
+        let source = |po_qty: Decimal| {
+            FixtureDataSource {
+                part_dtl: vec![
+                    row(true, "OR", 100),
+                    SQLReturnRow {
+                        qty: po_qty,
+                        ..row(false, "PO", 0)
+                    },
+                    raw(true, "JM"),
+                    raw(false, "PO"),
+                ],
+                ..FixtureDataSource::default()
+            }
+            .prepare()
+        };
+        let scope = PlanningConfig::from_vars(|_| None).unwrap().default_scopes()[0].clone();
+        let options = PegOptions::default();
+
+        let mut first = Snapshot::load(&source(dec!(4)), &scope, &options, None)
+            .await
+            .unwrap();
+        // Mark RAW's pegging so it shows whether it was kept or pegged again
+        Arc::make_mut(&mut first.pegging).demand.get_mut("RAW").unwrap()[0].pegged_demand = dec!(99);
+
+        let second = Snapshot::load(&source(dec!(1)), &scope, &options, Some(Arc::new(first)))
+            .await
+            .unwrap();
+        assert_eq!(second.pegging.demand["RAW"][0].pegged_demand, dec!(99));
+        assert_eq!(release_demand(&second.pegging, "FG", 100, 1, 1)[0].pegged_demand, dec!(1));
+    }
+}
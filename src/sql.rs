@@ -1,7 +1,19 @@
+use actix_web::body::BoxBody;
+use actix_web::http::header::ContentType;
+use actix_web::{HttpResponse, Responder};
+use bb8_tiberius::ConnectionManager;
 use chrono::NaiveDate;
 use rust_decimal::Decimal;
-use serde::Serialize;
-use tiberius::{Config, EncryptionLevel};
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::time::Duration;
+use tiberius::{Config, EncryptionLevel, Query, Row};
+
+use crate::config::PlanningScope;
+use crate::transformtozero::transform_zero_to_none;
 
 extern crate dotenv;
 use dotenv::dotenv;
@@ -28,18 +40,77 @@ pub fn get_sql_config() -> Config {
     config.host(db_host);
     config.port(db_port);
     config.database(db_database);
-    return config;
+    config
+}
+
+/// Pool of connections to the ERP database shared by every loader
+pub type SqlPool = bb8::Pool<ConnectionManager>;
+
+/// How the connection pool is sized and checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolSettings {
+    pub max_size: u32,
+    pub connect_timeout: u64,
+    pub test_on_check_out: bool,
+}
+
+impl PoolSettings {
+    /// Reads the SQL_POOL_* settings from a variable lookup. A blank or missing value falls
+    /// back to its default, while one that can't be parsed is an error.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<PoolSettings, anyhow::Error> {
+        Ok(PoolSettings {
+            max_size: pool_setting(&var, "SQL_POOL_MAX_SIZE", "a positive integer")?
+                .unwrap_or(10),
+            connect_timeout: pool_setting(&var, "SQL_POOL_CONNECT_TIMEOUT", "a number of seconds")?
+                .unwrap_or(30),
+            test_on_check_out: pool_setting(&var, "SQL_POOL_TEST_ON_CHECKOUT", "true or false")?
+                .unwrap_or(true),
+        })
+    }
+}
+
+fn pool_setting<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    expected: &str,
+) -> Result<Option<T>, anyhow::Error> {
+    match var(name).filter(|val| !val.trim().is_empty()) {
+        Some(val) => val.trim().parse::<T>().map(Some).map_err(|_| {
+            anyhow::anyhow!("Could not parse {}. Please provide {}.", name, expected)
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Builds the connection pool. The pool is sized and checked using the SQL_POOL_*
+/// environmental variables, falling back to sensible defaults when they are not set.
+pub async fn get_sql_pool() -> Result<SqlPool, anyhow::Error> {
+    let config = get_sql_config();
+    let settings = PoolSettings::from_vars(|name| env::var(name).ok())?;
+
+    let manager = ConnectionManager::new(config);
+    let pool = bb8::Pool::builder()
+        .max_size(settings.max_size)
+        .connection_timeout(Duration::from_secs(settings.connect_timeout))
+        .test_on_check_out(settings.test_on_check_out)
+        .build(manager)
+        .await?;
+
+    Ok(pool)
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SQLReturnRow {
+    #[serde(default)]
     pub id: u32,
     pub requirement: bool,
     pub part_num: String,
+    pub plant: String,
     pub due_date: NaiveDate,
     pub sourcefile: String,
     pub qty: Decimal,
+    #[serde(default)]
     pub net_qty: Decimal,
     pub job_num: String,
     pub asm: i32,
@@ -50,15 +121,82 @@ pub struct SQLReturnRow {
     pub po_num: Option<i32>,
     pub po_line: Option<i32>,
     pub po_rel: Option<i32>,
+    /// Whether the row goes straight to or from a job rather than through stock, which is
+    /// when PartDtl.StockTrans is off
     pub direct: bool,
+    /// The customer ID of sales order demand
+    #[serde(default)]
+    pub customer: String,
+    #[serde(default = "default_firm")]
+    pub firm: bool,
+    /// The UOM the row is shown in, which for PO supply is its purchase UOM
+    #[serde(default)]
+    pub uom: String,
+    /// What one `uom` is in the inventory UOM, which `qty` is always in
+    #[serde(default = "default_uom_factor")]
+    pub uom_factor: Decimal,
+}
+
+// Everything but the id, which is only the row's place among every part's rows
+impl Hash for SQLReturnRow {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let SQLReturnRow {
+            id: _,
+            requirement,
+            part_num,
+            plant,
+            due_date,
+            sourcefile,
+            qty,
+            net_qty,
+            job_num,
+            asm,
+            mtl,
+            order,
+            order_line,
+            order_rel,
+            po_num,
+            po_line,
+            po_rel,
+            direct,
+            customer,
+            firm,
+            uom,
+            uom_factor,
+        } = self;
+        (requirement, part_num, plant, due_date, sourcefile, qty, net_qty).hash(state);
+        (job_num, asm, mtl, order, order_line, order_rel).hash(state);
+        (po_num, po_line, po_rel, direct, customer, firm, uom, uom_factor).hash(state);
+    }
+}
+
+impl Responder for SQLReturnRow {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &actix_web::HttpRequest) -> HttpResponse<Self::Body> {
+        let body = serde_json::to_string(&self).unwrap();
+
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+fn default_firm() -> bool {
+    true
+}
+
+fn default_uom_factor() -> Decimal {
+    dec!(1)
 }
 
 impl SQLReturnRow {
-    pub fn new_on_hand(part_num: &str, qty: Decimal) -> SQLReturnRow {
+    pub fn new_on_hand(part_num: &str, plant: &str, qty: Decimal) -> SQLReturnRow {
         SQLReturnRow {
             id: 0,
             requirement: false,
             part_num: part_num.to_string(),
+            plant: plant.to_string(),
             due_date: NaiveDate::from_ymd_opt(1999, 1, 1).unwrap(),
             sourcefile: "OH".to_owned(),
             qty,
@@ -73,11 +211,133 @@ impl SQLReturnRow {
             po_num: None,
             po_line: None,
             po_rel: None,
+            customer: "".to_owned(),
+            firm: true,
+            uom: "".to_owned(),
+            uom_factor: dec!(1),
+        }
+    }
+}
+
+/// Keeps a running net quantity for each part in each plant, supply adding and requirements
+/// subtracting. On hand is not included, so each total starts from zero.
+pub fn apply_net_qty(rows: &mut [SQLReturnRow]) {
+    let mut net_qty: HashMap<(String, String), Decimal> = HashMap::new();
+
+    rows.iter_mut().for_each(|row| {
+        let balance = net_qty
+            .entry((row.part_num.to_owned(), row.plant.to_owned()))
+            .or_insert(dec!(0.0));
+        if row.requirement {
+            *balance = balance.saturating_sub(row.qty);
+        } else {
+            *balance = balance.saturating_add(row.qty);
         }
+        row.net_qty = *balance;
+    });
+}
+
+pub fn transform_row_to_sql_return_row(id: u32, val: &Row) -> SQLReturnRow {
+    let requirement = val
+        .get::<bool, _>("RequirementFlag")
+        .unwrap_or(false.to_owned())
+        .to_owned();
+    let direct = val
+        .get::<bool, _>("StockTrans")
+        .unwrap_or(false.to_owned())
+        .to_owned();
+    let sourcefile = val
+        .get::<&str, &str>("SourceFile")
+        .unwrap_or("ER")
+        .to_owned();
+    let part_num = val
+        .get::<&str, &str>("PartNum")
+        .unwrap_or("ERROR")
+        .to_owned();
+    let plant = val.get::<&str, &str>("Plant").unwrap_or("").to_owned();
+    let qty = val
+        .get::<Decimal, _>("Quantity")
+        .unwrap_or(dec!(0.0))
+        .to_owned();
+    let due_date = val
+        .get::<NaiveDate, _>("DueDate")
+        .unwrap_or(NaiveDate::from_ymd_opt(1999, 1, 1).unwrap())
+        .to_owned();
+    let job_num = val.get::<&str, &str>("JobNum").unwrap_or("").to_owned();
+    let asm = val.get::<i32, _>("AssemblySeq").unwrap_or(0);
+    let mtl = val.get::<i32, _>("JobSeq").unwrap_or(0);
+    let order = val.get::<i32, _>("OrderNum").unwrap_or(0);
+    let order_line = val.get::<i32, _>("OrderLine").unwrap_or(0);
+    let order_rel = val.get::<i32, _>("OrderRelNum").unwrap_or(0);
+    let po_num = transform_zero_to_none(val.get::<i32, _>("PONum").to_owned());
+    let po_line = transform_zero_to_none(val.get::<i32, _>("POLine").to_owned());
+    let po_rel = transform_zero_to_none(val.get::<i32, _>("PORelNum").to_owned());
+    let customer = val.get::<&str, &str>("CustID").unwrap_or("").to_owned();
+    let firm = val.get::<bool, _>("FirmRelease").unwrap_or(true);
+    // The quantity is pegged in the inventory UOM. The UOM it was stated in, and the
+    // quantity in it, are only kept to show it in.
+    let uom = val.get::<&str, &str>("UOM").unwrap_or("").to_owned();
+    let uom_factor = val
+        .get::<Decimal, _>("UOMQty")
+        .and_then(|uom_qty| qty.checked_div(uom_qty))
+        .filter(|factor| *factor > dec!(0))
+        .unwrap_or(dec!(1));
+
+    SQLReturnRow {
+        id,
+        part_num,
+        plant,
+        job_num,
+        asm,
+        mtl,
+        requirement,
+        due_date,
+        sourcefile,
+        qty,
+        net_qty: dec!(0.0),
+        order,
+        order_line,
+        order_rel,
+        po_num,
+        po_line,
+        po_rel,
+        direct: !direct,
+        customer,
+        firm,
+        uom,
+        uom_factor,
     }
 }
 
-pub fn define_query_string(part_numbers: Option<Vec<String>>) -> String {
+pub async fn get_part_dtl_rows(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+    part_numbers: Option<&[String]>,
+) -> Result<Vec<SQLReturnRow>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
+
+    // Construct Query
+    let mut new_query = Query::new(define_query_string(scope, part_numbers));
+    bind_query_parameters(&mut new_query, scope, part_numbers);
+
+    // Stream Query
+    let stream = new_query.query(&mut client).await?;
+
+    // Consume stream
+    let rows = stream.into_first_result().await?;
+
+    let mut result: Vec<SQLReturnRow> = rows
+        .iter()
+        .enumerate()
+        .map(|(id, val)| transform_row_to_sql_return_row(id as u32, val))
+        .collect();
+    apply_net_qty(&mut result);
+
+    Ok(result)
+}
+
+pub fn define_query_string(scope: &PlanningScope, part_numbers: Option<&[String]>) -> String {
 
     // Initialize the query
     let mut new_query = 
@@ -85,6 +345,7 @@ pub fn define_query_string(part_numbers: Option<Vec<String>>) -> String {
             SELECT
                 PD.RequirementFlag,
                 PD.PartNum,
+                PD.Plant,
                 PD.SourceFile,
                 PD.Type,
                 PD.DueDate,
@@ -98,41 +359,61 @@ pub fn define_query_string(part_numbers: Option<Vec<String>>) -> String {
                 PD.PONum,
                 PD.POLine,
                 PD.PORelNum,
-                PD.StockTrans
+                PD.StockTrans,
+                PD.FirmRelease,
+                -- PO supply is shown in its purchase UOM. The open quantity of the release
+                -- in that UOM is its supplier quantity by the share of it still open.
+                -- Quantity stays in the inventory UOM and is what gets pegged.
+                (case when POD.PUM is not null and POREL.RelQty <> 0
+                    then POD.PUM else PD.IUM end) as UOM,
+                (case when POD.PUM is not null and POREL.RelQty <> 0
+                    then POREL.XRelQty * PD.Quantity / POREL.RelQty
+                    else PD.Quantity end) as UOMQty,
+                CUST.CustID
             FROM 
                 Erp.PartDtl as PD
+            LEFT OUTER JOIN Erp.PODetail as POD on
+                PD.SourceFile = 'PO'
+                and POD.Company = PD.Company
+                and POD.PONUM = PD.PONum
+                and POD.POLine = PD.POLine
+            LEFT OUTER JOIN Erp.PORel as POREL on
+                POREL.Company = POD.Company
+                and POREL.PONum = POD.PONUM
+                and POREL.POLine = POD.POLine
+                and POREL.PORelNum = PD.PORelNum
             LEFT OUTER JOIN Erp.Part as PART on 
                 PART.Company = PD.Company
                 and PART.PartNum = PD.PartNum
-                and (not PART.ProdCode = 'ETO' and not PART.ProdCode = 'RMA' and not PART.ProdCode = 'SAMPLE' and not PART.ProdCode = 'TOOL')
+            LEFT OUTER JOIN Erp.Customer as CUST on 
+                CUST.Company = PD.Company
+                and CUST.CustNum = PD.CustNum
             WHERE 
                 PD.Type <> 'Sub'
-                and PD.Plant = 'MfgSys'
-                and PD.Company = 'AE'
+                and PD.Company = @P1
                 ".to_string();
 
-    // If a vector of part numbers is passed, then we will want to filter on 
-    // those in the query
-    //
-    match part_numbers {
-        Some(parts) => {
-            let mut filter_text = "and PD.PartNum IN (".to_string();
-
-            parts.iter().enumerate().for_each(|(i, _)| {
-                let next = parts.get(i + 1);
-                match next {
-                    Some(_) => new_query.push_str(&format!("@P{}, ", i + 1)),
-                    None => new_query.push_str(&format!("@P{}", i + 1))
-                }
-            });
-
-            filter_text.push(')');
+    // Parameters are bound in the same order as in `bind_query_parameters`
+    let mut bound = 1;
+    new_query.push_str(&format!(
+        "and PD.Plant IN ({})\n",
+        parameter_list(bound, scope.plants.len())
+    ));
+    bound += scope.plants.len();
 
-            filter_text
-        },
-        None => "".to_string()
-    };
+    if !scope.excluded_prod_codes.is_empty() {
+        new_query.push_str(&format!(
+            "and (PART.ProdCode IS NULL or PART.ProdCode NOT IN ({}))\n",
+            parameter_list(bound, scope.excluded_prod_codes.len())
+        ));
+        bound += scope.excluded_prod_codes.len();
+    }
 
+    // If a vector of part numbers is passed, then we will want to filter on 
+    // those in the query
+    if let Some(parts) = part_numbers {
+        new_query.push_str(&format!("and PD.PartNum IN ({})", parameter_list(bound, parts.len())));
+    }
 
     //
     // Add the final Order By details
@@ -146,3 +427,108 @@ pub fn define_query_string(part_numbers: Option<Vec<String>>) -> String {
     new_query
 
 }
+
+fn bind_query_parameters(
+    query: &mut Query,
+    scope: &PlanningScope,
+    part_numbers: Option<&[String]>,
+) {
+    query.bind(scope.company.to_owned());
+    scope.plants.iter().for_each(|plant| query.bind(plant.to_owned()));
+    scope
+        .excluded_prod_codes
+        .iter()
+        .for_each(|prod_code| query.bind(prod_code.to_owned()));
+    if let Some(parts) = part_numbers {
+        parts.iter().for_each(|part| query.bind(part.to_owned()));
+    }
+}
+
+/// Placeholders for `count` query parameters numbered after the `bound` parameters already
+/// in the query, e.g. `@P3, @P4`
+pub fn parameter_list(bound: usize, count: usize) -> String {
+    (bound + 1..=bound + count)
+        .map(|i| format!("@P{}", i))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blank_pool_settings_fall_back_to_the_defaults() {
+        let blank = PoolSettings::from_vars(|_| Some("".to_owned())).unwrap();
+        assert_eq!(
+            blank,
+            PoolSettings {
+                max_size: 10,
+                connect_timeout: 30,
+                test_on_check_out: true,
+            }
+        );
+
+        let settings = PoolSettings::from_vars(|name| match name {
+            "SQL_POOL_MAX_SIZE" => Some(" 4 ".to_owned()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(settings.max_size, 4);
+
+        let invalid = PoolSettings::from_vars(|name| match name {
+            "SQL_POOL_TEST_ON_CHECKOUT" => Some("sometimes".to_owned()),
+            _ => None,
+        });
+        assert!(invalid.is_err());
+    }
+
+    fn scope(excluded_prod_codes: &[&str]) -> PlanningScope {
+        PlanningScope {
+            company: "AE".to_owned(),
+            plants: vec!["East".to_owned(), "West".to_owned()],
+            excluded_prod_codes: excluded_prod_codes.iter().map(|c| c.to_string()).collect(),
+            excluded_warehouse_plants: vec![],
+            safety_stock: vec![],
+        }
+    }
+
+    #[test]
+    fn filters_query_on_part_numbers() {
+        let parts = vec!["A".to_owned(), "B".to_owned(), "C".to_owned()];
+        let query = define_query_string(&scope(&[]), Some(&parts));
+
+        assert!(query.contains("and PD.PartNum IN (@P4, @P5, @P6)"));
+        assert!(!define_query_string(&scope(&[]), None).contains("PD.PartNum IN"));
+    }
+
+    #[test]
+    fn filters_query_on_scope() {
+        let query = define_query_string(&scope(&["ETO", "RMA"]), None);
+
+        assert!(query.contains("PD.Company = @P1"));
+        assert!(query.contains("and PD.Plant IN (@P2, @P3)"));
+        assert!(query.contains("PART.ProdCode NOT IN (@P4, @P5)"));
+        assert!(!define_query_string(&scope(&[]), None).contains("ProdCode"));
+    }
+
+    #[test]
+    fn keeps_a_net_qty_per_part_and_plant() {
+        let mut rows = vec![
+            SQLReturnRow::new_on_hand("A", "East", dec!(5)),
+            SQLReturnRow {
+                requirement: true,
+                ..SQLReturnRow::new_on_hand("A", "East", dec!(2))
+            },
+            SQLReturnRow::new_on_hand("A", "West", dec!(1)),
+            SQLReturnRow {
+                requirement: true,
+                ..SQLReturnRow::new_on_hand("B", "East", dec!(4))
+            },
+        ];
+        apply_net_qty(&mut rows);
+
+        let net_qty: Vec<Decimal> = rows.iter().map(|row| row.net_qty).collect();
+        assert_eq!(net_qty, vec![dec!(5), dec!(3), dec!(1), dec!(-4)]);
+    }
+}
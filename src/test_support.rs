@@ -0,0 +1,130 @@
+//! Builders shared by the tests of the reports. Everything is in plant MfgSys and, unless a
+//! month is given, in January 2024.
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+
+use crate::{
+    directlinks::DirectLinks,
+    getdata::peg_time_phase_data,
+    onhand::OnHand,
+    partsubs::PartSubstitutes,
+    parttimephase::{Demand, Supply},
+    peg::{ExcessSupply, PegOptions, Pegging, SafetyStock},
+    sql::SQLReturnRow,
+};
+
+/// A day in January 2024
+pub fn date(day: u32) -> NaiveDate {
+    month_date(1, day)
+}
+
+/// A day in 2024
+pub fn month_date(month: u32, day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(2024, month, day).unwrap()
+}
+
+/// A PartDtl row due on a day in January, job material demand or PO supply
+pub fn row(part_num: &str, requirement: bool, day: u32, qty: Decimal) -> SQLReturnRow {
+    row_due(part_num, requirement, date(day), qty)
+}
+
+/// A PartDtl row due on `due_date`, job material demand or PO supply
+pub fn row_due(
+    part_num: &str,
+    requirement: bool,
+    due_date: NaiveDate,
+    qty: Decimal,
+) -> SQLReturnRow {
+    SQLReturnRow {
+        requirement,
+        due_date,
+        sourcefile: if requirement { "JM" } else { "PO" }.to_owned(),
+        ..SQLReturnRow::new_on_hand(part_num, "MfgSys", qty)
+    }
+}
+
+pub fn on_hand(part_num: &str, qty: Decimal) -> OnHand {
+    OnHand {
+        part_num: part_num.to_owned(),
+        site: "MfgSys".to_owned(),
+        qty,
+        uom: "".to_owned(),
+    }
+}
+
+/// Demand of material 10 of job J100, pegged to the `supply`
+pub fn demand(part_number: &str, day: u32, demand_qty: Decimal, supply: Vec<Supply>) -> Demand {
+    Demand {
+        part_number: part_number.to_owned(),
+        plant: "MfgSys".to_owned(),
+        due_date: date(day),
+        sourcefile: "JM".to_owned(),
+        demand_qty,
+        job_num: "J100".to_owned(),
+        asm: 0,
+        mtl: 10,
+        order: 0,
+        order_line: 0,
+        order_rel: 0,
+        pegged_demand: supply.iter().map(|supply| supply.pegged_qty).sum(),
+        supply,
+    }
+}
+
+/// Supply due on `day` pegged to demand due on `demand_day`
+pub fn supply(sourcefile: &str, day: u32, demand_day: u32, pegged_qty: Decimal) -> Supply {
+    Supply {
+        due_date: date(day),
+        plant: "MfgSys".to_owned(),
+        sourcefile: sourcefile.to_owned(),
+        pegged_qty,
+        days_late: (date(day) - date(demand_day)).num_days(),
+        job_num: "".to_owned(),
+        asm: 0,
+        mtl: 0,
+        po_num: None,
+        po_line: None,
+        po_rel: None,
+        substitute_part: None,
+        conversion_factor: None,
+        uom: "".to_owned(),
+        uom_qty: pegged_qty,
+        bom: vec![],
+    }
+}
+
+pub fn excess(part_number: &str, sourcefile: &str, day: u32, qty: Decimal) -> ExcessSupply {
+    ExcessSupply {
+        part_number: part_number.to_owned(),
+        plant: "MfgSys".to_owned(),
+        due_date: date(day),
+        sourcefile: sourcefile.to_owned(),
+        qty,
+        job_num: "".to_owned(),
+        asm: 0,
+        mtl: 0,
+        po_num: None,
+        po_line: None,
+        po_rel: None,
+        uom: "".to_owned(),
+        uom_qty: qty,
+    }
+}
+
+/// Pegs the rows without any make direct links or substitutes
+pub fn peg(
+    part_dtl: &[SQLReturnRow],
+    on_hand: &[OnHand],
+    safety_stock: &SafetyStock,
+    options: &PegOptions,
+) -> Pegging {
+    peg_time_phase_data(
+        part_dtl,
+        on_hand,
+        safety_stock,
+        &DirectLinks::new(),
+        &PartSubstitutes::new(),
+        options,
+    )
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{onhand::OnHand, sql::SQLReturnRow};
+
+/// A single PartDtl row along with the projected balance once it has happened
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct TimelineEvent {
+    pub due_date: NaiveDate,
+    pub sourcefile: String,
+    pub requirement: bool,
+    pub qty: Decimal,
+    pub balance: Decimal,
+    pub job_num: String,
+    pub asm: i32,
+    pub mtl: i32,
+    pub order: i32,
+    pub order_line: i32,
+    pub order_rel: i32,
+    pub po_num: Option<i32>,
+    pub po_line: Option<i32>,
+    pub po_rel: Option<i32>,
+}
+
+/// The projected available balance of a part in one plant, starting from nettable on hand
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct PartTimeline {
+    pub part_number: String,
+    pub plant: String,
+    pub on_hand: Decimal,
+    pub events: Vec<TimelineEvent>,
+    pub first_negative_date: Option<NaiveDate>,
+    pub min_balance: Decimal,
+    /// When the balance first drops to its minimum. Empty when on hand is the minimum.
+    pub min_balance_date: Option<NaiveDate>,
+    pub ending_balance: Decimal,
+}
+
+/// Builds the time line of the part in every plant it has on hand or PartDtl rows in.
+///
+/// The rows are expected in PartDtl query order, so each row's `net_qty` is already the
+/// running PartDtl balance of its plant.
+pub fn get_part_timeline(
+    part_dtl: &[SQLReturnRow],
+    on_hand: &[OnHand],
+    part_num: &str,
+) -> Vec<PartTimeline> {
+    let mut timelines: BTreeMap<String, PartTimeline> = BTreeMap::new();
+
+    on_hand
+        .iter()
+        .filter(|row| row.part_num == part_num)
+        .for_each(|row| plant_timeline(&mut timelines, part_num, &row.site).on_hand += row.qty);
+
+    part_dtl
+        .iter()
+        .filter(|row| row.part_num == part_num)
+        .for_each(|row| {
+            plant_timeline(&mut timelines, part_num, &row.plant).events.push(TimelineEvent {
+                due_date: row.due_date,
+                sourcefile: row.sourcefile.to_owned(),
+                requirement: row.requirement,
+                qty: row.qty,
+                balance: row.net_qty,
+                job_num: row.job_num.to_owned(),
+                asm: row.asm,
+                mtl: row.mtl,
+                order: row.order,
+                order_line: row.order_line,
+                order_rel: row.order_rel,
+                po_num: row.po_num,
+                po_line: row.po_line,
+                po_rel: row.po_rel,
+            })
+        });
+
+    timelines
+        .into_values()
+        .map(|mut timeline| {
+            timeline.min_balance = timeline.on_hand;
+            timeline.ending_balance = timeline.on_hand;
+
+            for event in timeline.events.iter_mut() {
+                event.balance += timeline.on_hand;
+                timeline.ending_balance = event.balance;
+
+                if event.balance < Decimal::ZERO && timeline.first_negative_date.is_none() {
+                    timeline.first_negative_date = Some(event.due_date);
+                }
+                if event.balance < timeline.min_balance {
+                    timeline.min_balance = event.balance;
+                    timeline.min_balance_date = Some(event.due_date);
+                }
+            }
+
+            timeline
+        })
+        .collect()
+}
+
+fn plant_timeline<'a>(
+    timelines: &'a mut BTreeMap<String, PartTimeline>,
+    part_num: &str,
+    plant: &str,
+) -> &'a mut PartTimeline {
+    timelines.entry(plant.to_owned()).or_insert(PartTimeline {
+        part_number: part_num.to_owned(),
+        plant: plant.to_owned(),
+        on_hand: Decimal::ZERO,
+        events: vec![],
+        first_negative_date: None,
+        min_balance: Decimal::ZERO,
+        min_balance_date: None,
+        ending_balance: Decimal::ZERO,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::apply_net_qty;
+    use crate::test_support::{date, on_hand, row};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn projects_the_balance_from_on_hand() {
+        let mut part_dtl = vec![
+            row("OTHER", true, 1, dec!(50)),
+            row("RAW", true, 5, dec!(4)),
+            row("RAW", true, 10, dec!(3)),
+            row("RAW", false, 12, dec!(6)),
+            row("RAW", true, 20, dec!(2)),
+        ];
+        apply_net_qty(&mut part_dtl);
+        let on_hand = vec![on_hand("RAW", dec!(5))];
+
+        let timelines = get_part_timeline(&part_dtl, &on_hand, "RAW");
+        assert_eq!(timelines.len(), 1);
+
+        let timeline = &timelines[0];
+        let balances: Vec<Decimal> = timeline.events.iter().map(|e| e.balance).collect();
+        assert_eq!(balances, vec![dec!(1), dec!(-2), dec!(4), dec!(2)]);
+        assert_eq!(timeline.first_negative_date, Some(date(10)));
+        assert_eq!(timeline.min_balance, dec!(-2));
+        assert_eq!(timeline.ending_balance, dec!(2));
+
+        assert!(get_part_timeline(&part_dtl, &on_hand, "NONE").is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::{BTreeSet, HashMap};
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use tiberius::Query;
+
+use crate::{
+    config::PlanningScope,
+    jobmtl::JobMtl,
+    onhand::OnHand,
+    partmtl::PartMtl,
+    sql::{SQLReturnRow, SqlPool},
+};
+
+/// How a unit of measure of a part converts to the part's inventory UOM. One `uom` is
+/// `factor` of the `inventory_uom`.
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UomConversion {
+    pub part_num: String,
+    pub uom: String,
+    pub inventory_uom: String,
+    pub factor: Decimal,
+}
+
+/// Conversion factors to the inventory UOM keyed by part and UOM
+#[derive(Debug, Clone, Default)]
+pub struct UomConversions {
+    factors: HashMap<(String, String), Decimal>,
+    inventory_uoms: HashMap<String, String>,
+}
+
+impl UomConversions {
+    /// Keeps the conversions that can be used. A factor that is zero or negative would
+    /// wipe out or flip quantities and can't be converted back from, so it is left out and
+    /// the UOM is treated as unknown.
+    pub fn new(conversions: &[UomConversion]) -> UomConversions {
+        let mut uom_conversions = UomConversions::default();
+
+        let (valid, rejected): (Vec<&UomConversion>, Vec<&UomConversion>) = conversions
+            .iter()
+            .partition(|conversion| conversion.factor > dec!(0.0));
+        rejected.iter().for_each(|conversion| {
+            println!(
+                "Skipping UOM conversion of {} from {} with factor {}",
+                conversion.part_num, conversion.uom, conversion.factor
+            );
+        });
+
+        for conversion in valid {
+            uom_conversions.factors.insert(
+                (conversion.part_num.to_owned(), conversion.uom.to_owned()),
+                conversion.factor,
+            );
+            uom_conversions
+                .inventory_uoms
+                .insert(conversion.part_num.to_owned(), conversion.inventory_uom.to_owned());
+        }
+
+        uom_conversions
+    }
+
+    pub fn inventory_uom(&self, part_num: &str) -> Option<&str> {
+        self.inventory_uoms.get(part_num).map(|uom| uom.as_str())
+    }
+
+    /// What one `uom` of the part is in its inventory UOM. A blank UOM, or any UOM of a
+    /// part without conversions, is taken to already be the inventory UOM. A UOM the part
+    /// has no conversion for is `None`.
+    pub fn factor(&self, part_num: &str, uom: &str) -> Option<Decimal> {
+        match self.inventory_uom(part_num) {
+            Some(inventory_uom) if !uom.is_empty() && inventory_uom != uom => self
+                .factors
+                .get(&(part_num.to_owned(), uom.to_owned()))
+                .copied(),
+            _ => Some(dec!(1)),
+        }
+    }
+}
+
+/// A UOM used by a part that it has no PartUOM conversion for
+#[derive(Debug, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MissingUomConversion {
+    pub part_num: String,
+    pub uom: String,
+}
+
+/// The UOMs found without a conversion while normalizing
+pub type MissingUomConversions = BTreeSet<MissingUomConversion>;
+
+fn missing(part_num: &str, uom: &str) -> MissingUomConversion {
+    MissingUomConversion {
+        part_num: part_num.to_owned(),
+        uom: uom.to_owned(),
+    }
+}
+
+/// Converts an inventory UOM quantity back to the UOM it was loaded in. A factor that is
+/// not positive can't be divided by, so the quantity is left as it is.
+pub fn to_loaded_uom(qty: Decimal, factor: Decimal) -> Decimal {
+    match factor > dec!(0.0) {
+        true => qty / factor,
+        false => qty,
+    }
+}
+
+/// Works out the UOM each PartDtl row is shown in. Quantities are always in the inventory
+/// UOM and are pegged as they are, so only the factor used to show them in `uom` is set
+/// here. Rows loaded with their own quantity in `uom`, like PO supply in its purchase UOM,
+/// already carry it. Any other UOM is looked up, and one without a conversion is shown in
+/// the inventory UOM instead.
+pub fn normalize_part_dtl(
+    rows: &mut [SQLReturnRow],
+    conversions: &UomConversions,
+) -> MissingUomConversions {
+    let mut missing_conversions = MissingUomConversions::new();
+
+    rows.iter_mut().for_each(|row| {
+        let inventory_uom = conversions.inventory_uom(&row.part_num).unwrap_or("");
+        if row.uom.is_empty() {
+            row.uom = inventory_uom.to_owned();
+        }
+        if row.uom_factor != dec!(1) && row.uom_factor > dec!(0) {
+            return;
+        }
+
+        match conversions.factor(&row.part_num, &row.uom) {
+            Some(factor) => row.uom_factor = factor,
+            None => {
+                missing_conversions.insert(missing(&row.part_num, &row.uom));
+                row.uom = inventory_uom.to_owned();
+                row.uom_factor = dec!(1);
+            }
+        }
+    });
+
+    missing_conversions
+}
+
+/// Labels on hand with its part's inventory UOM, which it is always kept in
+pub fn normalize_on_hand(on_hand: &mut [OnHand], conversions: &UomConversions) {
+    on_hand.iter_mut().for_each(|row| {
+        if let Some(inventory_uom) = conversions.inventory_uom(&row.part_num) {
+            row.uom = inventory_uom.to_owned();
+        }
+    });
+}
+
+/// Converts the required quantity of each job material, which is loaded in the base UOM
+/// of the part's UOM class, to the part's inventory UOM. A material whose UOM has no
+/// conversion is left as it is.
+pub fn normalize_job_mtl(
+    job_mtls: &mut [JobMtl],
+    conversions: &UomConversions,
+) -> MissingUomConversions {
+    let mut missing_conversions = MissingUomConversions::new();
+
+    job_mtls.iter_mut().for_each(|job_mtl| {
+        match conversions.factor(&job_mtl.part_num, &job_mtl.uom) {
+            Some(factor) => job_mtl.req_qty *= factor,
+            None => {
+                missing_conversions.insert(missing(&job_mtl.part_num, &job_mtl.uom));
+            }
+        }
+        if let Some(inventory_uom) = conversions.inventory_uom(&job_mtl.part_num) {
+            job_mtl.uom = inventory_uom.to_owned();
+        }
+    });
+
+    missing_conversions
+}
+
+/// Converts the quantity per of each BOM material to the material's inventory UOM. A
+/// material whose UOM has no conversion is left as it is.
+pub fn normalize_part_mtl(
+    part_mtls: &mut [PartMtl],
+    conversions: &UomConversions,
+) -> MissingUomConversions {
+    let mut missing_conversions = MissingUomConversions::new();
+
+    part_mtls.iter_mut().for_each(|part_mtl| {
+        match conversions.factor(&part_mtl.mtl_part_num, &part_mtl.uom) {
+            Some(factor) => part_mtl.qty_per *= factor,
+            None => {
+                missing_conversions.insert(missing(&part_mtl.mtl_part_num, &part_mtl.uom));
+            }
+        }
+        if let Some(inventory_uom) = conversions.inventory_uom(&part_mtl.mtl_part_num) {
+            part_mtl.uom = inventory_uom.to_owned();
+        }
+    });
+
+    missing_conversions
+}
+
+/// Gets the conversion of every UOM of every part to the part's inventory UOM. PartUOM
+/// factors are relative to the base UOM of the part's UOM class, so both sides are brought
+/// to the base first.
+pub async fn get_uom_conversions(
+    pool: &SqlPool,
+    scope: &PlanningScope,
+) -> Result<Vec<UomConversion>, anyhow::Error> {
+    // Get a connection from the pool
+    let mut client = pool.get().await?;
+
+    // Construct Query
+    let mut select = Query::new(
+        "
+            SELECT
+                PU.PartNum,
+                PU.UOMCode,
+                PART.IUM,
+                (case when PU.ConvOperator = '/' then 1 / PU.ConvFactor else PU.ConvFactor end)
+                    / (case when IU.ConvOperator = '/' then 1 / IU.ConvFactor else IU.ConvFactor end)
+                    as Factor
+            FROM
+                Erp.PartUOM as PU
+            INNER JOIN Erp.Part as PART on
+                PART.Company = PU.Company
+                and PART.PartNum = PU.PartNum
+            INNER JOIN Erp.PartUOM as IU on
+                IU.Company = PART.Company
+                and IU.PartNum = PART.PartNum
+                and IU.UOMCode = PART.IUM
+            WHERE
+                PU.Company = @P1
+                and PU.ConvFactor <> 0
+                and IU.ConvFactor <> 0
+            ",
+    );
+
+    select.bind(scope.company.to_owned());
+
+    // Stream Query
+    let stream = select.query(&mut client).await?;
+
+    // Consume stream
+    let row = stream.into_first_result().await?;
+
+    let result: Vec<UomConversion> = row
+        .iter()
+        .map(|val| UomConversion {
+            part_num: val.get("PartNum").unwrap_or("").to_owned(),
+            uom: val.get("UOMCode").unwrap_or("").to_owned(),
+            inventory_uom: val.get("IUM").unwrap_or("").to_owned(),
+            factor: val.get::<Decimal, _>("Factor").unwrap_or(dec!(1)),
+        })
+        .collect();
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn conversion(uom: &str, factor: Decimal) -> UomConversion {
+        UomConversion {
+            part_num: "RAW".to_owned(),
+            uom: uom.to_owned(),
+            inventory_uom: "EA".to_owned(),
+            factor,
+        }
+    }
+
+    #[test]
+    fn shows_part_dtl_in_the_uom_it_was_loaded_in() {
+        let conversions =
+            UomConversions::new(&[conversion("EA", dec!(1)), conversion("BOX", dec!(12))]);
+        let mut rows = vec![
+            SQLReturnRow {
+                uom: "BOX".to_owned(),
+                due_date: NaiveDate::from_ymd_opt(2024, 1, 5).unwrap(),
+                sourcefile: "PO".to_owned(),
+                ..SQLReturnRow::new_on_hand("RAW", "MfgSys", dec!(24))
+            },
+            SQLReturnRow {
+                requirement: true,
+                uom: "BAG".to_owned(),
+                ..SQLReturnRow::new_on_hand("RAW", "MfgSys", dec!(5))
+            },
+            SQLReturnRow {
+                uom: "CASE".to_owned(),
+                uom_factor: dec!(6),
+                ..SQLReturnRow::new_on_hand("RAW", "MfgSys", dec!(12))
+            },
+            SQLReturnRow::new_on_hand("RAW", "MfgSys", dec!(3)),
+        ];
+
+        let missing_conversions = normalize_part_dtl(&mut rows, &conversions);
+
+        // Quantities stay in the inventory UOM
+        assert_eq!(rows[0].qty, dec!(24));
+        assert_eq!(rows[0].uom_factor, dec!(12));
+        assert_eq!(rows[0].uom, "BOX");
+        // A UOM without a conversion is shown in the inventory UOM and reported
+        assert_eq!(rows[1].qty, dec!(5));
+        assert_eq!(rows[1].uom, "EA");
+        assert_eq!(rows[1].uom_factor, dec!(1));
+        assert_eq!(
+            missing_conversions.into_iter().collect::<Vec<_>>(),
+            vec![missing("RAW", "BAG")]
+        );
+        // A factor loaded with the row is kept
+        assert_eq!(rows[2].uom_factor, dec!(6));
+        assert_eq!(rows[3].uom, "EA");
+    }
+
+    #[test]
+    fn leaves_out_factors_that_are_not_positive() {
+        let conversions = UomConversions::new(&[
+            conversion("EA", dec!(1)),
+            conversion("BOX", dec!(0)),
+            conversion("BAG", dec!(-2)),
+        ]);
+
+        assert_eq!(conversions.factor("RAW", "BOX"), None);
+        assert_eq!(conversions.factor("RAW", "BAG"), None);
+        assert_eq!(conversions.factor("RAW", "EA"), Some(dec!(1)));
+        assert_eq!(to_loaded_uom(dec!(6), dec!(0)), dec!(6));
+        assert_eq!(to_loaded_uom(dec!(6), dec!(3)), dec!(2));
+    }
+}
@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDate};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    late::{get_late_pegs, LatePeg, LatePegFilter},
+    onhand::OnHand,
+    peg::{group_part_inputs, multi_peg_part_dtl, PartInput, PegOptions, Pegging},
+    shortage::{get_shortages, Shortage, ShortageFilter},
+    snapshot::Snapshot,
+    sql::SQLReturnRow,
+};
+
+/// Picks PartDtl rows by whichever fields are given. A row matches when every given field
+/// does.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RowMatch {
+    pub part_num: Option<String>,
+    pub plant: Option<String>,
+    pub sourcefile: Option<String>,
+    pub job_num: Option<String>,
+    pub asm: Option<i32>,
+    pub mtl: Option<i32>,
+    pub order: Option<i32>,
+    pub order_line: Option<i32>,
+    pub order_rel: Option<i32>,
+    pub po_num: Option<i32>,
+    pub po_line: Option<i32>,
+    pub po_rel: Option<i32>,
+}
+
+impl RowMatch {
+    fn is_empty(&self) -> bool {
+        self.part_num.is_none()
+            && self.job_num.is_none()
+            && self.order.is_none()
+            && self.po_num.is_none()
+    }
+
+    fn matches(&self, row: &SQLReturnRow) -> bool {
+        self.part_num.as_ref().is_none_or(|part_num| &row.part_num == part_num)
+            && self.plant.as_ref().is_none_or(|plant| row.plant.eq_ignore_ascii_case(plant))
+            && self
+                .sourcefile
+                .as_ref()
+                .is_none_or(|sourcefile| row.sourcefile.eq_ignore_ascii_case(sourcefile))
+            && self.job_num.as_ref().is_none_or(|job_num| &row.job_num == job_num)
+            && self.asm.is_none_or(|asm| row.asm == asm)
+            && self.mtl.is_none_or(|mtl| row.mtl == mtl)
+            && self.order.is_none_or(|order| row.order == order)
+            && self.order_line.is_none_or(|line| row.order_line == line)
+            && self.order_rel.is_none_or(|rel| row.order_rel == rel)
+            && self.po_num.is_none_or(|po_num| row.po_num == Some(po_num))
+            && self.po_line.is_none_or(|po_line| row.po_line == Some(po_line))
+            && self.po_rel.is_none_or(|po_rel| row.po_rel == Some(po_rel))
+    }
+}
+
+fn default_demand_sourcefile() -> String {
+    "OR".to_owned()
+}
+
+/// A change to the snapshot to simulate. Overrides picking rows need a part, job, order or
+/// PO to pick them by, and fail when they pick nothing. A missing plant is the first plant
+/// of the scope.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Override {
+    /// Moves supply to a new due date, or by a number of days
+    MoveSupply {
+        #[serde(flatten)]
+        rows: RowMatch,
+        due_date: Option<NaiveDate>,
+        days: Option<i64>,
+    },
+    /// Changes the quantity of supply
+    ChangeSupply {
+        #[serde(flatten)]
+        rows: RowMatch,
+        qty: Decimal,
+    },
+    CancelSupply {
+        #[serde(flatten)]
+        rows: RowMatch,
+    },
+    AddDemand {
+        part_num: String,
+        plant: Option<String>,
+        due_date: NaiveDate,
+        qty: Decimal,
+        #[serde(default = "default_demand_sourcefile")]
+        sourcefile: String,
+        #[serde(default)]
+        customer: String,
+    },
+    RemoveDemand {
+        #[serde(flatten)]
+        rows: RowMatch,
+    },
+    /// Adds to on hand, or takes away from it with a negative quantity
+    AdjustOnHand {
+        part_num: String,
+        plant: Option<String>,
+        qty: Decimal,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WhatIfRequest {
+    pub overrides: Vec<Override>,
+}
+
+/// Something that is in the baseline and the scenario, but differs between them
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct Change<T> {
+    pub baseline: T,
+    pub scenario: T,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct Diff<T> {
+    /// Only in the scenario
+    pub new: Vec<T>,
+    /// Only in the baseline
+    pub resolved: Vec<T>,
+    pub changed: Vec<Change<T>>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
+pub struct WhatIfResult {
+    /// The parts that were re-pegged. Nothing else can change.
+    pub parts: Vec<String>,
+    pub shortages: Diff<Shortage>,
+    pub late_pegs: Diff<LatePeg>,
+}
+
+/// Applies the overrides to a copy of the snapshot's inputs and re-pegs the parts they
+/// touch, comparing the shortages and late pegs of those parts with the unchanged inputs.
+///
+/// Both sides are pegged the same way, without substitutes, so only the overrides make a
+/// difference.
+pub fn simulate(
+    snapshot: &Snapshot,
+    overrides: &[Override],
+    options: &PegOptions,
+    today: NaiveDate,
+) -> Result<WhatIfResult, String> {
+    let default_plant = snapshot.scope.plants[0].to_owned();
+    let plant_in_scope = |plant: &Option<String>| -> Result<String, String> {
+        match plant {
+            Some(plant) => snapshot
+                .scope
+                .plants
+                .iter()
+                .find(|scope_plant| scope_plant.eq_ignore_ascii_case(plant))
+                .cloned()
+                .ok_or(format!("Plant '{}' is not in the scope", plant)),
+            None => Ok(default_plant.to_owned()),
+        }
+    };
+
+    // Work out which parts are touched before copying any of their rows
+    let mut parts: Vec<String> = vec![];
+    for (index, change) in overrides.iter().enumerate() {
+        let touched: Vec<String> = match change {
+            Override::MoveSupply { rows, .. }
+            | Override::ChangeSupply { rows, .. }
+            | Override::CancelSupply { rows } => matching_parts(&snapshot.part_dtl, rows, false),
+            Override::RemoveDemand { rows } => matching_parts(&snapshot.part_dtl, rows, true),
+            Override::AddDemand { part_num, .. } | Override::AdjustOnHand { part_num, .. } => {
+                vec![part_num.to_owned()]
+            }
+        };
+        if touched.is_empty() {
+            return Err(format!("Override {} does not match anything", index + 1));
+        }
+        touched.into_iter().for_each(|part_num| {
+            if !parts.contains(&part_num) {
+                parts.push(part_num);
+            }
+        });
+    }
+
+    let baseline_rows: Vec<SQLReturnRow> = snapshot
+        .part_dtl
+        .iter()
+        .filter(|row| parts.contains(&row.part_num))
+        .cloned()
+        .collect();
+    let baseline_on_hand: Vec<OnHand> = snapshot
+        .on_hand
+        .iter()
+        .filter(|row| parts.contains(&row.part_num))
+        .cloned()
+        .collect();
+
+    let mut rows = baseline_rows.clone();
+    let mut on_hand = baseline_on_hand.clone();
+    for change in overrides {
+        match change {
+            Override::MoveSupply {
+                rows: picked,
+                due_date,
+                days,
+            } => {
+                if due_date.is_none() && days.is_none() {
+                    return Err("move_supply needs a due_date or days".to_owned());
+                }
+                supply_rows(&mut rows, picked).for_each(|row| {
+                    row.due_date = due_date.unwrap_or(row.due_date) + Duration::days(days.unwrap_or(0));
+                });
+            }
+            Override::ChangeSupply { rows: picked, qty } => {
+                if *qty < dec!(0.0) {
+                    return Err("change_supply needs a quantity of zero or more".to_owned());
+                }
+                supply_rows(&mut rows, picked).for_each(|row| row.qty = *qty);
+            }
+            Override::CancelSupply { rows: picked } => {
+                rows.retain(|row| row.requirement || !picked.matches(row));
+            }
+            Override::RemoveDemand { rows: picked } => {
+                rows.retain(|row| !row.requirement || !picked.matches(row));
+            }
+            Override::AddDemand {
+                part_num,
+                plant,
+                due_date,
+                qty,
+                sourcefile,
+                customer,
+            } => {
+                if *qty <= dec!(0.0) {
+                    return Err("add_demand needs a quantity above zero".to_owned());
+                }
+                rows.push(SQLReturnRow {
+                    requirement: true,
+                    due_date: *due_date,
+                    sourcefile: sourcefile.to_owned(),
+                    customer: customer.to_owned(),
+                    ..SQLReturnRow::new_on_hand(part_num, &plant_in_scope(plant)?, *qty)
+                });
+            }
+            Override::AdjustOnHand {
+                part_num,
+                plant,
+                qty,
+            } => {
+                let plant = plant_in_scope(plant)?;
+                match on_hand
+                    .iter_mut()
+                    .find(|row| &row.part_num == part_num && row.site == plant)
+                {
+                    Some(row) => row.qty = Decimal::max(row.qty + qty, dec!(0.0)),
+                    None => on_hand.push(OnHand {
+                        part_num: part_num.to_owned(),
+                        site: plant,
+                        qty: Decimal::max(*qty, dec!(0.0)),
+                        uom: "".to_owned(),
+                    }),
+                }
+            }
+        }
+    }
+
+    let baseline = peg_parts(snapshot, &parts, &baseline_rows, &baseline_on_hand, options);
+    let scenario = peg_parts(snapshot, &parts, &rows, &on_hand, options);
+
+    let shortages = |pegging: &Pegging| {
+        get_shortages(pegging, &ShortageFilter::default(), today).shortages
+    };
+    let late_pegs = |pegging: &Pegging| get_late_pegs(pegging, &LatePegFilter::default());
+
+    Ok(WhatIfResult {
+        shortages: diff(
+            shortages(&baseline),
+            shortages(&scenario),
+            shortage_key,
+            |a, b| a.shortage_qty == b.shortage_qty,
+        ),
+        late_pegs: diff(
+            late_pegs(&baseline),
+            late_pegs(&scenario),
+            late_peg_key,
+            |a, b| a.days_late == b.days_late && a.pegged_qty == b.pegged_qty,
+        ),
+        parts,
+    })
+}
+
+fn matching_parts(part_dtl: &[SQLReturnRow], rows: &RowMatch, requirement: bool) -> Vec<String> {
+    if rows.is_empty() {
+        return vec![];
+    }
+
+    let mut parts: Vec<String> = vec![];
+    part_dtl
+        .iter()
+        .filter(|row| row.requirement == requirement && rows.matches(row))
+        .for_each(|row| {
+            if !parts.contains(&row.part_num) {
+                parts.push(row.part_num.to_owned());
+            }
+        });
+    parts
+}
+
+fn supply_rows<'a>(
+    rows: &'a mut [SQLReturnRow],
+    picked: &'a RowMatch,
+) -> impl Iterator<Item = &'a mut SQLReturnRow> {
+    rows.iter_mut()
+        .filter(move |row| !row.requirement && picked.matches(row))
+}
+
+fn peg_parts(
+    snapshot: &Snapshot,
+    parts: &[String],
+    rows: &[SQLReturnRow],
+    on_hand: &[OnHand],
+    options: &PegOptions,
+) -> Pegging {
+    let options = PegOptions {
+        use_substitutes: false,
+        ..options.clone()
+    };
+    let mut pegging = Pegging::default();
+    let inputs = group_part_inputs(rows, on_hand);
+
+    parts.iter().for_each(|part_num| {
+        let part_pegging = multi_peg_part_dtl(
+            inputs.get(part_num).unwrap_or(&PartInput::default()),
+            &snapshot.safety_stock,
+            &snapshot.direct_links,
+            part_num,
+            &options,
+        );
+        pegging.demand.insert(part_num.to_owned(), part_pegging.demand);
+        pegging.excess.insert(part_num.to_owned(), part_pegging.excess);
+    });
+
+    pegging
+}
+
+// Identifies a shortage on both sides by its demand
+type ShortageKey = (String, String, NaiveDate, String, String, i32, i32, i32, i32, i32);
+
+fn shortage_key(shortage: &Shortage) -> ShortageKey {
+    (
+        shortage.part_number.to_owned(),
+        shortage.plant.to_owned(),
+        shortage.due_date,
+        shortage.sourcefile.to_owned(),
+        shortage.job_num.to_owned(),
+        shortage.asm,
+        shortage.mtl,
+        shortage.order,
+        shortage.order_line,
+        shortage.order_rel,
+    )
+}
+
+// Identifies a late peg on both sides by its demand and supply, leaving out the supply's due
+// date so that moved supply shows as changed
+type LatePegKey = (ShortageKey, String, String, Option<i32>, Option<i32>, Option<i32>);
+
+fn late_peg_key(late_peg: &LatePeg) -> LatePegKey {
+    (
+        (
+            late_peg.part_number.to_owned(),
+            late_peg.plant.to_owned(),
+            late_peg.demand_due_date,
+            late_peg.demand_sourcefile.to_owned(),
+            late_peg.demand_job_num.to_owned(),
+            late_peg.asm,
+            late_peg.mtl,
+            late_peg.order,
+            late_peg.order_line,
+            late_peg.order_rel,
+        ),
+        late_peg.supply_sourcefile.to_owned(),
+        late_peg.supply_job_num.to_owned(),
+        late_peg.po_num,
+        late_peg.po_line,
+        late_peg.po_rel,
+    )
+}
+
+/// Pairs up the baseline and scenario by key, keeping the order of each side
+fn diff<T: Clone, K: Eq + std::hash::Hash>(
+    baseline: Vec<T>,
+    scenario: Vec<T>,
+    key: impl Fn(&T) -> K,
+    same: impl Fn(&T, &T) -> bool,
+) -> Diff<T> {
+    // Several items may share a key, so they are paired up in turn
+    let mut unmatched: HashMap<K, Vec<usize>> = HashMap::new();
+    baseline.iter().enumerate().rev().for_each(|(index, item)| {
+        unmatched.entry(key(item)).or_default().push(index)
+    });
+
+    let mut matched = vec![false; baseline.len()];
+    let mut new: Vec<T> = vec![];
+    let mut changed: Vec<Change<T>> = vec![];
+
+    scenario.into_iter().for_each(|item| {
+        match unmatched.get_mut(&key(&item)).and_then(|indexes| indexes.pop()) {
+            Some(index) => {
+                matched[index] = true;
+                if !same(&baseline[index], &item) {
+                    changed.push(Change {
+                        baseline: baseline[index].clone(),
+                        scenario: item,
+                    });
+                }
+            }
+            None => new.push(item),
+        }
+    });
+
+    Diff {
+        new,
+        resolved: baseline
+            .into_iter()
+            .zip(matched)
+            .filter(|(_, matched)| !matched)
+            .map(|(item, _)| item)
+            .collect(),
+        changed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PlanningConfig;
+    use crate::fixture::FixtureDataSource;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    async fn snapshot() -> Snapshot {
+        let source = FixtureDataSource {
+            part_dtl: vec![
+                SQLReturnRow {
+                    requirement: true,
+                    due_date: date(10),
+                    sourcefile: "JM".to_owned(),
+                    job_num: "J100".to_owned(),
+                    mtl: 10,
+                    ..SQLReturnRow::new_on_hand("RAW", "MfgSys", dec!(5))
+                },
+                SQLReturnRow {
+                    due_date: date(5),
+                    sourcefile: "PO".to_owned(),
+                    po_num: Some(4567),
+                    po_line: Some(1),
+                    po_rel: Some(1),
+                    ..SQLReturnRow::new_on_hand("RAW", "MfgSys", dec!(5))
+                },
+                SQLReturnRow {
+                    requirement: true,
+                    due_date: date(10),
+                    sourcefile: "OR".to_owned(),
+                    order: 100,
+                    ..SQLReturnRow::new_on_hand("FG", "MfgSys", dec!(1))
+                },
+            ],
+            ..FixtureDataSource::default()
+        }
+        .prepare();
+        let scope = PlanningConfig::from_vars(|_| None).unwrap().default_scopes()[0].clone();
+
+        Snapshot::load(&source, &scope, &PegOptions::default(), None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn diffs_a_slipped_po_and_a_new_order() {
+        let snapshot = snapshot().await;
+        let request: WhatIfRequest = serde_json::from_str(
+            r#"{"overrides": [
+                {"type": "move_supply", "po_num": 4567, "days": 14},
+                {"type": "add_demand", "part_num": "RAW", "due_date": "2024-01-20", "qty": "3"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let result = simulate(&snapshot, &request.overrides, &PegOptions::default(), date(1)).unwrap();
+
+        // FG is not touched, so its shortage is in neither side
+        assert_eq!(result.parts, vec!["RAW"]);
+        assert_eq!(result.shortages.new.len(), 1);
+        assert_eq!(result.shortages.new[0].due_date, date(20));
+        assert_eq!(result.shortages.new[0].shortage_qty, dec!(3));
+        assert!(result.shortages.resolved.is_empty());
+
+        assert_eq!(result.late_pegs.new.len(), 1);
+        assert_eq!(result.late_pegs.new[0].days_late, 9);
+        assert_eq!(result.late_pegs.new[0].po_num, Some(4567));
+    }
+
+    #[tokio::test]
+    async fn rejects_overrides_that_match_nothing() {
+        let snapshot = snapshot().await;
+        let cancel = |po_num: i32| Override::CancelSupply {
+            rows: RowMatch {
+                po_num: Some(po_num),
+                ..RowMatch::default()
+            },
+        };
+
+        assert!(simulate(&snapshot, &[cancel(9999)], &PegOptions::default(), date(1)).is_err());
+        // Nothing to pick rows by
+        let everything = Override::CancelSupply {
+            rows: RowMatch::default(),
+        };
+        assert!(simulate(&snapshot, &[everything], &PegOptions::default(), date(1)).is_err());
+
+        let result = simulate(&snapshot, &[cancel(4567)], &PegOptions::default(), date(1)).unwrap();
+        assert_eq!(result.shortages.new[0].shortage_qty, dec!(5));
+    }
+}